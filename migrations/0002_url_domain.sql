@@ -0,0 +1,8 @@
+ALTER TABLE url ADD COLUMN domain varchar not null default '';
+
+DROP INDEX url_short_index;
+DROP INDEX url_long_index;
+
+-- codes and urls are now only unique within a domain
+CREATE UNIQUE INDEX url_short_index on url (domain, short_code);
+CREATE UNIQUE INDEX url_long_index on url (domain, long_url);
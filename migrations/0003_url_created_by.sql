@@ -0,0 +1,4 @@
+-- api key that created the link, null for links made without one
+ALTER TABLE url ADD COLUMN created_by varchar;
+
+CREATE INDEX url_created_by_index on url (created_by);
@@ -0,0 +1,2 @@
+-- <title> of the target page at shorten time, null when not fetched or unavailable
+ALTER TABLE url ADD COLUMN title varchar;
@@ -0,0 +1,4 @@
+-- open graph metadata of the target page at shorten time, each null when missing
+ALTER TABLE url ADD COLUMN og_title varchar;
+ALTER TABLE url ADD COLUMN og_description varchar;
+ALTER TABLE url ADD COLUMN og_image varchar;
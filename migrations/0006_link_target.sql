@@ -0,0 +1,13 @@
+-- alternative destinations for a link, each live within an optional time window
+-- the first one (by position) whose window contains now wins, url.long_url otherwise
+CREATE TABLE IF NOT EXISTS link_target (
+    domain varchar not null default '',
+    short_code varchar not null,
+    position integer not null,
+    long_url varchar not null,
+    -- unix seconds, inclusive, null means unbounded
+    starts_at integer,
+    -- unix seconds, exclusive, null means unbounded
+    ends_at integer
+);
+CREATE UNIQUE INDEX IF NOT EXISTS link_target_index ON link_target(domain, short_code, position);
@@ -0,0 +1,2 @@
+-- status this link redirects with, null follows the REDIRECT_STATUS default
+ALTER TABLE url ADD COLUMN redirect_status integer;
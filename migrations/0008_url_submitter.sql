@@ -0,0 +1,4 @@
+-- who created the link, for abuse investigation, only with CAPTURE_SUBMITTER
+-- the ip is stored hashed, never as is
+ALTER TABLE url ADD COLUMN submitted_ip varchar;
+ALTER TABLE url ADD COLUMN submitted_user_agent varchar;
@@ -0,0 +1,7 @@
+-- codes retired by a rotation, answered with 410 until expires_at (unix seconds)
+CREATE TABLE IF NOT EXISTS tombstone (
+    domain varchar not null default '',
+    short_code varchar not null,
+    expires_at integer not null
+);
+CREATE UNIQUE INDEX IF NOT EXISTS tombstone_index ON tombstone(domain, short_code);
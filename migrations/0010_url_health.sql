@@ -0,0 +1,4 @@
+-- what the link checker last saw at the target, both null until it gets round to the link
+-- last_status is the http status, or 0 when the target couldn't be reached at all
+ALTER TABLE url ADD COLUMN last_status integer;
+ALTER TABLE url ADD COLUMN last_checked_at integer;
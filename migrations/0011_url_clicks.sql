@@ -0,0 +1,2 @@
+-- redirects served for the link, written behind from memory so can lag slightly
+ALTER TABLE url ADD COLUMN clicks integer not null default 0;
@@ -0,0 +1,2 @@
+-- visitors a target is for, by ISO 3166 alpha-2 country code, null means anyone
+ALTER TABLE link_target ADD COLUMN country varchar;
@@ -0,0 +1,2 @@
+-- visitors a target is for, by BCP 47 language tag, null means any language
+ALTER TABLE link_target ADD COLUMN language varchar;
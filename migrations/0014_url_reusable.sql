@@ -0,0 +1,7 @@
+ALTER TABLE url ADD COLUMN reusable boolean not null default 1;
+
+DROP INDEX url_long_index;
+
+-- links minted under DEDUP_POLICY=always_new share their url with others,
+-- only the one a resubmission gets back has to be unique
+CREATE UNIQUE INDEX url_long_index on url (domain, long_url) WHERE reusable;
@@ -0,0 +1,4 @@
+-- when the link's stats last changed, for conditional requests on /stats
+ALTER TABLE url ADD COLUMN updated_at integer;
+
+UPDATE url SET updated_at = CAST(strftime('%s', 'now') AS integer);
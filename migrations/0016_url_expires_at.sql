@@ -0,0 +1,2 @@
+-- unix seconds after which the link stops redirecting, null never expires
+ALTER TABLE url ADD COLUMN expires_at integer;
@@ -0,0 +1,2 @@
+-- free-text note from whoever created the link, never used for redirecting
+ALTER TABLE url ADD COLUMN description varchar;
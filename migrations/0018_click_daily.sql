@@ -0,0 +1,9 @@
+-- clicks per link per utc day, added to by every click flush
+CREATE TABLE IF NOT EXISTS click_daily (
+    domain varchar not null,
+    short_code varchar not null,
+    date varchar not null,
+    count integer not null default 0
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS click_daily_index ON click_daily(domain, short_code, date);
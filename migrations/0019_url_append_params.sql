@@ -0,0 +1,2 @@
+-- query params added to the target on redirect, null falls back to APPEND_PARAMS
+ALTER TABLE url ADD COLUMN append_params varchar;
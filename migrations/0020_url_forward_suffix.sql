@@ -0,0 +1,2 @@
+-- whether paths past the code are appended to the target, see `suffix`
+ALTER TABLE url ADD COLUMN forward_suffix boolean not null default 0;
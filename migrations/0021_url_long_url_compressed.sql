@@ -0,0 +1,2 @@
+-- the target under COMPRESS_URLS, long_url then only holds its key, see `compress`
+ALTER TABLE url ADD COLUMN long_url_compressed blob;
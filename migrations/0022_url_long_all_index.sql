@@ -0,0 +1,2 @@
+-- every code for a url, not just the reusable one, so MAX_CODES_PER_URL can count them
+CREATE INDEX url_long_all_index on url (domain, long_url);
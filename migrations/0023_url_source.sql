@@ -0,0 +1,2 @@
+-- the channel a link came in through, every link so far came in through the api
+ALTER TABLE url ADD COLUMN source varchar not null default 'api';
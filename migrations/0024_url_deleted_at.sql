@@ -0,0 +1,2 @@
+-- unix seconds a link was soft deleted at under SOFT_DELETE, null while it's live
+ALTER TABLE url ADD COLUMN deleted_at integer;
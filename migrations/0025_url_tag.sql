@@ -0,0 +1,3 @@
+-- a label links can be deleted by in bulk, null for links shortened without one
+ALTER TABLE url ADD COLUMN tag varchar;
+CREATE INDEX url_tag_index on url (domain, tag);
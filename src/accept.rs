@@ -0,0 +1,78 @@
+use axum::http::{HeaderMap, header};
+
+/// Which of the `offered` media types the client's `Accept` header likes best.
+///
+/// Honours q-values and `type/*` / `*/*` ranges, ties go to whichever was
+/// offered first. `None` when there's no `Accept` header or nothing matches.
+pub fn preferred<'a>(headers: &HeaderMap, offered: &[&'a str]) -> Option<&'a str> {
+    let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+    let ranges = parse_ranges(accept);
+
+    let mut best: Option<(&str, f32)> = None;
+    for &media in offered {
+        // the most specific matching range decides the quality
+        let q = ranges
+            .iter()
+            .filter_map(|(range, q)| Some((specificity(range, media)?, *q)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q);
+
+        if let Some(q) = q
+            && q > 0.0
+            && best.is_none_or(|(_, best_q)| q > best_q)
+        {
+            best = Some((media, q));
+        }
+    }
+
+    best.map(|(media, _)| media)
+}
+
+/// The client's `Accept-Language` ranges, most preferred first.
+///
+/// Lowercased, ties keep the order they were sent in. Ranges with `q=0`
+/// and the `*` wildcard are left out, they never pick a language.
+pub fn languages(headers: &HeaderMap) -> Vec<String> {
+    let Some(accept) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Vec::new();
+    };
+
+    let mut ranges = parse_ranges(accept)
+        .into_iter()
+        .filter(|(range, q)| *q > 0.0 && !range.is_empty() && range != "*")
+        .collect::<Vec<_>>();
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+/// `a, b;q=0.5` -> `[("a", 1.0), ("b", 0.5)]`, for any header shaped like `Accept`
+fn parse_ranges(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let value = parts.next()?.trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((value, q))
+        })
+        .collect()
+}
+
+/// how specifically `range` matches `media`, `None` when it doesn't
+fn specificity(range: &str, media: &str) -> Option<u8> {
+    if range == media {
+        return Some(2);
+    }
+    if range == "*/*" {
+        return Some(0);
+    }
+    let (range_type, range_sub) = range.split_once('/')?;
+    let (media_type, _) = media.split_once('/')?;
+    (range_sub == "*" && range_type == media_type).then_some(1)
+}
@@ -0,0 +1,131 @@
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+use crate::{AppCtx, client_ip};
+
+/// Header a request id is read from, and echoed back on
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// What gets written per request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLog {
+    #[default]
+    Off,
+    /// one JSON object per line on stdout
+    Json,
+}
+
+impl FromStr for AccessLog {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AccessLog::Off),
+            "json" => Ok(AccessLog::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    method: &'a str,
+    /// without the query, which can hold whole urls
+    path: &'a str,
+    status: u16,
+    latency_ms: f64,
+    client_ip: String,
+    request_id: &'a str,
+    /// `None` for streamed bodies of unknown length
+    bytes_out: Option<u64>,
+}
+
+/// The id a request is known by in logs and error bodies, see `request_id`
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// unique enough within a deployment, startup time keeps restarts from reusing ids
+fn generate_id() -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!(
+        "{:x}-{:x}",
+        started,
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// outermost middleware, gives each request an id for the layers inside and echoes it back
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    // a caller's own id lets their logs and ours be joined up
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(generate_id, |id| id.to_owned());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut res = next.run(req).await;
+    if let Ok(id) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    res
+}
+
+/// logs every response including the ones other layers reject
+pub async fn log(
+    State(ctx): State<AppCtx>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if ctx.config.access_log == AccessLog::Off {
+        return next.run(req).await;
+    }
+
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let client_ip = client_ip::resolve(&ctx.config, peer, req.headers());
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default();
+
+    let res = next.run(req).await;
+
+    let bytes_out = res
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or_else(|| axum::body::HttpBody::size_hint(res.body()).exact());
+    let line = Line {
+        method: method.as_str(),
+        path: &path,
+        status: res.status().as_u16(),
+        // microsecond precision is plenty
+        latency_ms: (started.elapsed().as_secs_f64() * 1_000_000.0).round() / 1000.0,
+        client_ip: client_ip.to_string(),
+        request_id: &request_id,
+        bytes_out,
+    };
+    println!("{}", serde_json::to_string(&line).unwrap());
+    res
+}
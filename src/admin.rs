@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{HeaderMap, StatusCode, header, request::Parts},
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::{
+    AppCtx, clicks,
+    dedup::DedupPolicy,
+    domain::{self, DEFAULT_DOMAIN},
+    health::PoolHealth,
+    lookup_code_for_url, lookup_entry, normalize, predicted_code, privacy, submitted_url,
+    tasks::TaskStats,
+};
+
+/// Guard for the `/admin` routes, expects `Authorization: Bearer <ADMIN_TOKEN>`
+pub struct AdminAuth;
+
+impl FromRequestParts<AppCtx> for AdminAuth {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, ctx: &AppCtx) -> Result<Self, Self::Rejection> {
+        // no token configured means nobody gets in
+        let Some(expected) = ctx.config.admin_token.as_deref() else {
+            return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()));
+        };
+
+        let provided = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        match provided {
+            Some(token) if token == expected => Ok(AdminAuth),
+            _ => Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CacheSizes {
+    short_to_long: usize,
+    long_to_short: usize,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    total_links: i64,
+    /// flushed clicks across every link, pending ones aren't in it yet
+    total_clicks: i64,
+    db_size_bytes: i64,
+    cache_sizes: CacheSizes,
+    /// approximate memory held by each cache, what `CACHE_MAX_BYTES` bounds
+    cache_bytes: CacheSizes,
+    /// redirects counted in memory that haven't reached the db yet
+    pending_clicks: i64,
+    /// latest background db check
+    db_health: PoolHealth,
+    /// how each background task's runs have gone, by task
+    tasks: BTreeMap<&'static str, TaskStats>,
+}
+
+/// GET /admin/stats
+///
+/// single human-readable snapshot of how big the service is
+pub async fn stats(_: AdminAuth, State(ctx): State<AppCtx>) -> impl IntoResponse {
+    println!("/admin/stats GET <--");
+
+    let db_stats = async {
+        let total_links = sqlx::query_scalar!("SELECT COUNT(*) FROM url")
+            .fetch_one(&ctx.pool)
+            .await?;
+        let total_clicks = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(clicks), 0) AS "total_clicks!: i64" FROM url"#
+        )
+        .fetch_one(&ctx.pool)
+        .await?;
+
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+            .fetch_one(&ctx.pool)
+            .await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+            .fetch_one(&ctx.pool)
+            .await?;
+
+        Ok::<_, sqlx::Error>((total_links, total_clicks, page_count * page_size))
+    };
+
+    let (total_links, total_clicks, db_size_bytes) = match db_stats.await {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Failed to collect stats: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+
+    let cache_sizes = CacheSizes {
+        short_to_long: ctx.short_to_long_cache.len(),
+        long_to_short: ctx.long_to_short_cache.len(),
+    };
+    let cache_bytes = CacheSizes {
+        short_to_long: ctx.short_to_long_cache.bytes(),
+        long_to_short: ctx.long_to_short_cache.bytes(),
+    };
+
+    Json(Stats {
+        total_links,
+        total_clicks,
+        db_size_bytes,
+        cache_sizes,
+        cache_bytes,
+        pending_clicks: clicks::pending(&ctx),
+        db_health: ctx.pool_health.read().unwrap().clone(),
+        tasks: ctx.task_health.snapshot(),
+    })
+    .into_response()
+}
+
+/// Everything stored about a link, including what the public endpoints leave out
+#[derive(Serialize)]
+struct Link {
+    short_code: String,
+    domain: String,
+    long_url: String,
+    created_by: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    redirect_status: Option<i64>,
+    append_params: Option<String>,
+    forward_suffix: bool,
+    source: String,
+    tag: Option<String>,
+    submitted_ip: Option<String>,
+    submitted_user_agent: Option<String>,
+    clicks: i64,
+    last_status: Option<i64>,
+    last_checked_at: Option<i64>,
+    deleted_at: Option<i64>,
+}
+
+/// GET /admin/links/{short_code}
+///
+/// the full record of a link, `?domain=` picks the domain when it isn't the default one
+pub async fn link(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    Path(short_code): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    println!("/admin/links GET <-- {}", short_code);
+
+    let domain = params.get("domain").map_or(DEFAULT_DOMAIN, |d| d.as_str());
+    match lookup_entry(domain, &short_code, &ctx.pool).await {
+        Ok(Some(url)) => Json(Link {
+            short_code: url.short_code,
+            domain: url.domain,
+            long_url: url.long_url,
+            created_by: url.created_by,
+            title: url.title,
+            description: url.description,
+            redirect_status: url.redirect_status,
+            append_params: url.append_params,
+            forward_suffix: url.forward_suffix,
+            source: url.source,
+            tag: url.tag,
+            submitted_ip: url.submitted_ip,
+            submitted_user_agent: url.submitted_user_agent,
+            clicks: url.clicks,
+            last_status: url.last_status,
+            last_checked_at: url.last_checked_at,
+            deleted_at: url.deleted_at,
+        })
+        .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            "Short code not recognised".to_owned(),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Failed to look up entry: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// How `shorten` would code a url, see `debug_hash`
+#[derive(Serialize)]
+struct HashDebug {
+    normalized_url: String,
+    /// what the code generator gives the url first, `None` for `random`
+    hash: Option<String>,
+    dedup_policy: &'static str,
+    /// the code a new link would get, `None` where it's random. differs from `hash`
+    /// when that one's on the wordlist
+    short_code: Option<String>,
+    /// the code the url already has in the domain, what a resubmission returns
+    existing_code: Option<String>,
+    /// whatever is stored under `short_code` when it's a different url, a hash collision
+    collides_with: Option<String>,
+}
+
+/// GET /admin/debug/hash?url=
+///
+/// the normalized url, its hash and the code `shorten` would hand out for it,
+/// without storing anything. the domain comes from `?domain=` or the `Host` as for `shorten`
+pub async fn debug_hash(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let Some(url) = submitted_url(params.get("url")) else {
+        println!("/admin/debug/hash GET <--");
+        return (StatusCode::BAD_REQUEST, "URL was not provided".to_owned()).into_response();
+    };
+    println!(
+        "/admin/debug/hash GET <-- {}",
+        privacy::log_url(&ctx.config, &url)
+    );
+
+    // every step goes through what `shorten` uses, so the answer can't drift from it
+    let domain = match domain::for_shorten(&ctx.config, params.get("domain"), &headers, None) {
+        Ok(domain) => domain,
+        Err(e) => return e.into_response(),
+    };
+    let normalized_url = normalize::normalize_url(&ctx.config, &url);
+    let hash = ctx
+        .code_generator
+        .deterministic()
+        .then(|| ctx.code_generator.generate(&normalized_url, 0));
+    let short_code = predicted_code(
+        &ctx,
+        &normalized_url,
+        ctx.config.dedup_policy == DedupPolicy::Reuse,
+    );
+
+    let stored = async {
+        let existing_code = lookup_code_for_url(&domain, &normalized_url, &ctx.pool).await?;
+        let occupant = match short_code.as_ref().or(hash.as_ref()) {
+            Some(code) => lookup_entry(&domain, code, &ctx.pool).await?,
+            None => None,
+        };
+        Ok::<_, sqlx::Error>((existing_code, occupant))
+    };
+    let (existing_code, occupant) = match stored.await {
+        Ok(stored) => stored,
+        Err(e) => {
+            eprintln!("Failed to look up entry: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+    let collides_with = occupant
+        .map(|url| url.long_url)
+        .filter(|long_url| *long_url != normalized_url);
+    if collides_with.is_some() {
+        println!("\thash already taken by another url");
+    }
+
+    Json(HashDebug {
+        normalized_url,
+        hash,
+        dedup_policy: match ctx.config.dedup_policy {
+            DedupPolicy::Reuse => "reuse",
+            DedupPolicy::AlwaysNew => "always_new",
+        },
+        short_code,
+        existing_code,
+        collides_with,
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode, header},
+    };
+
+    use crate::{
+        AppCtx, clicks,
+        config::Config,
+        tests::{self, ADMIN_TOKEN, Reply, admin, call, ctx, ctx_with, get, shorten},
+        wordlist::Wordlist,
+    };
+
+    async fn debug_hash(ctx: &AppCtx, long_url: &str, host: Option<&str>) -> Reply {
+        let q = url::form_urlencoded::byte_serialize(long_url.as_bytes()).collect::<String>();
+        let mut req = Request::get(format!("/admin/debug/hash?url={}", q))
+            .header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN));
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        call(ctx, req.body(Body::empty()).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn stats_count_what_was_stored() {
+        let ctx = ctx().await;
+        let first = shorten(&ctx, "https://example.com/first").await;
+        shorten(&ctx, "https://example.com/second").await;
+        for _ in 0..3 {
+            get(&ctx, &format!("/redirect/{}", first)).await;
+        }
+
+        let reply = admin(&ctx, Method::GET, "/admin/stats", None).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let stats = reply.json();
+        assert_eq!(stats["total_links"], 2);
+        assert_eq!(stats["total_clicks"], 0);
+        assert_eq!(stats["pending_clicks"], 3);
+
+        clicks::flush(&ctx).await.unwrap();
+        let stats = admin(&ctx, Method::GET, "/admin/stats", None).await.json();
+        assert_eq!(stats["total_links"], 2);
+        assert_eq!(stats["total_clicks"], 3);
+        assert_eq!(stats["pending_clicks"], 0);
+    }
+
+    #[tokio::test]
+    async fn debug_hash_predicts_the_code_shorten_hands_out() {
+        let mut ctx = ctx_with(Config {
+            normalize_path: true,
+            ..tests::config()
+        })
+        .await;
+        // padded and unnormalized, as a client might send it
+        let long_url = "  https://example.com//a/./b  ";
+        let debug = debug_hash(&ctx, long_url, None).await.json();
+        assert_eq!(debug["normalized_url"], "https://example.com/a/b");
+        let hash = debug["hash"].as_str().unwrap().to_owned();
+
+        // with the first hash on the wordlist, both move on to the same retry
+        ctx.code_wordlist = Arc::new(Wordlist::parse(&hash));
+        let debug = debug_hash(&ctx, long_url, None).await.json();
+        assert_eq!(debug["hash"], hash.as_str());
+        assert_ne!(debug["short_code"], hash.as_str());
+        let predicted = debug["short_code"].as_str().unwrap().to_owned();
+
+        assert_eq!(shorten(&ctx, long_url).await, predicted);
+        let debug = debug_hash(&ctx, long_url, None).await.json();
+        assert_eq!(debug["existing_code"], predicted.as_str());
+        assert!(debug["collides_with"].is_null());
+    }
+
+    #[tokio::test]
+    async fn debug_hash_resolves_the_domain_like_shorten() {
+        let ctx = ctx_with(Config {
+            domains: vec!["go.brand-a.com".to_owned()],
+            ..tests::config()
+        })
+        .await;
+        let long_url = "https://example.com/branded";
+        let req = Request::post(format!("/shorten?q={}", long_url))
+            .header(header::HOST, "go.brand-a.com")
+            .body(Body::empty())
+            .unwrap();
+        let short_code = call(&ctx, req).await.body;
+
+        let debug = debug_hash(&ctx, long_url, Some("go.brand-a.com"))
+            .await
+            .json();
+        assert_eq!(debug["existing_code"], short_code.as_str());
+        // it's another domain's link, the default one has nothing for the url
+        let debug = debug_hash(&ctx, long_url, None).await.json();
+        assert!(debug["existing_code"].is_null());
+    }
+}
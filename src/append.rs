@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+
+use url::{Url, form_urlencoded};
+
+use crate::{AppCtx, domain};
+
+/// query params in the order they're appended
+pub type Params = Vec<(String, String)>;
+
+/// params from a query string like `ref=ourshortener&utm_medium=link`,
+/// `None` when one of them has no name
+pub fn parse(params: &str) -> Option<Params> {
+    let pairs = form_urlencoded::parse(params.as_bytes())
+        .into_owned()
+        .collect::<Params>();
+    pairs
+        .iter()
+        .all(|(key, _)| !key.is_empty())
+        .then_some(pairs)
+}
+
+/// `params` back as a query string, the form they're stored in
+pub fn to_query(params: &[(String, String)]) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish()
+}
+
+/// `long_url` with `params` added to the end of its query, before any fragment.
+/// params the url already has keep their value, the target knows best
+pub fn apply(long_url: &str, params: &[(String, String)]) -> String {
+    if params.is_empty() {
+        return long_url.to_owned();
+    }
+    let Ok(mut url) = Url::parse(long_url) else {
+        return long_url.to_owned();
+    };
+
+    let present = url
+        .query_pairs()
+        .map(|(key, _)| key.into_owned())
+        .collect::<HashSet<String>>();
+    let missing = params
+        .iter()
+        .filter(|(key, _)| !present.contains(key))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return long_url.to_owned();
+    }
+
+    url.query_pairs_mut().extend_pairs(missing);
+    url.into()
+}
+
+/// `long_url` with the params `short_code` adds, its own if it was shortened
+/// with some or else `APPEND_PARAMS`
+pub fn for_link(ctx: &AppCtx, domain: &str, short_code: &str, long_url: &str) -> String {
+    let overrides = ctx.append_params.read().unwrap();
+    let params = overrides
+        .get(&domain::scoped(domain, short_code))
+        .unwrap_or(&ctx.config.append_params);
+    apply(long_url, params)
+}
+
+/// keep `ctx.append_params` in line with a link's stored `append_params`
+pub fn set(ctx: &AppCtx, domain: &str, short_code: &str, append_params: Option<&str>) {
+    let key = domain::scoped(domain, short_code);
+    let mut overrides = ctx.append_params.write().unwrap();
+    match append_params.and_then(parse) {
+        Some(params) => overrides.insert(key, params),
+        None => overrides.remove(&key),
+    };
+}
+
+/// seed the per-link params, links using `APPEND_PARAMS` aren't kept in memory
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT domain, short_code, append_params AS "append_params!" FROM url WHERE append_params IS NOT NULL"#
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    for row in &rows {
+        set(ctx, &row.domain, &row.short_code, Some(&row.append_params));
+    }
+    println!("loaded {} per-link append params", rows.len());
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+
+use crate::AppCtx;
+
+/// browsers ask for this on every page, so let them keep it for a day
+const FAVICON_CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// crawlers only recheck now and then anyway
+const ROBOTS_CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// read the icon at `FAVICON_PATH` once at startup
+pub fn load_favicon(path: &str) -> std::io::Result<Bytes> {
+    std::fs::read(path).map(Bytes::from)
+}
+
+/// GET /favicon.ico
+///
+/// serves the configured icon, or an empty 204 so the request doesn't 404
+pub async fn favicon(State(ctx): State<AppCtx>) -> impl IntoResponse {
+    match &ctx.favicon {
+        Some(icon) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "image/x-icon"),
+                (header::CACHE_CONTROL, FAVICON_CACHE_CONTROL),
+            ],
+            icon.clone(),
+        )
+            .into_response(),
+        None => (
+            StatusCode::NO_CONTENT,
+            [(header::CACHE_CONTROL, FAVICON_CACHE_CONTROL)],
+        )
+            .into_response(),
+    }
+}
+
+/// read the policy at `ROBOTS_TXT_PATH` once at startup
+pub fn load_robots_txt(path: &str) -> std::io::Result<String> {
+    std::fs::read_to_string(path)
+}
+
+/// GET /robots.txt
+///
+/// the configured policy, otherwise one keeping every crawler off the codes under
+/// `REDIRECT_PREFIX`. at the root any path could be a code, so that's all of them
+pub async fn robots_txt(State(ctx): State<AppCtx>) -> impl IntoResponse {
+    let policy = match &ctx.robots_txt {
+        Some(policy) => policy.clone(),
+        None => format!(
+            "User-agent: *\nDisallow: {}\n",
+            ctx.config.redirect_prefix.path("")
+        ),
+    };
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (header::CACHE_CONTROL, ROBOTS_CACHE_CONTROL),
+        ],
+        policy,
+    )
+}
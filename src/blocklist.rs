@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{AppCtx, admin::AdminAuth};
+
+/// Targets nobody may link to, read from `BLOCKLIST_PATH`
+///
+/// each line is either a domain, which also covers its subdomains, or the
+/// hex sha-256 of a single normalized url. blank lines and `#` comments are skipped
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+    hashes: HashSet<String>,
+}
+
+impl Blocklist {
+    pub fn parse(contents: &str) -> Blocklist {
+        let mut blocklist = Blocklist::default();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line = line.to_ascii_lowercase();
+            if line.len() == 64 && line.bytes().all(|b| b.is_ascii_hexdigit()) {
+                blocklist.hashes.insert(line);
+            } else {
+                blocklist
+                    .domains
+                    .insert(line.trim_end_matches('.').to_owned());
+            }
+        }
+        blocklist
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len() + self.hashes.len()
+    }
+
+    /// whether `long_url`, already normalized, is on the list
+    pub fn matches(&self, long_url: &str) -> bool {
+        if self.hashes.contains(&sha256_hex(long_url)) {
+            return true;
+        }
+
+        let Some(host) = url::Url::parse(long_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|h| h.trim_end_matches('.').to_owned()))
+        else {
+            return false;
+        };
+
+        // the host itself and every parent domain, `a.b.c` -> `a.b.c`, `b.c`, `c`
+        let mut rest = host.as_str();
+        loop {
+            if self.domains.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+fn sha256_hex(s: &str) -> String {
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// read the list at `path`
+pub fn load(path: &str) -> std::io::Result<Blocklist> {
+    std::fs::read_to_string(path).map(|contents| Blocklist::parse(&contents))
+}
+
+/// whether `long_url` is blocklisted right now
+pub fn is_blocked(ctx: &AppCtx, long_url: &str) -> bool {
+    ctx.blocklist.read().unwrap().matches(long_url)
+}
+
+/// 451 for a stored link whose target has been listed since it was created,
+/// only when `BLOCKLIST_ON_REDIRECT` is on
+pub fn check_redirect(ctx: &AppCtx, long_url: &str) -> Result<(), (StatusCode, String)> {
+    if ctx.config.blocklist_on_redirect && is_blocked(ctx, long_url) {
+        println!("\ttarget is blocklisted");
+        return Err((
+            StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            "Link target is blocked".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Reloaded {
+    entries: usize,
+}
+
+/// POST /admin/blocklist/reload
+///
+/// re-reads `BLOCKLIST_PATH`, the old list stays in place if that fails
+pub async fn reload(_: AdminAuth, State(ctx): State<AppCtx>) -> impl IntoResponse {
+    println!("/admin/blocklist/reload POST <--");
+
+    let Some(path) = &ctx.config.blocklist_path else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "No blocklist configured".to_owned(),
+        )
+            .into_response();
+    };
+
+    match load(path) {
+        Ok(blocklist) => {
+            let entries = blocklist.len();
+            *ctx.blocklist.write().unwrap() = blocklist;
+            println!("\treloaded {} blocklist entries", entries);
+            Json(Reloaded { entries }).into_response()
+        }
+        Err(e) => {
+            eprintln!("Failed to reload blocklist: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Probabilistic set of every stored short code.
+///
+/// `might_contain` never says no for a code that was inserted, but may say
+/// yes for one that wasn't, so a "maybe" still has to be confirmed by the db.
+/// Bits can't be cleared, so deleted codes keep answering "maybe".
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// size the filter for `expected` items at roughly `fp_rate` false positives
+    pub fn new(expected: usize, fp_rate: f64) -> BloomFilter {
+        let n = expected.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+
+        // optimal m = -n ln(p) / ln(2)^2, k = m/n ln(2)
+        let num_bits = (-n * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        for idx in self.indexes(item) {
+            self.bits[(idx / 64) as usize] |= 1 << (idx % 64);
+        }
+    }
+
+    pub fn might_contain(&self, item: &str) -> bool {
+        self.indexes(item)
+            .all(|idx| self.bits[(idx / 64) as usize] & (1 << (idx % 64)) != 0)
+    }
+
+    /// Kirsch-Mitzenmacher double hashing, g_i = h1 + i * h2
+    fn indexes(&self, item: &str) -> impl Iterator<Item = u64> + use<> {
+        let h1 = seeded_hash(item, 0);
+        let h2 = seeded_hash(item, 1) | 1;
+        let num_bits = self.num_bits;
+
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+fn seeded_hash(item: &str, seed: u64) -> u64 {
+    let mut s = DefaultHasher::new();
+    seed.hash(&mut s);
+    item.hash(&mut s);
+    s.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        domain::{self, DEFAULT_DOMAIN},
+        tests::{admin, ctx, get, shorten},
+    };
+
+    #[test]
+    fn inserted_items_are_never_missed() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000 {
+            filter.insert(&format!("code{}", i));
+        }
+        assert!((0..10_000).all(|i| filter.might_contain(&format!("code{}", i))));
+    }
+
+    #[test]
+    fn false_positives_stay_near_the_rate_it_was_sized_for() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000 {
+            filter.insert(&format!("code{}", i));
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| filter.might_contain(&format!("other{}", i)))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[tokio::test]
+    async fn a_never_stored_code_is_refused_without_the_db() {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com/stored").await;
+        let key = domain::scoped(DEFAULT_DOMAIN, &short_code);
+        assert!(ctx.code_filter.read().unwrap().might_contain(&key));
+        // so the stored code has to be looked up as well
+        ctx.short_to_long_cache.remove(&key);
+
+        // any lookup that reached the db now would fail with 503
+        ctx.pool.close().await;
+        assert_eq!(
+            get(&ctx, "/redirect/neverstored").await.status,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get(&ctx, &format!("/redirect/{}", short_code)).await.status,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn a_deleted_code_still_goes_to_the_db() {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com/deleted").await;
+        let reply = admin(
+            &ctx,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [short_code] })),
+        )
+        .await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+
+        // bits can't be cleared, the db is what says it's gone
+        assert!(
+            ctx.code_filter
+                .read()
+                .unwrap()
+                .might_contain(&domain::scoped(DEFAULT_DOMAIN, &short_code))
+        );
+        assert_eq!(
+            get(&ctx, &format!("/redirect/{}", short_code)).await.status,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
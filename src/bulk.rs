@@ -0,0 +1,287 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{
+    Json,
+    body::to_bytes,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppCtx, admin::AdminAuth, create_link, keys::ApiKey, query::StrictQuery, read_only::Writable,
+};
+
+/// links accepted in one bulk call
+const MAX_BATCH: usize = 1000;
+
+/// `source` of links made through `/shorten/bulk` that don't name their own
+const BULK_SOURCE: &str = "bulk";
+
+/// `source` of links made through `/links/import` that don't name their own
+const IMPORT_SOURCE: &str = "import";
+
+/// `shorten` params that belong to a single link, never to a whole batch
+const PER_LINK_PARAMS: [&str; 3] = ["q", "alias", "dry_run"];
+
+#[derive(Deserialize)]
+pub struct ImportedLink {
+    long_url: String,
+    /// kept as the link's alias, a fresh code is made when missing
+    short_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Outcome {
+    long_url: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// POST /shorten/bulk
+///
+/// body is a JSON array of long urls, each shortened as `/shorten` would with the
+/// query string applied to all of them. responds with what happened to each one in
+/// the same order, a failed link doesn't stop the rest
+pub async fn shorten(
+    State(ctx): State<AppCtx>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiKey(api_key): ApiKey,
+    _: Writable,
+    StrictQuery(params): StrictQuery,
+    Json(long_urls): Json<Vec<String>>,
+) -> impl IntoResponse {
+    println!("/shorten/bulk POST <-- {} urls", long_urls.len());
+
+    let links = long_urls
+        .into_iter()
+        .map(|long_url| (long_url, None))
+        .collect();
+    run(&ctx, peer, headers, api_key, params, links, BULK_SOURCE).await
+}
+
+/// POST /links/import
+///
+/// body is a JSON array of `{"long_url", "short_code"}` from another shortener,
+/// each keeping its code. otherwise just like `/shorten/bulk`, and the links don't
+/// count toward any API key's quota
+pub async fn import(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    _: Writable,
+    StrictQuery(params): StrictQuery,
+    Json(imported): Json<Vec<ImportedLink>>,
+) -> impl IntoResponse {
+    println!("/links/import POST <-- {} links", imported.len());
+
+    let links = imported
+        .into_iter()
+        .map(|link| (link.long_url, link.short_code))
+        .collect();
+    run(&ctx, peer, headers, None, params, links, IMPORT_SOURCE).await
+}
+
+/// shortens each `(long_url, alias)` in turn, so a url listed twice dedups like
+/// two `/shorten` calls would
+async fn run(
+    ctx: &AppCtx,
+    peer: SocketAddr,
+    headers: HeaderMap,
+    api_key: Option<String>,
+    params: HashMap<String, String>,
+    links: Vec<(String, Option<String>)>,
+    default_source: &str,
+) -> Response {
+    if links.len() > MAX_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("At most {} links per request", MAX_BATCH),
+        )
+            .into_response();
+    }
+    if let Some(param) = PER_LINK_PARAMS.iter().find(|p| params.contains_key(**p)) {
+        println!("\tper-link param {} on a batch", param);
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("{} can't be set for a whole batch", param),
+        )
+            .into_response();
+    }
+
+    let mut outcomes = Vec::with_capacity(links.len());
+    for (long_url, alias) in links {
+        let mut link_params = params.clone();
+        link_params.insert("q".to_owned(), long_url.clone());
+        if let Some(alias) = alias {
+            link_params.insert("alias".to_owned(), alias);
+        }
+
+        let reply = create_link(
+            ctx.clone(),
+            peer,
+            headers.clone(),
+            api_key.clone(),
+            link_params,
+            default_source,
+        )
+        .await;
+        let status = reply.status();
+        let body = match to_bytes(reply.into_body(), usize::MAX).await {
+            Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+            Err(e) => {
+                eprintln!("Failed to read shorten response: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our end".to_owned(),
+                )
+                    .into_response();
+            }
+        };
+
+        let (short_code, error) = if status.is_success() {
+            (Some(body), None)
+        } else {
+            (None, Some(body))
+        };
+        outcomes.push(Outcome {
+            long_url,
+            status: status.as_u16(),
+            short_code,
+            error,
+        });
+    }
+
+    Json(outcomes).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, header},
+    };
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::tests::{Reply, admin, call, ctx, get};
+
+    async fn bulk_shorten(ctx: &AppCtx, query: &str, long_urls: Value) -> Reply {
+        let req = Request::post(format!("/shorten/bulk{}", query))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(long_urls.to_string()))
+            .unwrap();
+        call(ctx, req).await
+    }
+
+    fn sources(reply: &Reply) -> Vec<(String, String)> {
+        reply.json()["links"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|link| {
+                (
+                    link["short_code"].as_str().unwrap().to_owned(),
+                    link["source"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bulk_links_are_tagged_bulk_and_filterable() {
+        let ctx = ctx().await;
+        let api = crate::tests::shorten(&ctx, "https://example.com/api").await;
+
+        let reply = bulk_shorten(
+            &ctx,
+            "",
+            json!(["https://example.com/1", "https://example.com/2"]),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let outcomes = reply.json();
+        assert_eq!(outcomes[0]["status"], 201);
+        assert_eq!(outcomes[1]["status"], 201);
+        let first = outcomes[0]["short_code"].as_str().unwrap().to_owned();
+        let second = outcomes[1]["short_code"].as_str().unwrap().to_owned();
+
+        let reply = admin(&ctx, Method::GET, "/links?source=bulk", None).await;
+        assert_eq!(
+            sources(&reply),
+            vec![(first, "bulk".to_owned()), (second, "bulk".to_owned())]
+        );
+        let reply = admin(&ctx, Method::GET, "/links?source=api", None).await;
+        assert_eq!(sources(&reply), vec![(api, "api".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn a_bad_url_fails_alone() {
+        let ctx = ctx().await;
+        let reply = bulk_shorten(
+            &ctx,
+            "?source=web-form",
+            json!(["https://example.com/ok", "   ", "https://example.com/ok"]),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let outcomes = reply.json();
+        assert_eq!(outcomes[0]["status"], 201);
+        assert_eq!(outcomes[1]["status"], 400);
+        assert_eq!(outcomes[1]["error"], "URL was not provided");
+        // the second copy dedups to the first
+        assert_eq!(outcomes[2]["status"], 200);
+        assert_eq!(outcomes[0]["short_code"], outcomes[2]["short_code"]);
+
+        let reply = admin(&ctx, Method::GET, "/links?source=web-form", None).await;
+        assert_eq!(sources(&reply).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn per_link_params_are_refused_on_a_batch() {
+        let ctx = ctx().await;
+        let reply = bulk_shorten(&ctx, "?alias=shared", json!(["https://example.com/1"])).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn imported_links_keep_their_codes_and_are_tagged_import() {
+        let ctx = ctx().await;
+        let reply = admin(
+            &ctx,
+            Method::POST,
+            "/links/import",
+            Some(json!([
+                { "long_url": "https://example.com/old", "short_code": "legacy-code" },
+                { "long_url": "https://example.com/new" },
+            ])),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let outcomes = reply.json();
+        assert_eq!(outcomes[0]["short_code"], "legacy-code");
+        assert_eq!(outcomes[1]["status"], 201);
+
+        let reply = get(&ctx, "/redirect/legacy-code").await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+        let reply = admin(&ctx, Method::GET, "/links?source=import", None).await;
+        assert_eq!(sources(&reply).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn import_needs_the_admin_token() {
+        let ctx = ctx().await;
+        let req = Request::post("/links/import")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"[{"long_url": "https://example.com"}]"#))
+            .unwrap();
+        let reply = call(&ctx, req).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+    }
+}
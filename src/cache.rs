@@ -0,0 +1,291 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// rough per-entry cost on top of the key and value bytes, the map slot,
+/// the recency index node and the key's second copy in it
+const ENTRY_OVERHEAD: usize = 96;
+
+#[derive(Debug)]
+struct Entry {
+    value: String,
+    last_used: u64,
+    inserted_at: Instant,
+}
+
+/// String map behind both caches, optionally bounded by approximate size.
+///
+/// Without a budget it grows like a plain `HashMap`. With one, inserting past
+/// the budget evicts least recently used entries until it fits again. With a
+/// ttl, entries older than it are dropped the next time they're looked up.
+#[derive(Debug, Default)]
+pub struct Cache {
+    entries: HashMap<String, Entry>,
+    /// keys by their last use, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: usize,
+    max_bytes: Option<usize>,
+    ttl: Option<Duration>,
+}
+
+fn entry_size(key: &str, value: &str) -> usize {
+    key.len() * 2 + value.len() + ENTRY_OVERHEAD
+}
+
+impl Cache {
+    pub fn new(max_bytes: Option<usize>, ttl: Option<Duration>) -> Cache {
+        Cache {
+            max_bytes,
+            ttl,
+            ..Cache::default()
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        self.ttl
+            .is_some_and(|ttl| entry.inserted_at.elapsed() >= ttl)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// value for `key`, counting as a use
+    pub fn get(&mut self, key: &str) -> Option<&String> {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| self.is_expired(entry))
+        {
+            self.remove(key);
+            return None;
+        }
+
+        let now = self.tick();
+        let entry = self.entries.get_mut(key)?;
+
+        let key = self.recency.remove(&entry.last_used)?;
+        self.recency.insert(now, key);
+        entry.last_used = now;
+
+        Some(&entry.value)
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        self.remove(&key);
+
+        let size = entry_size(&key, &value);
+        if self.max_bytes.is_some_and(|max| size > max) {
+            // would evict everything else and still not fit
+            return;
+        }
+
+        let now = self.tick();
+        self.recency.insert(now, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                last_used: now,
+                inserted_at: Instant::now(),
+            },
+        );
+        self.bytes += size;
+
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry_size(key, &entry.value);
+        Some(entry.value)
+    }
+
+    /// drop least recently used entries until back under budget
+    fn evict(&mut self) {
+        let Some(max) = self.max_bytes else {
+            return;
+        };
+        while self.bytes > max {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry_size(&key, &entry.value);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// approximate memory held, what `max_bytes` is compared against
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// plain copy of every entry still within its ttl, for snapshotting
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.entries
+            .iter()
+            .filter(|(_, e)| !self.is_expired(e))
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+            .collect()
+    }
+
+    /// drop every entry, keeping the budget
+    pub fn clear(&mut self) {
+        *self = Cache::new(self.max_bytes, self.ttl);
+    }
+}
+
+/// A `Cache` split into shards by a hash of the key, each behind its own lock.
+///
+/// Lookups for different keys mostly land on different shards and don't wait
+/// on each other. The byte budget is divided evenly, so each shard evicts on
+/// its own and least recently used only holds within a shard.
+#[derive(Debug)]
+pub struct ShardedCache {
+    shards: Box<[Mutex<Cache>]>,
+}
+
+impl ShardedCache {
+    pub fn new(shards: usize, max_bytes: Option<usize>, ttl: Option<Duration>) -> ShardedCache {
+        let shards = shards.max(1);
+        ShardedCache {
+            shards: (0..shards)
+                .map(|_| Mutex::new(Cache::new(max_bytes.map(|max| max / shards), ttl)))
+                .collect(),
+        }
+    }
+
+    /// the shard `key` lives in, for when several steps on it have to happen together
+    pub fn lock(&self, key: &str) -> MutexGuard<'_, Cache> {
+        let mut s = DefaultHasher::new();
+        key.hash(&mut s);
+        let i = s.finish() as usize % self.shards.len();
+        self.shards[i].lock().unwrap()
+    }
+
+    /// value for `key`, counting as a use
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lock(key).get(key).cloned()
+    }
+
+    pub fn insert(&self, key: String, value: String) {
+        self.lock(&key).insert(key, value);
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.lock(key).remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    /// approximate memory held across all shards
+    pub fn bytes(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().bytes()).sum()
+    }
+
+    /// plain copy of every entry, for snapshotting
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.shards
+            .iter()
+            .flat_map(|s| s.lock().unwrap().to_map())
+            .collect()
+    }
+
+    /// replace the contents with `map`, still within budget
+    pub fn fill(&self, map: HashMap<String, String>) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().clear();
+        }
+        for (key, value) in map {
+            self.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const THREADS: usize = 8;
+    const OPS: usize = 200_000;
+    const KEYS: usize = 10_000;
+
+    /// `THREADS` threads each doing `OPS` lookups with an insert every tenth over
+    /// `KEYS` codes, the mix a busy redirect path puts on `short_to_long_cache`
+    fn hammer(cache: &ShardedCache) {
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                s.spawn(move || {
+                    for i in 0..OPS {
+                        let key = format!("code{}", (i * 31 + t * 7) % KEYS);
+                        if i % 10 == 0 {
+                            cache.insert(key, "https://example.com/".to_owned());
+                        } else {
+                            cache.get(&key);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn entries_past_their_ttl_are_dropped() {
+        let mut cache = Cache::new(None, Some(Duration::from_millis(50)));
+        cache.insert("code".to_owned(), "https://example.com/".to_owned());
+        assert_eq!(
+            cache.get("code").map(String::as_str),
+            Some("https://example.com/")
+        );
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(cache.to_map().is_empty());
+        assert_eq!(cache.get("code"), None);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    // cargo test --release cache -- --ignored --nocapture
+    #[test]
+    #[ignore = "timing, run by hand"]
+    fn shards_cut_contention() {
+        let mut timings = Vec::new();
+        for shards in [1, 16] {
+            let cache = ShardedCache::new(shards, None, None);
+            for i in 0..KEYS {
+                cache.insert(format!("code{}", i), "https://example.com/".to_owned());
+            }
+            let started = Instant::now();
+            hammer(&cache);
+            let elapsed = started.elapsed();
+            println!("{:>2} shards: {:?}", shards, elapsed);
+
+            assert_eq!(cache.len(), KEYS);
+            timings.push(elapsed);
+        }
+        // on one core the threads never contend, so there's nothing for shards to win
+        if thread::available_parallelism().is_ok_and(|n| n.get() == 1) {
+            return;
+        }
+        assert!(
+            timings[1] < timings[0],
+            "16 shards weren't faster than 1: {:?}",
+            timings
+        );
+    }
+}
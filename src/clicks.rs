@@ -0,0 +1,208 @@
+use std::time::{Duration, Instant};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    AppCtx, domain, read_only, targets,
+    tasks::{self, Task},
+};
+
+/// count a redirect for `short_code`, it reaches the db on the next flush
+pub fn record(ctx: &AppCtx, domain: &str, short_code: &str) {
+    *ctx.pending_clicks
+        .lock()
+        .unwrap()
+        .entry(domain::scoped(domain, short_code))
+        .or_default() += 1;
+}
+
+/// clicks counted but not flushed yet
+pub fn pending(ctx: &AppCtx) -> i64 {
+    ctx.pending_clicks.lock().unwrap().values().sum()
+}
+
+/// flush pending clicks every `CLICK_FLUSH_INTERVAL_MS`
+pub fn spawn_flusher(ctx: AppCtx) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(
+            ctx.config.click_flush_interval_ms.max(1),
+        ));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            // clicks keep being counted, they're written once writes are back on
+            if read_only::is_on(&ctx) {
+                continue;
+            }
+            let started = Instant::now();
+            let res = flush(&ctx).await;
+            tasks::record(&ctx, Task::ClickFlush, started, &res);
+            if let Err(e) = res {
+                eprintln!("Failed to flush clicks: {}", e);
+            }
+        }
+    });
+}
+
+/// write every pending click to the db, `CLICK_FLUSH_BATCH` links per transaction.
+/// whatever doesn't make it stays pending for the next flush
+pub async fn flush(ctx: &AppCtx) -> Result<usize, sqlx::Error> {
+    let pending = std::mem::take(&mut *ctx.pending_clicks.lock().unwrap());
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    let pending = pending.into_iter().collect::<Vec<_>>();
+    let mut flushed = 0;
+    for batch in pending.chunks(ctx.config.click_flush_batch.max(1)) {
+        if let Err(e) = store_clicks(batch, targets::now(ctx), &ctx.pool).await {
+            // clicks counted since the take are already back in the map, add to them
+            let mut pending_clicks = ctx.pending_clicks.lock().unwrap();
+            for (key, clicks) in &pending[flushed..] {
+                *pending_clicks.entry(key.clone()).or_default() += clicks;
+            }
+            return Err(e);
+        }
+        flushed += batch.len();
+    }
+    Ok(flushed)
+}
+
+/// flush what's still pending once the server has stopped, read-only mode drops it instead
+pub async fn flush_on_shutdown(ctx: &AppCtx) -> Result<usize, sqlx::Error> {
+    // every request has finished by now, so nothing is counted after this
+    if read_only::is_on(ctx) {
+        println!("read-only, dropping {} unflushed clicks", pending(ctx));
+        return Ok(0);
+    }
+    flush(ctx).await
+}
+
+/// S -> D : add_clicks([(short_code, clicks)], now) . D -> S : ok()
+///
+/// pending clicks count towards the day they're flushed on, at most one
+/// interval after they happened
+async fn store_clicks(
+    batch: &[(String, i64)],
+    now: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for (key, clicks) in batch {
+        let Some((domain, short_code)) = domain::unscope(key) else {
+            continue;
+        };
+        sqlx::query!(
+            "UPDATE url SET clicks = clicks + $3, updated_at = $4 WHERE domain = $1 AND short_code = $2",
+            domain,
+            short_code,
+            clicks,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO click_daily (domain, short_code, date, count)
+            VALUES ($1, $2, date($4, 'unixepoch'), $3)
+            ON CONFLICT (domain, short_code, date) DO UPDATE SET count = count + excluded.count",
+            domain,
+            short_code,
+            clicks,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// S -> D : delete_daily(short_code) . D -> S : ok()
+///
+/// the rollup goes with its link, so a later link under the same code starts from nothing
+pub async fn delete_daily(
+    domain: &str,
+    short_code: &str,
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM click_daily WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        domain::DEFAULT_DOMAIN,
+        tests::{self, ctx, ctx_with, get, shorten},
+    };
+
+    async fn stored_clicks(ctx: &AppCtx, short_code: &str) -> i64 {
+        sqlx::query_scalar!(
+            "SELECT clicks FROM url WHERE domain = $1 AND short_code = $2",
+            DEFAULT_DOMAIN,
+            short_code
+        )
+        .fetch_one(&ctx.pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redirects_reach_the_db_on_flush() {
+        // one link per transaction, so the flush takes more than one batch
+        let ctx = ctx_with(Config {
+            click_flush_batch: 1,
+            ..tests::config()
+        })
+        .await;
+        let first = shorten(&ctx, "https://example.com/first").await;
+        let second = shorten(&ctx, "https://example.com/second").await;
+        for short_code in [&first, &first, &second] {
+            get(&ctx, &format!("/redirect/{}", short_code)).await;
+        }
+
+        assert_eq!(pending(&ctx), 3);
+        assert_eq!(stored_clicks(&ctx, &first).await, 0);
+
+        assert_eq!(flush(&ctx).await.unwrap(), 2);
+        assert_eq!(pending(&ctx), 0);
+        assert_eq!(stored_clicks(&ctx, &first).await, 2);
+        assert_eq!(stored_clicks(&ctx, &second).await, 1);
+
+        // nothing pending, nothing written twice
+        assert_eq!(flush(&ctx).await.unwrap(), 0);
+        assert_eq!(stored_clicks(&ctx, &first).await, 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_what_is_pending() {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com/shutdown").await;
+        get(&ctx, &format!("/redirect/{}", short_code)).await;
+
+        assert_eq!(flush_on_shutdown(&ctx).await.unwrap(), 1);
+        assert_eq!(pending(&ctx), 0);
+        assert_eq!(stored_clicks(&ctx, &short_code).await, 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_in_read_only_mode_writes_nothing() {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com/read-only").await;
+        get(&ctx, &format!("/redirect/{}", short_code)).await;
+        ctx.read_only.store(true, Ordering::SeqCst);
+
+        assert_eq!(flush_on_shutdown(&ctx).await.unwrap(), 0);
+        assert_eq!(stored_clicks(&ctx, &short_code).await, 0);
+    }
+}
@@ -0,0 +1,75 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::HeaderMap;
+
+use crate::config::Config;
+
+/// The address of the client that made the request.
+///
+/// Without `TRUST_PROXY` this is always the socket peer. With it, the peer is
+/// taken to be a proxy and the forwarding headers are walked right to left,
+/// skipping hops in `TRUSTED_PROXIES`, until the first address we don't
+/// trust. Everything left of that hop was supplied by the client and could be
+/// spoofed, so it's never looked at.
+pub fn resolve(config: &Config, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let peer = peer.ip();
+    if !config.trust_proxy || (!config.trusted_proxies.is_empty() && !is_trusted(config, peer)) {
+        return peer;
+    }
+
+    let hops = forwarded_for(headers);
+    hops.iter()
+        .rev()
+        .copied()
+        .find(|ip| !is_trusted(config, *ip))
+        // every hop was one of ours, the leftmost is as close to the client as we get
+        .or_else(|| hops.first().copied())
+        .unwrap_or(peer)
+}
+
+fn is_trusted(config: &Config, ip: IpAddr) -> bool {
+    config.trusted_proxies.contains(&ip)
+}
+
+/// chain of forwarded addresses, client first, from `X-Forwarded-For` or else `Forwarded`
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let xff = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(parse_node)
+        .collect::<Vec<_>>();
+    if !xff.is_empty() {
+        return xff;
+    }
+
+    // RFC 7239, `Forwarded: for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .filter_map(parse_node)
+        .collect()
+}
+
+/// a single hop, which may be quoted, bracketed and/or carry a port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // `[v6]` without a port
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
@@ -0,0 +1,201 @@
+use std::{
+    fmt::Debug,
+    hash::{DefaultHasher, Hash, Hasher},
+    str::FromStr,
+    sync::Arc,
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+use crate::{config::Config, fresh_code, hash_url};
+
+/// How `shorten` comes up with a code for a url that wasn't given an alias
+pub trait CodeGenerator: Send + Sync + Debug {
+    /// the code to try for `long_url`, `attempt` counts up from 0 on each clash
+    /// and every attempt gives a different code
+    fn generate(&self, long_url: &str, attempt: u32) -> String;
+
+    /// whether the same url and attempt always give the same code, so a dry run
+    /// can say which code a url would get
+    fn deterministic(&self) -> bool {
+        false
+    }
+}
+
+/// Which `CodeGenerator` `CODE_GENERATOR` picks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodeStrategy {
+    /// a hash of the url, the same url always lands on the same code
+    #[default]
+    Hash,
+    /// unrelated to the url, so codes can't be guessed from it
+    Random,
+    /// a keyed hash of the url, deterministic but only for whoever holds `CODE_HMAC_KEY`
+    Hmac,
+}
+
+impl FromStr for CodeStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(CodeStrategy::Hash),
+            "random" => Ok(CodeStrategy::Random),
+            "hmac" => Ok(CodeStrategy::Hmac),
+            _ => Err(()),
+        }
+    }
+}
+
+/// the generator `CODE_GENERATOR` asks for
+pub fn from_config(config: &Config) -> Arc<dyn CodeGenerator> {
+    match (config.code_generator, &config.code_hmac_key) {
+        (CodeStrategy::Hash, _) => Arc::new(Hashed),
+        (CodeStrategy::Random, _) => Arc::new(Random),
+        (CodeStrategy::Hmac, Some(key)) => Arc::new(Signed {
+            key: key.as_bytes().to_vec(),
+        }),
+        (CodeStrategy::Hmac, None) => {
+            eprintln!("CODE_GENERATOR=hmac needs CODE_HMAC_KEY, hashing urls instead");
+            Arc::new(Hashed)
+        }
+    }
+}
+
+/// `hash_url`, the codes links have always had
+#[derive(Debug)]
+pub struct Hashed;
+
+impl CodeGenerator for Hashed {
+    fn generate(&self, long_url: &str, attempt: u32) -> String {
+        if attempt == 0 {
+            return hash_url(long_url);
+        }
+        let mut s = DefaultHasher::new();
+        long_url.hash(&mut s);
+        attempt.hash(&mut s);
+        format!("{:x}", s.finish())
+    }
+
+    fn deterministic(&self) -> bool {
+        true
+    }
+}
+
+/// `fresh_code` seeded with the url, different every call
+#[derive(Debug)]
+pub struct Random;
+
+impl CodeGenerator for Random {
+    fn generate(&self, long_url: &str, attempt: u32) -> String {
+        fresh_code(long_url, attempt)
+    }
+}
+
+/// HMAC-SHA256 of the url under a secret key, cut to the length of a hashed code
+pub struct Signed {
+    key: Vec<u8>,
+}
+
+// the key stays out of debug output
+impl Debug for Signed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Signed").finish_non_exhaustive()
+    }
+}
+
+impl CodeGenerator for Signed {
+    fn generate(&self, long_url: &str, attempt: u32) -> String {
+        let mut message = long_url.as_bytes().to_vec();
+        if attempt > 0 {
+            message.extend_from_slice(&attempt.to_be_bytes());
+        }
+        hmac_sha256(&self.key, &message)[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn deterministic(&self) -> bool {
+        true
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    // any key length is valid for HMAC, long ones are hashed first
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/some/page";
+
+    fn signed(key: &str) -> Signed {
+        Signed {
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn attempts_give_different_codes() {
+        let generators: [&dyn CodeGenerator; 3] = [&Hashed, &Random, &signed("secret")];
+        for generator in generators {
+            assert_ne!(
+                generator.generate(URL, 0),
+                generator.generate(URL, 1),
+                "{:?}",
+                generator
+            );
+        }
+    }
+
+    #[test]
+    fn deterministic_generators_repeat_themselves() {
+        let generators: [&dyn CodeGenerator; 2] = [&Hashed, &signed("secret")];
+        for generator in generators {
+            assert!(generator.deterministic());
+            for attempt in [0, 1] {
+                assert_eq!(
+                    generator.generate(URL, attempt),
+                    generator.generate(URL, attempt),
+                    "{:?}",
+                    generator
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn signed_codes_depend_on_the_key() {
+        assert_ne!(
+            signed("secret").generate(URL, 0),
+            signed("another secret").generate(URL, 0)
+        );
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        // test case 1
+        assert_eq!(
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        // test case 6, a key longer than a block is hashed first
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
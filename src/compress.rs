@@ -0,0 +1,174 @@
+use sha2::{Digest, Sha256};
+
+use crate::Url;
+
+/// stands in for `long_url` on compressed rows, the unique index and dedup
+/// lookups then see a short fixed-size key instead of the whole url
+const KEY_PREFIX: &str = "sha256:";
+
+/// bytes of the digest kept in a key, plenty to never collide
+const KEY_BYTES: usize = 16;
+
+/// zstd's default level, higher ones cost more time than they save on a url
+const LEVEL: i32 = 3;
+
+/// `long_url` as a zstd frame, `None` when it wouldn't come out smaller with
+/// its key stored beside it
+pub fn compress(long_url: &str) -> Option<Vec<u8>> {
+    let compressed = match zstd::bulk::compress(long_url.as_bytes(), LEVEL) {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            eprintln!("Failed to compress url: {}", e);
+            return None;
+        }
+    };
+
+    let key_len = KEY_PREFIX.len() + KEY_BYTES * 2;
+    (compressed.len() + key_len < long_url.len()).then_some(compressed)
+}
+
+/// the url `compress` was given, `None` for bytes it didn't produce
+pub fn decompress(compressed: &[u8]) -> Option<String> {
+    let bytes = zstd::stream::decode_all(compressed).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// what a compressed row holds in `long_url`, derived from the uncompressed
+/// normalized url so resubmitting it still finds the row
+pub fn key(long_url: &str) -> String {
+    let digest = Sha256::digest(long_url.as_bytes());
+    let hex = digest[..KEY_BYTES]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("{}{}", KEY_PREFIX, hex)
+}
+
+/// the target a row points at, its compressed url if it has one
+pub fn restored(long_url: String, compressed: Option<&[u8]>) -> String {
+    let Some(compressed) = compressed else {
+        return long_url;
+    };
+    match decompress(compressed) {
+        Some(long_url) => long_url,
+        None => {
+            eprintln!("Failed to decompress url stored under {}", long_url);
+            long_url
+        }
+    }
+}
+
+/// `url` with its `long_url` decompressed, for rows read with `SELECT *`
+pub fn restore(mut url: Url) -> Url {
+    url.long_url = restored(url.long_url, url.long_url_compressed.as_deref());
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{StatusCode, header};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        domain::{self, DEFAULT_DOMAIN},
+        lookup_entry,
+        tests::{self, ctx_with, get, shorten},
+    };
+
+    fn long_url() -> String {
+        format!(
+            "https://www.example.com/campaigns/2030/spring?{}",
+            (0..40)
+                .map(|i| format!("utm_content=variant-{}&ref=newsletter", i))
+                .collect::<Vec<_>>()
+                .join("&")
+        )
+    }
+
+    #[test]
+    fn round_trips_and_shrinks_a_long_url() {
+        let long_url = long_url();
+        let compressed = compress(&long_url).unwrap();
+        assert!(
+            compressed.len() < long_url.len() / 4,
+            "{}",
+            compressed.len()
+        );
+        assert_eq!(decompress(&compressed).unwrap(), long_url);
+    }
+
+    #[test]
+    fn short_urls_and_foreign_bytes_are_left_alone() {
+        assert_eq!(compress("https://example.com/a"), None);
+        assert_eq!(decompress(b"not a zstd frame"), None);
+    }
+
+    #[tokio::test]
+    async fn a_long_url_round_trips_through_compressed_storage() {
+        let ctx = ctx_with(Config {
+            compress_urls: true,
+            ..tests::config()
+        })
+        .await;
+        let long_url = long_url();
+        let short_code = shorten(&ctx, &long_url).await;
+
+        // the row only holds the key in place of the url
+        let row = sqlx::query!(
+            "SELECT long_url, long_url_compressed FROM url WHERE short_code = $1",
+            short_code
+        )
+        .fetch_one(&ctx.pool)
+        .await
+        .unwrap();
+        assert_eq!(row.long_url, key(&long_url));
+        assert!(row.long_url_compressed.unwrap().len() < long_url.len());
+
+        let url = lookup_entry(DEFAULT_DOMAIN, &short_code, &ctx.pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(url.long_url, long_url);
+
+        ctx.short_to_long_cache
+            .remove(&domain::scoped(DEFAULT_DOMAIN, &short_code));
+        let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+        assert_eq!(reply.header(header::LOCATION), Some(long_url.as_str()));
+    }
+
+    #[tokio::test]
+    async fn dedup_matches_the_uncompressed_normalized_url() {
+        let shared = tests::SharedDb::new();
+        let plain = ctx_with(shared.config()).await;
+        let long_url = long_url();
+        let stored_plain = shorten(&plain, &long_url).await;
+
+        let compressing = ctx_with(Config {
+            compress_urls: true,
+            normalize_path: true,
+            ..shared.config()
+        })
+        .await;
+        // a row stored before compression was on still dedups
+        assert_eq!(shorten(&compressing, &long_url).await, stored_plain);
+
+        // and compressed rows dedup on the url they hold, after normalization
+        let other = long_url.replace("/campaigns/", "/campaigns/autumn/");
+        let compressed = shorten(&compressing, &other).await;
+        let unnormalized = other.replace("/campaigns/", "/campaigns//./");
+        compressing
+            .long_to_short_cache
+            .remove(&domain::scoped(DEFAULT_DOMAIN, &other));
+        let req = axum::http::Request::post(format!(
+            "/shorten?q={}",
+            url::form_urlencoded::byte_serialize(unnormalized.as_bytes()).collect::<String>()
+        ))
+        .body(axum::body::Body::empty())
+        .unwrap();
+        let reply = tests::call(&compressing, req).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, compressed);
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    env,
+    net::IpAddr,
+    num::{NonZeroU32, NonZeroU64},
+};
+
+use sqlx::sqlite::SqliteSynchronous;
+
+use crate::{
+    access_log::AccessLog, append, code_gen::CodeStrategy, dedup::DedupPolicy, domain,
+    link_check::BrokenLinkBehavior, prefix::RedirectPrefix, privacy::LogUrls, rate_limit::Strategy,
+    redirect_status::RedirectStatus, retry_after::RetryAfterFormat, scheme,
+};
+
+/// Runtime settings, read once from the environment at startup
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// sqlite database to use, `sqlite::memory:` for a throwaway in-memory one
+    pub database_url: String,
+    /// `PRAGMA synchronous`, unset leaves sqlx's `full`
+    pub sqlite_synchronous: Option<SqliteSynchronous>,
+    /// `PRAGMA cache_size`, pages when positive and KiB when negative, unset leaves sqlite's
+    pub sqlite_cache_size: Option<i64>,
+    /// bearer token required by the `/admin` routes, unset means admin is locked
+    pub admin_token: Option<String>,
+    /// where to persist the caches across restarts, unset disables snapshots
+    pub cache_snapshot_path: Option<String>,
+    /// how many codes the bloom filter is sized for before its false-positive rate climbs
+    pub bloom_expected_codes: usize,
+    /// public base urls of every short-link domain served, e.g. `https://go.brand.com`
+    pub domains: Vec<String>,
+    /// keys accepted on `shorten`, empty leaves it open to anyone
+    pub api_keys: Vec<String>,
+    /// api keys tied to a tenant, whose links then live in the tenant's own namespace
+    pub api_key_tenants: Vec<(String, String)>,
+    /// max links a single api key may own, unset is unlimited
+    pub api_key_quota: Option<i64>,
+    /// requests a client may make per `rate_limit_window_secs`, unset disables limiting
+    pub rate_limit: Option<u32>,
+    pub rate_limit_window_secs: u64,
+    pub rate_limit_strategy: Strategy,
+    /// how `Retry-After` is written on 429s and 503s, whole seconds or an http date
+    pub retry_after_format: RetryAfterFormat,
+    /// redirects one link may serve per minute across all clients, unset disables it
+    pub link_rate_limit: Option<u32>,
+    /// honour `X-Forwarded-For`/`Forwarded` when working out the client ip
+    pub trust_proxy: bool,
+    /// proxy hops skipped when `trust_proxy` is on, empty trusts only the socket peer
+    pub trusted_proxies: Vec<IpAddr>,
+    /// icon served at `/favicon.ico`, unset answers with an empty 204
+    pub favicon_path: Option<String>,
+    /// policy served at `/robots.txt`, unset disallows the redirect prefix
+    pub robots_txt_path: Option<String>,
+    /// concurrent `/ws/stats` sockets allowed
+    pub ws_max_connections: usize,
+    /// collapse `//` and resolve `.`/`..` in submitted url paths
+    pub normalize_path: bool,
+    /// decode escaped unreserved characters and uppercase the remaining escapes
+    pub normalize_percent_encoding: bool,
+    /// fetch each new target and store its `<title>`
+    pub fetch_title: bool,
+    /// fetch each new target and store its `og:title`/`og:description`/`og:image`
+    pub fetch_open_graph: bool,
+    /// timeout for every outbound request made on a link's behalf
+    pub fetch_timeout_ms: u64,
+    /// let outbound requests reach loopback/private addresses, for local development only
+    pub allow_private_targets: bool,
+    /// refuse to shorten urls whose host doesn't resolve
+    pub validate_dns: bool,
+    /// schemes links may point at, lowercased. `*` is any but the dangerous ones
+    pub allowed_schemes: Vec<String>,
+    /// how long urls are written to the logs
+    pub log_urls: LogUrls,
+    /// populate both caches from `shorten`, not just from redirects
+    pub cache_on_write: bool,
+    /// whether resubmitting a url returns its existing code or mints a new one
+    pub dedup_policy: DedupPolicy,
+    /// how codes are made for links without an alias
+    pub code_generator: CodeStrategy,
+    /// secret for `CODE_GENERATOR=hmac`
+    pub code_hmac_key: Option<String>,
+    /// newline-delimited words no generated code or alias may contain
+    pub code_wordlist_path: Option<String>,
+    /// query params added to every redirect target, unless a link has its own
+    pub append_params: append::Params,
+    /// status links redirect with unless they were shortened with their own
+    pub redirect_status: RedirectStatus,
+    /// newline-delimited domains and url hashes `shorten` refuses
+    pub blocklist_path: Option<String>,
+    /// also check the blocklist on every redirect, for targets listed after creation
+    pub blocklist_on_redirect: bool,
+    /// approximate size each cache may grow to before evicting least recently used entries
+    pub cache_max_bytes: Option<usize>,
+    /// independently locked pieces each cache is split into, by a hash of the key
+    pub cache_shards: usize,
+    /// seconds a cache entry is trusted for without `PEER_URLS` to evict it, 0 for ever
+    pub cache_ttl_secs: u64,
+    /// seconds between link checker rounds, unset never checks
+    pub link_check_interval_secs: Option<u64>,
+    /// links checked per round, least recently checked first
+    pub link_check_batch: i64,
+    /// pause between two checks in a round, so targets aren't hammered
+    pub link_check_delay_ms: u64,
+    /// what `redirect` does for links the checker last found broken
+    pub broken_link_behavior: BrokenLinkBehavior,
+    /// how often counted redirects are written to the db
+    pub click_flush_interval_ms: u64,
+    /// links whose clicks are written per transaction during a flush
+    pub click_flush_batch: usize,
+    /// `/links/delete` marks links deleted instead of removing them, see `soft_delete`
+    pub soft_delete: bool,
+    /// how long a soft deleted link can be restored before it's removed for good
+    pub soft_delete_grace_secs: i64,
+    /// start refusing writes with 503, `POST /admin/readonly` switches it at runtime
+    pub read_only: bool,
+    /// seconds between background db checks, 0 never checks
+    pub db_health_interval_secs: u64,
+    /// failed checks in a row before `/readyz` reports not ready
+    pub db_unhealthy_after: u32,
+    /// header a proxy puts the visitor's country in, e.g. `CF-IPCountry`, for country targets
+    pub country_header: Option<String>,
+    /// requests served at once before the rest get a 503, unset never sheds
+    pub max_in_flight: Option<usize>,
+    /// record a hash of the creator's ip and their user agent on each new link
+    pub capture_submitter: bool,
+    /// mixed into submitter ip hashes, so they can't be reversed by hashing every address
+    pub submitter_ip_salt: Option<String>,
+    /// page unknown codes redirect to instead of a 404
+    pub not_found_redirect: Option<String>,
+    /// base urls of sibling instances told to evict links changed here
+    pub peer_urls: Vec<String>,
+    /// honour `X-Debug: true` on redirects with headers saying how they were served
+    pub debug_headers: bool,
+    /// machine-readable line per request on stdout
+    pub access_log: AccessLog,
+    /// path `redirect` is served under, short urls are built with it too
+    pub redirect_prefix: RedirectPrefix,
+    /// shortest alias `shorten` accepts
+    pub min_alias_length: usize,
+    /// codes one url may have before `shorten` refuses it another alias, unset allows any
+    pub max_codes_per_url: Option<i64>,
+    /// reuse a connection for several requests
+    pub http_keep_alive: bool,
+    /// close a connection that's gone this long without sending a request, 0 never does
+    pub http_idle_timeout_secs: u64,
+    /// requests one http/2 connection may have in flight at once
+    pub http2_max_concurrent_streams: u32,
+    /// ping idle http/2 connections this often and drop the ones that stop answering
+    pub http2_keep_alive_interval_secs: Option<u64>,
+    /// store long targets compressed, see `compress`
+    pub compress_urls: bool,
+    /// how long a `/links` page is served from memory, 0 always queries the db
+    pub links_cache_ttl_secs: u64,
+    /// OTLP/HTTP collector spans are exported to, unset records none
+    pub otel_endpoint: Option<String>,
+    /// `service.name` on exported spans
+    pub otel_service_name: String,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        Config {
+            database_url: var("DATABASE_URL")
+                .unwrap_or_else(|| "sqlite:urlshortener.db".to_owned()),
+            sqlite_synchronous: parse_opt("SQLITE_SYNCHRONOUS"),
+            sqlite_cache_size: parse_opt("SQLITE_CACHE_SIZE"),
+            admin_token: var("ADMIN_TOKEN"),
+            cache_snapshot_path: var("CACHE_SNAPSHOT_PATH"),
+            bloom_expected_codes: parse("BLOOM_EXPECTED_CODES", 1_000_000),
+            domains: list("DOMAINS"),
+            api_keys: list("API_KEYS"),
+            api_key_tenants: pairs("API_KEY_TENANTS"),
+            api_key_quota: parse_opt("API_KEY_QUOTA"),
+            // a limit or window of 0 would never let anything through, so both are malformed,
+            // the same goes for `LINK_RATE_LIMIT`
+            rate_limit: parse_opt("RATE_LIMIT").map(NonZeroU32::get),
+            rate_limit_window_secs: parse("RATE_LIMIT_WINDOW_SECS", NonZeroU64::new(60).unwrap())
+                .get(),
+            rate_limit_strategy: parse("RATE_LIMIT_STRATEGY", Strategy::TokenBucket),
+            retry_after_format: parse("RETRY_AFTER_FORMAT", RetryAfterFormat::Seconds),
+            link_rate_limit: parse_opt("LINK_RATE_LIMIT").map(NonZeroU32::get),
+            trust_proxy: flag("TRUST_PROXY", false),
+            trusted_proxies: list("TRUSTED_PROXIES")
+                .iter()
+                .filter_map(|ip| match ip.parse() {
+                    Ok(ip) => Some(ip),
+                    Err(_) => {
+                        eprintln!("ignoring malformed TRUSTED_PROXIES entry {}", ip);
+                        None
+                    }
+                })
+                .collect(),
+            favicon_path: var("FAVICON_PATH"),
+            robots_txt_path: var("ROBOTS_TXT_PATH"),
+            ws_max_connections: parse("WS_MAX_CONNECTIONS", 100),
+            normalize_path: flag("NORMALIZE_PATH", false),
+            normalize_percent_encoding: flag("NORMALIZE_PERCENT_ENCODING", false),
+            fetch_title: flag("FETCH_TITLE", false),
+            fetch_open_graph: flag("FETCH_OPEN_GRAPH", false),
+            fetch_timeout_ms: parse("FETCH_TIMEOUT_MS", 2000),
+            allow_private_targets: flag("ALLOW_PRIVATE_TARGETS", false),
+            validate_dns: flag("VALIDATE_DNS", false),
+            allowed_schemes: schemes("ALLOWED_SCHEMES"),
+            log_urls: parse("LOG_URLS", LogUrls::Redacted),
+            cache_on_write: flag("CACHE_ON_WRITE", true),
+            dedup_policy: parse("DEDUP_POLICY", DedupPolicy::Reuse),
+            append_params: var("APPEND_PARAMS")
+                .and_then(|params| {
+                    let parsed = append::parse(&params);
+                    if parsed.is_none() {
+                        eprintln!("ignoring malformed APPEND_PARAMS");
+                    }
+                    parsed
+                })
+                .unwrap_or_default(),
+            code_generator: parse("CODE_GENERATOR", CodeStrategy::Hash),
+            code_hmac_key: var("CODE_HMAC_KEY"),
+            code_wordlist_path: var("CODE_WORDLIST_PATH"),
+            redirect_status: parse("REDIRECT_STATUS", RedirectStatus::PermanentRedirect),
+            blocklist_path: var("BLOCKLIST_PATH"),
+            blocklist_on_redirect: flag("BLOCKLIST_ON_REDIRECT", false),
+            cache_max_bytes: parse_opt("CACHE_MAX_BYTES"),
+            cache_shards: parse("CACHE_SHARDS", 1).max(1),
+            cache_ttl_secs: parse("CACHE_TTL_SECS", 300),
+            link_check_interval_secs: parse_opt("LINK_CHECK_INTERVAL_SECS"),
+            link_check_batch: parse("LINK_CHECK_BATCH", 20),
+            link_check_delay_ms: parse("LINK_CHECK_DELAY_MS", 500),
+            broken_link_behavior: parse("BROKEN_LINK_BEHAVIOR", BrokenLinkBehavior::Redirect),
+            click_flush_interval_ms: parse("CLICK_FLUSH_INTERVAL_MS", 1000),
+            click_flush_batch: parse("CLICK_FLUSH_BATCH", 500),
+            soft_delete: flag("SOFT_DELETE", false),
+            soft_delete_grace_secs: parse("SOFT_DELETE_GRACE_SECS", 7 * 24 * 60 * 60).max(0),
+            read_only: flag("READ_ONLY", false),
+            db_health_interval_secs: parse("DB_HEALTH_INTERVAL_SECS", 10),
+            db_unhealthy_after: parse("DB_UNHEALTHY_AFTER", 3).max(1),
+            country_header: var("COUNTRY_HEADER").map(|h| h.to_ascii_lowercase()),
+            max_in_flight: parse_opt("MAX_IN_FLIGHT"),
+            capture_submitter: flag("CAPTURE_SUBMITTER", false),
+            submitter_ip_salt: var("SUBMITTER_IP_SALT"),
+            not_found_redirect: var("NOT_FOUND_REDIRECT"),
+            peer_urls: list("PEER_URLS"),
+            access_log: parse("ACCESS_LOG", AccessLog::Off),
+            redirect_prefix: parse("REDIRECT_PREFIX", RedirectPrefix::default()),
+            min_alias_length: parse("MIN_ALIAS_LENGTH", 3),
+            max_codes_per_url: parse_opt("MAX_CODES_PER_URL"),
+            debug_headers: flag("DEBUG_HEADERS", false),
+            http_keep_alive: flag("HTTP_KEEP_ALIVE", true),
+            http_idle_timeout_secs: parse("HTTP_IDLE_TIMEOUT_SECS", 60),
+            // 0 would refuse every stream
+            http2_max_concurrent_streams: parse(
+                "HTTP2_MAX_CONCURRENT_STREAMS",
+                NonZeroU32::new(200).unwrap(),
+            )
+            .get(),
+            http2_keep_alive_interval_secs: parse_opt("HTTP2_KEEP_ALIVE_INTERVAL_SECS")
+                .map(NonZeroU64::get),
+            compress_urls: flag("COMPRESS_URLS", false),
+            links_cache_ttl_secs: parse("LINKS_CACHE_TTL_SECS", 5),
+            otel_endpoint: var("OTEL_EXPORTER_OTLP_ENDPOINT"),
+            otel_service_name: var("OTEL_SERVICE_NAME")
+                .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned()),
+        }
+    }
+}
+
+/// an env var that is set and non-empty
+fn var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|v| !v.is_empty())
+}
+
+/// a boolean env var, `true`/`1` or `false`/`0`
+fn flag(key: &str, default: bool) -> bool {
+    match var(key).as_deref() {
+        Some("true" | "1") => true,
+        Some("false" | "0") => false,
+        Some(_) => {
+            eprintln!("ignoring malformed {}, using the default", key);
+            default
+        }
+        None => default,
+    }
+}
+
+/// a comma-separated env var, empty entries are skipped
+fn list(key: &str) -> Vec<String> {
+    var(key)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_owned())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// a comma-separated list of url schemes, `http,https` when unset or empty
+fn schemes(key: &str) -> Vec<String> {
+    let schemes = list(key)
+        .into_iter()
+        .map(|scheme| scheme.trim_end_matches(':').to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if schemes.is_empty() {
+        return scheme::DEFAULT_SCHEMES.map(str::to_owned).to_vec();
+    }
+    schemes
+}
+
+/// a comma-separated list of `key:tenant` pairs, malformed entries are skipped
+fn pairs(key: &str) -> Vec<(String, String)> {
+    list(key)
+        .into_iter()
+        .filter_map(|item| match item.split_once(':') {
+            Some((k, tenant)) if !k.is_empty() && domain::is_tenant(tenant) => {
+                Some((k.to_owned(), tenant.to_ascii_lowercase()))
+            }
+            _ => {
+                eprintln!("ignoring malformed {} entry", key);
+                None
+            }
+        })
+        .collect()
+}
+
+/// an env var parsed as `T`, `None` when unset or malformed
+fn parse_opt<T: std::str::FromStr>(key: &str) -> Option<T> {
+    let v = var(key)?;
+    match v.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            eprintln!("ignoring malformed {}", key);
+            None
+        }
+    }
+}
+
+/// an env var parsed as `T`, falling back to `default` when unset or malformed
+fn parse<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match var(key).map(|v| v.parse()) {
+        Some(Ok(v)) => v,
+        Some(Err(_)) => {
+            eprintln!("ignoring malformed {}, using the default", key);
+            default
+        }
+        None => default,
+    }
+}
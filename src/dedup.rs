@@ -0,0 +1,23 @@
+use std::str::FromStr;
+
+/// What `shorten` does with a url that already has a code in its domain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupPolicy {
+    /// same url, same code, resubmitting returns the existing one
+    #[default]
+    Reuse,
+    /// a distinct code every time, for tracking separate shares of one url apart
+    AlwaysNew,
+}
+
+impl FromStr for DedupPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reuse" => Ok(DedupPolicy::Reuse),
+            "always_new" => Ok(DedupPolicy::AlwaysNew),
+            _ => Err(()),
+        }
+    }
+}
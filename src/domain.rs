@@ -0,0 +1,144 @@
+use axum::http::{HeaderMap, StatusCode, header};
+
+use crate::config::Config;
+
+/// Domain used for requests that don't match any configured domain,
+/// and for every link created before multi-domain support
+pub const DEFAULT_DOMAIN: &str = "";
+
+/// Header naming the tenant a lookup resolves in, when `API_KEY_TENANTS` is set
+pub const TENANT_HEADER: &str = "x-tenant";
+
+/// host[:port] part of a base url, `https://go.brand.com/` -> `go.brand.com`
+pub fn authority(base_url: &str) -> &str {
+    let rest = base_url
+        .split_once("://")
+        .map_or(base_url, |(_, rest)| rest);
+    rest.split('/').next().unwrap_or(rest)
+}
+
+/// the configured domain whose host matches `host`, if any
+fn configured<'a>(config: &'a Config, host: &str) -> Option<&'a str> {
+    config
+        .domains
+        .iter()
+        .map(|base_url| authority(base_url))
+        .find(|domain| domain.eq_ignore_ascii_case(host))
+}
+
+/// configured base url of `domain`, without a trailing slash.
+/// a tenant's namespace gets its subdomain of the configured one
+pub fn base_url(config: &Config, domain: &str) -> Option<String> {
+    let find = |domain: &str| {
+        config
+            .domains
+            .iter()
+            .find(|base_url| authority(base_url).eq_ignore_ascii_case(domain))
+            .map(|base_url| base_url.trim_end_matches('/'))
+    };
+
+    if let Some(base_url) = find(domain) {
+        return Some(base_url.to_owned());
+    }
+    let (_, parent) = domain.split_once('.')?;
+    find(parent).map(|base_url| base_url.replacen(authority(base_url), domain, 1))
+}
+
+fn host_header(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::HOST).and_then(|h| h.to_str().ok())
+}
+
+/// whether `label` can name a tenant, which has to work as a subdomain
+pub fn is_tenant(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// namespace holding `tenant`'s links on `domain`, the same as its subdomain
+pub fn for_tenant(domain: &str, tenant: &str) -> String {
+    let tenant = tenant.to_ascii_lowercase();
+    if domain == DEFAULT_DOMAIN {
+        tenant
+    } else {
+        format!("{}.{}", tenant, domain)
+    }
+}
+
+/// tenant `key`'s links go to, `None` for keys that aren't tied to one
+pub fn tenant_of_key<'a>(config: &'a Config, key: &str) -> Option<&'a str> {
+    config
+        .api_key_tenants
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, tenant)| tenant.as_str())
+}
+
+/// configured domain a request was sent to, and the tenant it names, either
+/// by `X-Tenant` or by being sent to the tenant's subdomain.
+/// never a tenant when `API_KEY_TENANTS` isn't set
+fn addressed<'a>(config: &'a Config, headers: &HeaderMap) -> (&'a str, Option<String>) {
+    let host = host_header(headers);
+    if let Some(domain) = host.and_then(|host| configured(config, host)) {
+        return (domain, tenant_header(config, headers));
+    }
+
+    if !config.api_key_tenants.is_empty()
+        && let Some((label, parent)) = host.and_then(|host| host.split_once('.'))
+        && is_tenant(label)
+        && let Some(domain) = configured(config, parent)
+    {
+        return (domain, Some(label.to_owned()));
+    }
+
+    (DEFAULT_DOMAIN, tenant_header(config, headers))
+}
+
+fn tenant_header(config: &Config, headers: &HeaderMap) -> Option<String> {
+    if config.api_key_tenants.is_empty() {
+        return None;
+    }
+    headers
+        .get(TENANT_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|tenant| is_tenant(tenant))
+        .map(|tenant| tenant.to_owned())
+}
+
+/// namespace a lookup should resolve in, picked from the incoming `Host` and tenant
+pub fn from_host(config: &Config, headers: &HeaderMap) -> String {
+    match addressed(config, headers) {
+        (domain, Some(tenant)) => for_tenant(domain, &tenant),
+        (domain, None) => domain.to_owned(),
+    }
+}
+
+/// namespace a new link goes into, an explicit `domain` param wins over the `Host`.
+/// the tenant only ever comes from the api key, never from the request
+pub fn for_shorten(
+    config: &Config,
+    requested: Option<&String>,
+    headers: &HeaderMap,
+    tenant: Option<&str>,
+) -> Result<String, (StatusCode, String)> {
+    let domain = match requested {
+        Some(requested) => configured(config, requested)
+            .ok_or((StatusCode::BAD_REQUEST, "Unknown domain".to_owned()))?,
+        None => addressed(config, headers).0,
+    };
+    Ok(match tenant {
+        Some(tenant) => for_tenant(domain, tenant),
+        None => domain.to_owned(),
+    })
+}
+
+/// cache/filter key for `key` within `domain`, domains never contain a `/`
+pub fn scoped(domain: &str, key: &str) -> String {
+    format!("{}/{}", domain, key)
+}
+
+/// inverse of `scoped`
+pub fn unscope(scoped: &str) -> Option<(&str, &str)> {
+    scoped.split_once('/')
+}
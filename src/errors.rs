@@ -0,0 +1,162 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+use crate::access_log::RequestId;
+
+/// plain text errors are one line, a bigger body wasn't written by us and is left alone
+const MAX_MESSAGE_BYTES: usize = 16 * 1024;
+
+/// What every 4xx/5xx goes out as, `{"error": {"code", "message", "request_id"}}`
+#[derive(Serialize)]
+struct Envelope<'a> {
+    error: Details<'a>,
+}
+
+#[derive(Serialize)]
+struct Details<'a> {
+    /// the status's reason in snake case, `not_found`, `too_many_requests`
+    code: String,
+    message: &'a str,
+    request_id: &'a str,
+}
+
+/// `Too Many Requests` -> `too_many_requests`
+fn code(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_ascii_lowercase()
+        .replace([' ', '-'], "_")
+        .replace('\'', "")
+}
+
+/// middleware wrapping every plain text error in the JSON envelope, so handlers
+/// keep answering `(StatusCode, String)` and clients still get one shape.
+/// pages meant for browsers and bodies that are already JSON pass through
+pub async fn envelope(req: Request, next: Next) -> Response {
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default();
+    let res = next.run(req).await;
+
+    let status = res.status();
+    let plain_text = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|v| v.to_str().is_ok_and(|v| v.starts_with("text/plain")));
+    if !(status.is_client_error() || status.is_server_error()) || !plain_text {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let message = axum::body::to_bytes(body, MAX_MESSAGE_BYTES)
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_owned())
+        .unwrap_or_default();
+    // a bare status like the 401 from `AdminAuth` still gets a message
+    let message = if message.is_empty() {
+        status.canonical_reason().unwrap_or("Error").to_owned()
+    } else {
+        message
+    };
+
+    let body = serde_json::to_vec(&Envelope {
+        error: Details {
+            code: code(status),
+            message: &message,
+            request_id: &request_id,
+        },
+    })
+    .unwrap();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, extract::Path, middleware, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::access_log::{self, REQUEST_ID_HEADER};
+
+    /// answers `/{status}?message` the way handlers do, as plain text
+    async fn fail(Path(status): Path<u16>, req: Request) -> (StatusCode, String) {
+        let message = req.uri().query().unwrap_or_default().replace("%20", " ");
+        (StatusCode::from_u16(status).unwrap(), message)
+    }
+
+    #[tokio::test]
+    async fn every_error_status_gets_the_envelope() {
+        let app = Router::new()
+            .route("/{status}", get(fail))
+            .layer(middleware::from_fn(envelope))
+            .layer(middleware::from_fn(access_log::request_id));
+
+        let cases = [
+            (400, "bad_request", "URL was not provided"),
+            // a bare status, like the one `AdminAuth` rejects with
+            (401, "unauthorized", ""),
+            (403, "forbidden", "API key quota exceeded"),
+            (404, "not_found", "Short code not found"),
+            (409, "conflict", "Alias is already taken"),
+            (410, "gone", "Link has been deleted"),
+            (413, "payload_too_large", "Request body is too large"),
+            (429, "too_many_requests", "Too many requests"),
+            (
+                500,
+                "internal_server_error",
+                "Something went wrong on our end",
+            ),
+            (503, "service_unavailable", "Server is overloaded"),
+        ];
+        for (status, code, message) in cases {
+            let req = Request::get(format!("/{}?{}", status, message.replace(' ', "%20")))
+                .header(REQUEST_ID_HEADER, format!("req-{}", status))
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+
+            assert_eq!(res.status().as_u16(), status);
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                "application/json"
+            );
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let expected_message = if message.is_empty() {
+                StatusCode::from_u16(status)
+                    .unwrap()
+                    .canonical_reason()
+                    .unwrap()
+            } else {
+                message
+            };
+            assert_eq!(
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+                serde_json::json!({
+                    "error": {
+                        "code": code,
+                        "message": expected_message,
+                        "request_id": format!("req-{}", status),
+                    }
+                }),
+                "for {}",
+                status
+            );
+        }
+    }
+}
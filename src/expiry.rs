@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use axum::http::StatusCode;
+
+use crate::{AppCtx, domain, targets};
+
+/// when a link created now should expire, from `ttl_seconds` or `expires_at` (RFC 3339).
+/// both may be given as long as they name the same second, give or take one for the
+/// time the request took to arrive
+pub fn from_params(
+    params: &HashMap<String, String>,
+    now: i64,
+) -> Result<Option<i64>, (StatusCode, String)> {
+    let from_ttl = match params.get("ttl_seconds").map(|t| t.parse::<i64>()) {
+        Some(Ok(ttl)) if ttl > 0 => Some(now.saturating_add(ttl)),
+        Some(_) => return Err((StatusCode::BAD_REQUEST, "Invalid ttl_seconds".to_owned())),
+        None => None,
+    };
+    let absolute = match params.get("expires_at").map(|e| parse_timestamp(e)) {
+        Some(Some(expires_at)) if expires_at > now => Some(expires_at),
+        Some(Some(_)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "expires_at is in the past".to_owned(),
+            ));
+        }
+        Some(None) => return Err((StatusCode::BAD_REQUEST, "Invalid expires_at".to_owned())),
+        None => None,
+    };
+
+    match (from_ttl, absolute) {
+        (Some(a), Some(b)) if (a - b).abs() > 1 => Err((
+            StatusCode::BAD_REQUEST,
+            "ttl_seconds and expires_at disagree".to_owned(),
+        )),
+        (from_ttl, absolute) => Ok(absolute.or(from_ttl)),
+    }
+}
+
+/// unix seconds for an RFC 3339 timestamp, `2030-01-31T12:00:00Z` or with an offset
+/// like `+02:00`. fractional seconds are accepted and dropped
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+
+    let mut date = date.splitn(3, '-');
+    let year = number(date.next()?, 4)?;
+    let month = number(date.next()?, 2)?;
+    let day = number(date.next()?, 2)?;
+
+    let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(i) => time.split_at(i),
+        None => return None,
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let secs = number(hours, 2)? * 3600 + number(minutes, 2)? * 60;
+            if offset.starts_with('-') { -secs } else { secs }
+        }
+    };
+
+    let clock = match clock.split_once('.') {
+        Some((whole, fraction))
+            if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            whole
+        }
+        Some(_) => return None,
+        None => clock,
+    };
+    let mut clock = clock.splitn(3, ':');
+    let hour = number(clock.next()?, 2)?;
+    let minute = number(clock.next()?, 2)?;
+    // 60 is a leap second
+    let second = number(clock.next()?, 2)?;
+
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// a run of exactly `len` ascii digits
+fn number(s: &str, len: usize) -> Option<i64> {
+    if s.len() != len || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// days since 1970-01-01 in the proleptic gregorian calendar, Howard Hinnant's algorithm
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// whether `short_code` has an expiry at all, passed or not
+pub fn expires(ctx: &AppCtx, domain: &str, short_code: &str) -> bool {
+    ctx.expiries
+        .read()
+        .unwrap()
+        .contains_key(&domain::scoped(domain, short_code))
+}
+
+/// whether `short_code` had an expiry and it has passed
+pub fn is_expired(ctx: &AppCtx, domain: &str, short_code: &str) -> bool {
+    ctx.expiries
+        .read()
+        .unwrap()
+        .get(&domain::scoped(domain, short_code))
+        .is_some_and(|expires_at| *expires_at <= targets::now(ctx))
+}
+
+/// keep `ctx.expiries` in line with a link's stored `expires_at`
+pub fn set(ctx: &AppCtx, domain: &str, short_code: &str, expires_at: Option<i64>) {
+    let key = domain::scoped(domain, short_code);
+    let mut expiries = ctx.expiries.write().unwrap();
+    match expires_at {
+        Some(expires_at) => expiries.insert(key, expires_at),
+        None => expiries.remove(&key),
+    };
+}
+
+/// seed the expiries, links that never expire aren't kept in memory
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT domain, short_code, expires_at AS "expires_at!" FROM url WHERE expires_at IS NOT NULL"#
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let mut expiries = ctx.expiries.write().unwrap();
+    for row in &rows {
+        expiries.insert(domain::scoped(&row.domain, &row.short_code), row.expires_at);
+    }
+    println!("loaded {} link expiries", rows.len());
+    Ok(())
+}
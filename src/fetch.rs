@@ -0,0 +1,314 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::{
+    Client, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+
+use crate::config::Config;
+
+/// only the `<head>` is of interest, so stop reading well before large pages end
+const MAX_BODY_BYTES: usize = 64 * 1024;
+const MAX_REDIRECTS: usize = 5;
+/// `VALIDATE_DNS` sits in front of every create, so it can't wait on a slow resolver for long
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether `ip` is somewhere on the public internet, rather than loopback,
+/// the local network, or another range we should never be made to call.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // carrier-grade nat 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // ietf protocol assignments 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link local fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // documentation 2001:db8::/32
+        || (first == 0x2001 && ip.segments()[1] == 0xdb8))
+}
+
+/// DNS resolver that drops every non-public address, so a hostname pointing
+/// at internal infrastructure can't be fetched, even via a redirect.
+/// Resolving and connecting happen together, which also rules out rebinding.
+struct GuardedResolver;
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// whether `url`'s host is allowed on its face, ip literals skip dns so are checked here
+pub fn allowed_target(config: &Config, url: &Url) -> bool {
+    if config.allow_private_targets {
+        return true;
+    }
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        Some(url::Host::Domain(_)) => true,
+        None => false,
+    }
+}
+
+/// Why `check_host` turned a url down
+pub enum HostError {
+    /// no such name, or no answer within `DNS_TIMEOUT`
+    Unresolved,
+    /// resolves, but only to addresses the ssrf guard would refuse to call
+    NotPublic,
+}
+
+/// resolve `url`'s host once for `VALIDATE_DNS`, and hold what it resolved to
+/// against the same rule the ssrf guard applies
+pub async fn check_host(config: &Config, url: &Url) -> Result<(), HostError> {
+    let host = match url.host() {
+        Some(url::Host::Domain(host)) => host,
+        Some(_) => {
+            // ip literals have nothing to resolve
+            return if allowed_target(config, url) {
+                Ok(())
+            } else {
+                Err(HostError::NotPublic)
+            };
+        }
+        None => return Err(HostError::Unresolved),
+    };
+
+    let addrs = match tokio::time::timeout(DNS_TIMEOUT, tokio::net::lookup_host((host, 0))).await {
+        Ok(Ok(addrs)) => addrs.collect::<Vec<SocketAddr>>(),
+        Ok(Err(_)) | Err(_) => return Err(HostError::Unresolved),
+    };
+    if addrs.is_empty() {
+        Err(HostError::Unresolved)
+    } else if !config.allow_private_targets && !addrs.iter().any(|addr| is_public(addr.ip())) {
+        Err(HostError::NotPublic)
+    } else {
+        Ok(())
+    }
+}
+
+/// Client for every outbound request made on a link's behalf
+pub fn client(config: &Config) -> Client {
+    let guard_config = config.clone();
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.stop()
+        } else if !allowed_target(&guard_config, attempt.url()) {
+            attempt.error("redirected to a private address")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    let mut builder = Client::builder()
+        .timeout(Duration::from_millis(config.fetch_timeout_ms))
+        .redirect(policy)
+        .user_agent(concat!("url_shortener/", env!("CARGO_PKG_VERSION")));
+    if !config.allow_private_targets {
+        builder = builder.dns_resolver(std::sync::Arc::new(GuardedResolver));
+    }
+
+    builder.build().expect("failed to build http client")
+}
+
+/// GET `long_url` and return the start of its body, `None` on any failure
+pub async fn get_page(config: &Config, client: &Client, long_url: &str) -> Option<String> {
+    let url = Url::parse(long_url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || !allowed_target(config, &url) {
+        return None;
+    }
+
+    let mut res = match client.get(url).send().await {
+        Ok(res) if res.status().is_success() => res,
+        Ok(res) => {
+            println!("\tfetch got {}", res.status());
+            return None;
+        }
+        Err(e) => {
+            // reqwest puts the url in its errors, which the log policy may not allow
+            println!("\tfetch failed: {}", e.without_url());
+            return None;
+        }
+    };
+
+    let mut body = Vec::new();
+    while body.len() < MAX_BODY_BYTES {
+        match res.chunk().await {
+            Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => {
+                println!("\tfetch failed mid-body: {}", e.without_url());
+                break;
+            }
+        }
+    }
+    body.truncate(MAX_BODY_BYTES);
+
+    Some(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// text of the page's `<title>`, whitespace collapsed and basic entities decoded
+pub fn extract_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+
+    let title = decode_entities(&html[start..end]);
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+/// Open Graph tags a page declares about itself, each may be missing
+#[derive(Debug, Default)]
+pub struct OpenGraph {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
+
+/// `og:title`, `og:description` and `og:image` from the page's `<meta>` tags
+pub fn extract_open_graph(html: &str) -> OpenGraph {
+    let mut og = OpenGraph::default();
+    let lower = html.to_ascii_lowercase();
+
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("<meta") {
+        let start = from + i;
+        let Some(len) = lower[start..].find('>') else {
+            break;
+        };
+        let tag = &html[start + "<meta".len()..start + len];
+        from = start + len;
+
+        let attrs = parse_attrs(tag);
+        let attr = |key: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.as_str())
+        };
+
+        // sites use `name=` about as often as the spec'd `property=`
+        let Some(property) = attr("property").or_else(|| attr("name")) else {
+            continue;
+        };
+        let Some(content) = attr("content").map(|c| decode_entities(c.trim())) else {
+            continue;
+        };
+        if content.is_empty() {
+            continue;
+        }
+
+        // first declaration wins, like the unfurlers do
+        let slot = match property.to_ascii_lowercase().as_str() {
+            "og:title" => &mut og.title,
+            "og:description" => &mut og.description,
+            "og:image" => &mut og.image,
+            _ => continue,
+        };
+        slot.get_or_insert(content);
+    }
+
+    og
+}
+
+/// `key="value"` pairs of a tag, quoted with `"` or `'` or unquoted
+fn parse_attrs(tag: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag.trim_start();
+
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '/')
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = rest[key_end..].trim_start();
+
+        let value = if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            match after_eq.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let body = &after_eq[1..];
+                    let end = body.find(quote).unwrap_or(body.len());
+                    rest = body.get(end + 1..).unwrap_or("");
+                    &body[..end]
+                }
+                _ => {
+                    let end = after_eq.find(char::is_whitespace).unwrap_or(after_eq.len());
+                    rest = &after_eq[end..];
+                    &after_eq[..end]
+                }
+            }
+        } else {
+            ""
+        };
+
+        if key.is_empty() {
+            // stray `/` or similar, skip a char so we always make progress
+            rest = rest.get(1..).unwrap_or("");
+        } else {
+            attrs.push((key.to_owned(), value.to_owned()));
+        }
+        rest = rest.trim_start();
+    }
+
+    attrs
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        // last, so `&amp;lt;` becomes `&lt;` rather than `<`
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,210 @@
+use std::time::{Duration, Instant};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    AppCtx, targets,
+    tasks::{self, Task},
+};
+
+/// a db that takes longer than this to answer counts as down
+const DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// probe routes, never shed or rate limited, or a busy instance would look dead
+/// and get restarted
+const PROBES: &[&str] = &["/health", "/livez", "/ping", "/readyz"];
+
+/// whether `path` is one of the probe routes
+pub fn is_probe(path: &str) -> bool {
+    PROBES.contains(&path)
+}
+
+/// What the last background checks made of the db, see `spawn_monitor`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PoolHealth {
+    /// false once `DB_UNHEALTHY_AFTER` checks in a row have failed
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    /// unix seconds, 0 before the first check
+    pub last_checked_at: i64,
+    pub last_error: Option<String>,
+    /// open connections, idle or not
+    pub connections: u32,
+    pub idle_connections: usize,
+}
+
+impl PoolHealth {
+    /// healthy until proven otherwise, so startup doesn't wait on a first check
+    pub fn new() -> PoolHealth {
+        PoolHealth {
+            healthy: true,
+            ..PoolHealth::default()
+        }
+    }
+}
+
+/// whether the monitor has given up on the db, lookups then fail fast instead of
+/// each waiting out the pool's acquire timeout
+pub fn is_down(ctx: &AppCtx) -> bool {
+    !ctx.pool_health.read().unwrap().healthy
+}
+
+/// what a request that needed the db gets while it isn't answering
+pub fn unavailable() -> (StatusCode, String) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Database unavailable".to_owned(),
+    )
+}
+
+/// how long a client turned away by `unavailable` should wait, the next check may
+/// find the db back
+pub fn retry_in(ctx: &AppCtx) -> Duration {
+    Duration::from_secs(ctx.config.db_health_interval_secs.max(1))
+}
+
+/// `SELECT 1` every `DB_HEALTH_INTERVAL_SECS`, so a failing db shows up on
+/// `/readyz` before the next request stumbles into it. 0 never checks
+pub fn spawn_monitor(ctx: AppCtx) {
+    if ctx.config.db_health_interval_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(ctx.config.db_health_interval_secs));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            check_pool(&ctx).await;
+        }
+    });
+}
+
+async fn check_pool(ctx: &AppCtx) {
+    let started = Instant::now();
+    let res =
+        match tokio::time::timeout(DB_TIMEOUT, sqlx::query("SELECT 1").execute(&ctx.pool)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timed out".to_owned()),
+        };
+    tasks::record(ctx, Task::DbHealth, started, &res.as_ref().map(|_| 0));
+
+    let mut health = ctx.pool_health.write().unwrap();
+    health.last_checked_at = targets::now(ctx);
+    health.connections = ctx.pool.size();
+    health.idle_connections = ctx.pool.num_idle();
+    match res {
+        Ok(()) => {
+            if !health.healthy {
+                println!(
+                    "database reachable again after {} failed checks",
+                    health.consecutive_failures
+                );
+            }
+            health.healthy = true;
+            health.consecutive_failures = 0;
+            health.last_error = None;
+        }
+        Err(e) => {
+            health.consecutive_failures += 1;
+            if health.healthy && health.consecutive_failures >= ctx.config.db_unhealthy_after {
+                eprintln!(
+                    "Database unreachable for {} checks, reporting not ready: {}",
+                    health.consecutive_failures, e
+                );
+                health.healthy = false;
+            }
+            health.last_error = Some(e);
+        }
+    }
+}
+
+/// GET /livez, GET /health
+///
+/// the process is up and serving, says nothing about the db
+pub async fn livez() -> impl IntoResponse {
+    (StatusCode::OK, "ok".to_owned())
+}
+
+/// GET /ping
+///
+/// `pong`, for monitors that only check the http server answers. same as `/livez`
+/// under the name those tools tend to expect
+pub async fn ping() -> impl IntoResponse {
+    (StatusCode::OK, "pong".to_owned())
+}
+
+/// GET /readyz
+///
+/// 200 once the db answers and every migration this build knows about has run,
+/// the background checks haven't given up on the db and no critical background
+/// task has stopped running
+pub async fn readyz(State(ctx): State<AppCtx>) -> impl IntoResponse {
+    if let Some((task, quiet)) = tasks::stalled(&ctx) {
+        println!("/readyz GET <-- not ready: {} stalled", task);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("background task {} hasn't run for {}s", task, quiet),
+        );
+    }
+
+    // a lucky answer now doesn't outweigh several failed checks in a row
+    let failures = {
+        let health = ctx.pool_health.read().unwrap();
+        (!health.healthy).then_some(health.consecutive_failures)
+    };
+    if let Some(failures) = failures {
+        println!("/readyz GET <-- not ready: db failing health checks");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("database failed its last {} health checks", failures),
+        );
+    }
+
+    match tokio::time::timeout(DB_TIMEOUT, check_db(&ctx)).await {
+        Ok(Ok(())) => (StatusCode::OK, "ready".to_owned()),
+        Ok(Err(reason)) => {
+            println!("/readyz GET <-- not ready: {}", reason);
+            (StatusCode::SERVICE_UNAVAILABLE, reason)
+        }
+        Err(_) => {
+            println!("/readyz GET <-- not ready: db timed out");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database timed out".to_owned(),
+            )
+        }
+    }
+}
+
+async fn check_db(ctx: &AppCtx) -> Result<(), String> {
+    sqlx::query("SELECT 1")
+        .execute(&ctx.pool)
+        .await
+        .map_err(|e| format!("database unreachable: {}", e))?;
+
+    let latest = sqlx::migrate!("./migrations")
+        .iter()
+        .map(|m| m.version)
+        .max()
+        .unwrap_or(0);
+    let applied: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success = 1")
+            .fetch_one(&ctx.pool)
+            .await
+            .map_err(|e| format!("migrations not readable: {}", e))?;
+
+    if applied.unwrap_or(0) < latest {
+        return Err(format!(
+            "migrations pending (at {}, want {})",
+            applied.unwrap_or(0),
+            latest
+        ));
+    }
+    Ok(())
+}
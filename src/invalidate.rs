@@ -0,0 +1,295 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    Json,
+    extract::State,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppCtx,
+    admin::AdminAuth,
+    append, domain, expiry, link_check,
+    redirect_status::RedirectStatus,
+    soft_delete, suffix,
+    tasks::{self, Task},
+};
+
+/// peers that don't answer within this are skipped, their entries will be stale until evicted
+const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A link whose cached state changed on the instance that sent it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Changed {
+    pub short_code: String,
+    /// what it pointed at before the change, to find its long-to-short entry
+    pub long_url: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Invalidation {
+    domain: String,
+    links: Vec<Changed>,
+}
+
+/// Client for talking to `PEER_URLS`, which are usually internal so skip the ssrf guard
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(PEER_TIMEOUT)
+        .build()
+        .expect("failed to build peer client")
+}
+
+/// C -> P : invalidate(domain, links), fire and forget to every peer
+pub fn broadcast(ctx: &AppCtx, domain: &str, links: Vec<Changed>) {
+    if ctx.config.peer_urls.is_empty() || links.is_empty() {
+        return;
+    }
+
+    let ctx = ctx.clone();
+    let body = Invalidation {
+        domain: domain.to_owned(),
+        links,
+    };
+    tokio::spawn(async move {
+        let started = Instant::now();
+        // one run per broadcast, failed when any peer missed it
+        let mut reached = Ok(body.links.len());
+        for peer in &ctx.config.peer_urls {
+            let mut req = ctx
+                .peer_http
+                .post(format!(
+                    "{}/admin/cache/invalidate",
+                    peer.trim_end_matches('/')
+                ))
+                .json(&body);
+            if let Some(token) = &ctx.config.admin_token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+
+            match req.send().await {
+                Ok(res) if res.status().is_success() => {}
+                Ok(res) => {
+                    eprintln!("Peer {} refused invalidation: {}", peer, res.status());
+                    reached = Err(());
+                }
+                Err(e) => {
+                    eprintln!("Failed to reach peer {}: {}", peer, e.without_url());
+                    reached = Err(());
+                }
+            }
+        }
+        tasks::record(&ctx, Task::Invalidation, started, &reached);
+    });
+}
+
+/// POST /admin/cache/invalidate
+///
+/// sent by peers after changing links, evicts them here and re-reads what
+/// they look like now. never forwarded on, every instance broadcasts its own changes
+pub async fn invalidate(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    Json(req): Json<Invalidation>,
+) -> impl IntoResponse {
+    println!("/admin/cache/invalidate POST <-- {} links", req.links.len());
+
+    for link in &req.links {
+        ctx.short_to_long_cache
+            .remove(&domain::scoped(&req.domain, &link.short_code));
+
+        // another code may have taken the url over since
+        let lts_key = domain::scoped(&req.domain, &link.long_url);
+        // acquire lock
+        let mut long_to_short_cache = ctx.long_to_short_cache.lock(&lts_key);
+        if long_to_short_cache.get(&lts_key) == Some(&link.short_code) {
+            long_to_short_cache.remove(&lts_key);
+        }
+        // release lock
+    }
+
+    for link in &req.links {
+        if let Err(e) = resync(&ctx, &req.domain, &link.short_code).await {
+            eprintln!("Failed to resync {}: {}", link.short_code, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    }
+    println!("\tevicted {} links", req.links.len());
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// bring the in-memory per-link state for `short_code` back in line with the db
+async fn resync(ctx: &AppCtx, domain: &str, short_code: &str) -> Result<(), sqlx::Error> {
+    let key = domain::scoped(domain, short_code);
+
+    let row = sqlx::query!(
+        "SELECT redirect_status, last_status, expires_at, append_params, forward_suffix, deleted_at FROM url WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code
+    )
+    .fetch_optional(&ctx.pool)
+    .await?;
+    let has_targets = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM link_target WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code
+    )
+    .fetch_one(&ctx.pool)
+    .await?
+        > 0;
+
+    // codes created elsewhere have to get into our filter too, or we'd never look them up
+    if row.is_some() {
+        ctx.code_filter.write().unwrap().insert(&key);
+    }
+    ctx.links_cache.bust();
+    expiry::set(
+        ctx,
+        domain,
+        short_code,
+        row.as_ref().and_then(|r| r.expires_at),
+    );
+    soft_delete::set(
+        ctx,
+        domain,
+        short_code,
+        row.as_ref().and_then(|r| r.deleted_at),
+    );
+    append::set(
+        ctx,
+        domain,
+        short_code,
+        row.as_ref().and_then(|r| r.append_params.as_deref()),
+    );
+    suffix::set(
+        ctx,
+        domain,
+        short_code,
+        row.as_ref().is_some_and(|r| r.forward_suffix),
+    );
+    link_check::flag(
+        ctx,
+        domain,
+        short_code,
+        row.as_ref().and_then(|r| r.last_status),
+    );
+
+    match row
+        .and_then(|r| r.redirect_status)
+        .and_then(RedirectStatus::from_code)
+    {
+        Some(status) => ctx
+            .redirect_statuses
+            .write()
+            .unwrap()
+            .insert(key.clone(), status),
+        None => ctx.redirect_statuses.write().unwrap().remove(&key),
+    };
+
+    let mut targeted = ctx.targeted.write().unwrap();
+    if has_targets {
+        targeted.insert(key);
+    } else {
+        targeted.remove(&key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::{
+        AppCtx,
+        config::Config,
+        tests::{SharedDb, admin, ctx_with, eventually, get, serve, shorten},
+    };
+
+    /// two instances on one db, `a` telling `b` about its changes
+    async fn peers(db: &SharedDb) -> (AppCtx, AppCtx) {
+        let b = ctx_with(db.config()).await;
+        let a = ctx_with(Config {
+            peer_urls: vec![serve(&b).await],
+            ..db.config()
+        })
+        .await;
+        (a, b)
+    }
+
+    async fn status(ctx: &AppCtx, short_code: &str) -> StatusCode {
+        get(ctx, &format!("/redirect/{}", short_code)).await.status
+    }
+
+    #[tokio::test]
+    async fn a_peer_learns_about_new_links() {
+        let db = SharedDb::new();
+        let (a, b) = peers(&db).await;
+
+        let short_code = shorten(&a, "https://example.com/new").await;
+
+        // without the broadcast `b`'s bloom filter would answer 404 until it restarted
+        assert!(
+            eventually(async || status(&b, &short_code).await.is_redirection()).await,
+            "peer never served the new link"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_delete_evicts_the_peer_cache() {
+        let db = SharedDb::new();
+        let (a, b) = peers(&db).await;
+        let short_code = shorten(&a, "https://example.com/doomed").await;
+        assert!(eventually(async || status(&b, &short_code).await.is_redirection()).await);
+        // `b` now serves it from its cache
+        assert!(b.short_to_long_cache.len() > 0);
+
+        let reply = admin(
+            &a,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [short_code] })),
+        )
+        .await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+
+        assert!(
+            eventually(async || status(&b, &short_code).await == StatusCode::NOT_FOUND).await,
+            "peer kept serving the deleted link"
+        );
+    }
+
+    #[tokio::test]
+    async fn without_peers_the_cache_forgets_other_instances_changes() {
+        let db = SharedDb::new();
+        let b = ctx_with(Config {
+            cache_ttl_secs: 1,
+            ..db.config()
+        })
+        .await;
+        let a = ctx_with(db.config()).await;
+        let short_code = shorten(&b, "https://example.com/stale").await;
+        assert!(status(&b, &short_code).await.is_redirection());
+
+        let reply = admin(
+            &a,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [short_code] })),
+        )
+        .await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+
+        // nobody told `b`, it only finds out once its entry has expired
+        assert!(status(&b, &short_code).await.is_redirection());
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert_eq!(status(&b, &short_code).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OwnedMutexGuard;
+
+/// One async lock per key, created on first use and dropped once nobody holds or waits on it
+#[derive(Debug, Default)]
+pub struct KeyedLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Held for as long as the key is locked
+pub struct KeyGuard {
+    guard: Option<OwnedMutexGuard<()>>,
+    key: String,
+    locks: Arc<KeyedLocks>,
+}
+
+impl KeyedLocks {
+    /// wait for `key`, the flag says whether someone else had it first
+    pub async fn lock(self: &Arc<Self>, key: &str) -> (KeyGuard, bool) {
+        let lock = {
+            // acquire lock
+            let mut locks = self.locks.lock().unwrap();
+            locks.entry(key.to_owned()).or_default().clone()
+            // release lock
+        };
+
+        let (guard, contended) = match lock.clone().try_lock_owned() {
+            Ok(guard) => (guard, false),
+            Err(_) => (lock.lock_owned().await, true),
+        };
+
+        (
+            KeyGuard {
+                guard: Some(guard),
+                key: key.to_owned(),
+                locks: self.clone(),
+            },
+            contended,
+        )
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+
+        // acquire lock
+        let mut locks = self.locks.locks.lock().unwrap();
+        // only the map's own handle left, nobody is waiting
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+        // release lock
+    }
+}
@@ -0,0 +1,216 @@
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, State},
+    http::{StatusCode, request::Parts},
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::{AppCtx, admin::AdminAuth, targets};
+
+/// Header clients send their API key in
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// The API key a request was made with.
+///
+/// `None` only when no keys are configured, in which case the service is open.
+/// Once `API_KEYS` is set every request using this extractor must carry one of them.
+pub struct ApiKey(pub Option<String>);
+
+impl FromRequestParts<AppCtx> for ApiKey {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, ctx: &AppCtx) -> Result<Self, Self::Rejection> {
+        if ctx.config.api_keys.is_empty() {
+            return Ok(ApiKey(None));
+        }
+
+        let provided = parts
+            .headers
+            .get(API_KEY_HEADER)
+            .and_then(|v| v.to_str().ok());
+
+        match provided {
+            Some(key) if ctx.config.api_keys.iter().any(|k| k == key) => {
+                Ok(ApiKey(Some(key.to_owned())))
+            }
+            _ => Err((StatusCode::UNAUTHORIZED, "Invalid API key".to_owned())),
+        }
+    }
+}
+
+/// how many of the links `key` created are still live at `now`, soft deleted
+/// and expired ones don't count toward its quota
+pub async fn links_created_by(
+    key: &str,
+    now: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM url WHERE created_by = $1 AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $2)",
+        key,
+        now
+    )
+    .fetch_one(pool)
+    .await
+}
+
+#[derive(Serialize)]
+struct Usage {
+    key: String,
+    links: i64,
+    limit: Option<i64>,
+}
+
+/// GET /admin/keys/{key}/usage
+pub async fn usage(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    println!("/admin/keys/{{key}}/usage GET <--");
+
+    if !ctx.config.api_keys.contains(&key) {
+        return (StatusCode::NOT_FOUND, "API key not recognised".to_owned()).into_response();
+    }
+
+    match links_created_by(&key, targets::now(&ctx), &ctx.pool).await {
+        Ok(links) => Json(Usage {
+            key,
+            links,
+            limit: ctx.config.api_key_quota,
+        })
+        .into_response(),
+        Err(e) => {
+            eprintln!("Failed to count links for key: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        tests::{self, Reply, admin, call, ctx_with, stop_clock},
+    };
+
+    const KEY: &str = "client-key";
+
+    async fn with_quota(api_key_quota: i64) -> AppCtx {
+        ctx_with(Config {
+            api_keys: vec![KEY.to_owned()],
+            api_key_quota: Some(api_key_quota),
+            ..tests::config()
+        })
+        .await
+    }
+
+    async fn shorten_with_key(ctx: &AppCtx, query: &str) -> Reply {
+        let req = Request::post(format!("/shorten?{}", query))
+            .header(API_KEY_HEADER, KEY)
+            .body(Body::empty())
+            .unwrap();
+        call(ctx, req).await
+    }
+
+    #[tokio::test]
+    async fn a_full_quota_refuses_new_links() {
+        let ctx = with_quota(2).await;
+        for n in 0..2 {
+            let reply = shorten_with_key(&ctx, &format!("q=https://example.com/{}", n)).await;
+            assert_eq!(reply.status, StatusCode::CREATED);
+        }
+
+        let reply = shorten_with_key(&ctx, "q=https://example.com/over").await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert_eq!(reply.json()["error"]["message"], "API key quota exceeded");
+
+        // an existing link is handed back, it isn't a new one
+        let reply = shorten_with_key(&ctx, "q=https://example.com/0").await;
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn deleted_links_free_up_the_quota() {
+        let ctx = with_quota(1).await;
+        let short_code = shorten_with_key(&ctx, "q=https://example.com/first")
+            .await
+            .body;
+        let reply = shorten_with_key(&ctx, "q=https://example.com/second").await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+        let reply = admin(
+            &ctx,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [short_code] })),
+        )
+        .await;
+        assert!(reply.status.is_success());
+
+        let reply = shorten_with_key(&ctx, "q=https://example.com/second").await;
+        assert_eq!(reply.status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn soft_deleted_and_expired_links_dont_count() {
+        let mut ctx = ctx_with(Config {
+            api_keys: vec![KEY.to_owned()],
+            api_key_quota: Some(2),
+            soft_delete: true,
+            ..tests::config()
+        })
+        .await;
+        let clock = stop_clock(&mut ctx, 1_700_000_000);
+
+        let deleted = shorten_with_key(&ctx, "q=https://example.com/deleted")
+            .await
+            .body;
+        shorten_with_key(&ctx, "q=https://example.com/expiring&ttl_seconds=60").await;
+        let reply = shorten_with_key(&ctx, "q=https://example.com/over").await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+        admin(
+            &ctx,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [deleted] })),
+        )
+        .await;
+        let reply = admin(
+            &ctx,
+            Method::GET,
+            &format!("/admin/keys/{}/usage", KEY),
+            None,
+        )
+        .await;
+        assert_eq!(reply.json()["links"], 1);
+
+        clock.advance(60);
+        let reply = admin(
+            &ctx,
+            Method::GET,
+            &format!("/admin/keys/{}/usage", KEY),
+            None,
+        )
+        .await;
+        assert_eq!(reply.json()["links"], 0);
+
+        for n in 0..2 {
+            let reply = shorten_with_key(&ctx, &format!("q=https://example.com/{}", n)).await;
+            assert_eq!(reply.status, StatusCode::CREATED);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    AppCtx,
+    admin::AdminAuth,
+    compress,
+    config::Config,
+    domain, fetch, read_only, targets,
+    tasks::{self, Task},
+};
+
+/// `last_status` for a target that didn't answer at all, dns failure, timeout, refused
+pub const UNREACHABLE: i64 = 0;
+
+/// whether a `last_status` means the link leads nowhere useful
+pub fn is_broken(last_status: i64) -> bool {
+    last_status == UNREACHABLE || last_status >= 400
+}
+
+/// What `redirect` does for a link the checker last found broken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrokenLinkBehavior {
+    /// send visitors on anyway, the check may have been a blip
+    #[default]
+    Redirect,
+    /// a page saying the target looks broken, with a link to carry on
+    Warn,
+    /// 410, as if the link was gone
+    Gone,
+}
+
+impl FromStr for BrokenLinkBehavior {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redirect" => Ok(BrokenLinkBehavior::Redirect),
+            "warn" => Ok(BrokenLinkBehavior::Warn),
+            "gone" => Ok(BrokenLinkBehavior::Gone),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Due {
+    domain: String,
+    short_code: String,
+    long_url: String,
+    long_url_compressed: Option<Vec<u8>>,
+}
+
+/// seed the set of broken links from what earlier checks recorded
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let codes = sqlx::query!(
+        "SELECT domain, short_code FROM url WHERE last_status = $1 OR last_status >= 400",
+        UNREACHABLE
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let mut broken = ctx.broken.write().unwrap();
+    for code in &codes {
+        broken.insert(domain::scoped(&code.domain, &code.short_code));
+    }
+    println!("loaded {} broken links", codes.len());
+    Ok(())
+}
+
+/// keep `ctx.broken` in line with a freshly recorded `last_status`
+pub fn flag(ctx: &AppCtx, domain: &str, short_code: &str, last_status: Option<i64>) {
+    let key = domain::scoped(domain, short_code);
+    let mut broken = ctx.broken.write().unwrap();
+    if last_status.is_some_and(is_broken) {
+        broken.insert(key);
+    } else {
+        broken.remove(&key);
+    }
+}
+
+/// what `redirect` answers instead of sending visitors to a broken target,
+/// `None` to redirect as usual
+pub fn intercept(ctx: &AppCtx, domain: &str, short_code: &str, long_url: &str) -> Option<Response> {
+    if ctx.config.broken_link_behavior == BrokenLinkBehavior::Redirect
+        // only the link's own url is checked, not where rotating targets lead
+        || targets::rotates(ctx, domain, short_code)
+        || !ctx
+            .broken
+            .read()
+            .unwrap()
+            .contains(&domain::scoped(domain, short_code))
+    {
+        return None;
+    }
+
+    match ctx.config.broken_link_behavior {
+        BrokenLinkBehavior::Redirect => None,
+        BrokenLinkBehavior::Warn => {
+            println!("\ttarget looks broken, warning");
+            Some(Html(warning_page(long_url)).into_response())
+        }
+        BrokenLinkBehavior::Gone => {
+            println!("\ttarget looks broken, gone");
+            Some((StatusCode::GONE, "Link target is gone".to_owned()).into_response())
+        }
+    }
+}
+
+fn warning_page(long_url: &str) -> String {
+    let long_url = escape_html(long_url);
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>This link may be broken</title>
+    <style>
+        body {{ font-family: system-ui, sans-serif; text-align: center; padding: 4rem 1rem; color: #333; }}
+        h1 {{ font-size: 2rem; margin-bottom: 0.5rem; }}
+    </style>
+</head>
+<body>
+    <h1>This link may be broken</h1>
+    <p>The page it leads to didn't load when we last checked.</p>
+    <p><a href="{long_url}" rel="noreferrer">Continue to {long_url}</a></p>
+</body>
+</html>
+"#
+    )
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// check a batch of links every `LINK_CHECK_INTERVAL_SECS`, does nothing when that's unset
+pub fn spawn_checker(ctx: AppCtx) {
+    let Some(secs) = ctx.config.link_check_interval_secs else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs.max(1)));
+        // a slow round just pushes the next one back, rounds never pile up
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if read_only::is_on(&ctx) {
+                continue;
+            }
+            let started = Instant::now();
+            let res = check_round(&ctx).await;
+            tasks::record(&ctx, Task::LinkCheck, started, &res);
+            if let Err(e) = res {
+                eprintln!("Failed to check links: {}", e);
+            }
+        }
+    });
+}
+
+/// HEAD the least recently checked links one at a time and record what came back,
+/// responds with how many were checked
+async fn check_round(ctx: &AppCtx) -> Result<usize, sqlx::Error> {
+    let due = lookup_due(ctx.config.link_check_batch, &ctx.pool).await?;
+    let delay = Duration::from_millis(ctx.config.link_check_delay_ms);
+
+    let mut broken = 0;
+    for (i, link) in due.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(delay).await;
+        }
+        let long_url =
+            compress::restored(link.long_url.clone(), link.long_url_compressed.as_deref());
+        let status = check(&ctx.config, &ctx.http, &long_url).await;
+        if status.is_some_and(is_broken) {
+            broken += 1;
+        }
+        store_check(
+            &link.domain,
+            &link.short_code,
+            status,
+            targets::now(ctx),
+            &ctx.pool,
+        )
+        .await?;
+        flag(ctx, &link.domain, &link.short_code, status);
+    }
+
+    if !due.is_empty() {
+        println!("checked {} links, {} broken", due.len(), broken);
+    }
+    Ok(due.len())
+}
+
+/// status `long_url` answers with, `None` when it's not something we'd ever call
+pub async fn check(config: &Config, client: &Client, long_url: &str) -> Option<i64> {
+    let url = Url::parse(long_url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || !fetch::allowed_target(config, &url) {
+        return None;
+    }
+
+    let res = match client.head(url.clone()).send().await {
+        // plenty of servers don't do HEAD, ask for the page instead and drop the body
+        Ok(res)
+            if matches!(
+                res.status(),
+                StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+            ) =>
+        {
+            client.get(url).send().await
+        }
+        res => res,
+    };
+
+    Some(match res {
+        Ok(res) => res.status().as_u16() as i64,
+        Err(e) => {
+            // the client already keeps each check within FETCH_TIMEOUT_MS
+            println!("\tcheck failed: {}", e.without_url());
+            UNREACHABLE
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct Broken {
+    short_code: String,
+    domain: String,
+    long_url: String,
+    last_status: i64,
+    last_checked_at: i64,
+    #[serde(skip)]
+    long_url_compressed: Option<Vec<u8>>,
+}
+
+/// GET /links/broken
+///
+/// links whose target last answered 4xx/5xx or couldn't be reached, most recently checked first
+pub async fn broken(_: AdminAuth, State(ctx): State<AppCtx>) -> impl IntoResponse {
+    println!("/links/broken GET <--");
+
+    match lookup_broken(&ctx.pool).await {
+        Ok(broken) => {
+            println!("\t{} broken links", broken.len());
+            Json(broken).into_response()
+        }
+        Err(e) => {
+            eprintln!("Failed to look up broken links: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// S -> D : lookup_due(batch) . D -> S : ok([Due])
+async fn lookup_due(batch: i64, pool: &sqlx::SqlitePool) -> Result<Vec<Due>, sqlx::Error> {
+    // nulls sort first, so links never checked go before everything else.
+    // soft deleted links don't redirect, whether their target works doesn't matter
+    sqlx::query_as!(
+        Due,
+        "SELECT domain, short_code, long_url, long_url_compressed FROM url WHERE deleted_at IS NULL ORDER BY last_checked_at LIMIT $1",
+        batch
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// S -> D : store_check(short_code, last_status, now) . D -> S : ok()
+async fn store_check(
+    domain: &str,
+    short_code: &str,
+    last_status: Option<i64>,
+    now: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE url SET last_status = $3, last_checked_at = $4 WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code,
+        last_status,
+        now
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// S -> D : lookup_broken() . D -> S : ok([Broken])
+async fn lookup_broken(pool: &sqlx::SqlitePool) -> Result<Vec<Broken>, sqlx::Error> {
+    sqlx::query_as!(
+        Broken,
+        r#"SELECT short_code, domain, long_url,
+                  last_status AS "last_status!", last_checked_at AS "last_checked_at!",
+                  long_url_compressed
+           FROM url WHERE (last_status = $1 OR last_status >= 400) AND deleted_at IS NULL
+           ORDER BY last_checked_at DESC"#,
+        UNREACHABLE
+    )
+    .fetch_all(pool)
+    .await
+    .map(|rows| {
+        rows.into_iter()
+            .map(|mut row| {
+                row.long_url = compress::restored(row.long_url, row.long_url_compressed.as_deref());
+                row
+            })
+            .collect()
+    })
+}
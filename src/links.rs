@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppCtx, Url,
+    admin::AdminAuth,
+    clicks, compress,
+    domain::{self, DEFAULT_DOMAIN},
+    invalidate::{self, Changed},
+    read_only::Writable,
+    soft_delete, targets,
+};
+
+/// links per page when `?limit=` isn't given, and the most one page holds
+const DEFAULT_PAGE: i64 = 100;
+const MAX_PAGE: i64 = 1000;
+
+/// pages kept by `PageCache`, far more than a few dashboards polling need
+const MAX_CACHED_PAGES: usize = 256;
+
+#[derive(Clone, Serialize)]
+struct Listed {
+    short_code: String,
+    long_url: String,
+    description: Option<String>,
+    source: String,
+    clicks: i64,
+}
+
+#[derive(Clone, Serialize)]
+struct Page {
+    links: Vec<Listed>,
+    /// pass back as `?cursor=` for the next page, `None` on the last one
+    next_cursor: Option<String>,
+}
+
+/// `/links` pages served in the last `LINKS_CACHE_TTL_SECS`, so dashboards polling
+/// the same page don't each scan the table
+#[derive(Default)]
+pub struct PageCache {
+    pages: Mutex<HashMap<String, (Instant, Page)>>,
+    /// bumped on every bust, a page read before one isn't stored after it
+    generation: AtomicU64,
+}
+
+// a page of links is too much to dump into `AppCtx`'s debug output
+impl std::fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PageCache").finish_non_exhaustive()
+    }
+}
+
+impl PageCache {
+    fn get(&self, key: &str, ttl: Duration) -> Option<Page> {
+        let pages = self.pages.lock().unwrap();
+        let (stored_at, page) = pages.get(key)?;
+        (stored_at.elapsed() < ttl).then(|| page.clone())
+    }
+
+    fn put(&self, key: String, generation: u64, page: Page, ttl: Duration) {
+        let mut pages = self.pages.lock().unwrap();
+        // checked under the lock, so a bust can't land between the check and the insert
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if pages.len() >= MAX_CACHED_PAGES {
+            pages.retain(|_, (stored_at, _)| stored_at.elapsed() < ttl);
+        }
+        if pages.len() < MAX_CACHED_PAGES {
+            pages.insert(key, (Instant::now(), page));
+        }
+    }
+
+    /// drop every page, for when links are created, changed or deleted
+    pub fn bust(&self) {
+        let mut pages = self.pages.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        pages.clear();
+    }
+}
+
+/// rowids are stable and only grow, so "after this rowid" is the same place
+/// however many links are added or deleted in between
+fn encode_cursor(rowid: i64) -> String {
+    format!("{:x}", rowid)
+}
+
+fn decode_cursor(cursor: &str) -> Option<i64> {
+    i64::from_str_radix(cursor, 16)
+        .ok()
+        .filter(|rowid| *rowid > 0)
+}
+
+/// GET /links
+///
+/// every link in a domain, oldest first, `?limit=` at a time. `?cursor=` walks
+/// the whole table without skipping or repeating links that change under it,
+/// `?offset=` is there for quick looks. `?domain=` picks the domain when it
+/// isn't the default one, `?source=` keeps the links that came in through that channel
+pub async fn list(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    println!("/links GET <--");
+
+    let domain = params.get("domain").map_or(DEFAULT_DOMAIN, |d| d.as_str());
+    let source = params.get("source").map(|s| s.as_str());
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        Some(Ok(limit)) if (1..=MAX_PAGE).contains(&limit) => limit,
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("limit must be between 1 and {}", MAX_PAGE),
+            )
+                .into_response();
+        }
+        None => DEFAULT_PAGE,
+    };
+    let after = match (params.get("cursor"), params.get("offset")) {
+        (Some(_), Some(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Pass either cursor or offset, not both".to_owned(),
+            )
+                .into_response();
+        }
+        (Some(cursor), None) => match decode_cursor(cursor) {
+            Some(rowid) => After::Cursor(rowid),
+            None => return (StatusCode::BAD_REQUEST, "Invalid cursor".to_owned()).into_response(),
+        },
+        (None, Some(offset)) => match offset.parse::<i64>() {
+            Ok(offset) if offset >= 0 => After::Offset(offset),
+            _ => return (StatusCode::BAD_REQUEST, "Invalid offset".to_owned()).into_response(),
+        },
+        (None, None) => After::Cursor(0),
+    };
+
+    let ttl = Duration::from_secs(ctx.config.links_cache_ttl_secs);
+    let cache_key = format!("{}\n{:?}\n{}\n{:?}", domain, source, limit, after);
+    if !ttl.is_zero()
+        && let Some(page) = ctx.links_cache.get(&cache_key, ttl)
+    {
+        println!("\tfound in cache");
+        return Json(page).into_response();
+    }
+    let generation = ctx.links_cache.generation.load(Ordering::SeqCst);
+
+    // one extra row says whether there's a next page without a second query
+    let mut rows = match lookup_page(domain, source, after, limit + 1, &ctx.pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Failed to list entries: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| encode_cursor(row.rowid))
+    } else {
+        None
+    };
+    println!("\t{} links", rows.len());
+
+    let page = Page {
+        links: rows
+            .into_iter()
+            .map(|row| Listed {
+                short_code: row.short_code,
+                long_url: compress::restored(row.long_url, row.long_url_compressed.as_deref()),
+                description: row.description,
+                source: row.source,
+                clicks: row.clicks,
+            })
+            .collect(),
+        next_cursor,
+    };
+    if !ttl.is_zero() {
+        ctx.links_cache
+            .put(cache_key, generation, page.clone(), ttl);
+    }
+    Json(page).into_response()
+}
+
+/// Where a page of `/links` starts
+#[derive(Debug, Clone, Copy)]
+enum After {
+    /// past this rowid, 0 for the start
+    Cursor(i64),
+    /// past this many links
+    Offset(i64),
+}
+
+struct Row {
+    rowid: i64,
+    short_code: String,
+    long_url: String,
+    description: Option<String>,
+    source: String,
+    clicks: i64,
+    long_url_compressed: Option<Vec<u8>>,
+}
+
+/// S -> D : lookup_page(source, after, limit) . D -> S : ok([Row])
+async fn lookup_page(
+    domain: &str,
+    source: Option<&str>,
+    after: After,
+    limit: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<Row>, sqlx::Error> {
+    let (after_rowid, offset) = match after {
+        After::Cursor(rowid) => (rowid, 0),
+        After::Offset(offset) => (0, offset),
+    };
+    sqlx::query_as!(
+        Row,
+        r#"SELECT rowid AS "rowid!: i64", short_code, long_url, description, source, clicks, long_url_compressed
+           FROM url WHERE domain = $1 AND rowid > $2 AND ($5 IS NULL OR source = $5) AND deleted_at IS NULL
+           ORDER BY rowid LIMIT $3 OFFSET $4"#,
+        domain,
+        after_rowid,
+        limit,
+        offset,
+        source
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    /// the codes to delete, unless `tag` is given
+    #[serde(default)]
+    short_codes: Vec<String>,
+    /// delete every link shortened with this tag instead
+    tag: Option<String>,
+    /// domain the codes live in, the default domain when omitted
+    #[serde(default = "default_domain")]
+    domain: String,
+}
+
+fn default_domain() -> String {
+    DEFAULT_DOMAIN.to_owned()
+}
+
+/// Which links a bulk delete is for
+pub enum Matching<'a> {
+    Codes(&'a [String]),
+    Tag(&'a str),
+}
+
+impl Matching<'_> {
+    /// the codes matched, read inside the delete's own transaction so a tag
+    /// resolves to exactly the links that get deleted
+    pub async fn short_codes(
+        &self,
+        domain: &str,
+        conn: &mut sqlx::SqliteConnection,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        match self {
+            Matching::Codes(short_codes) => Ok(short_codes.to_vec()),
+            Matching::Tag(tag) => {
+                sqlx::query_scalar!(
+                    "SELECT short_code FROM url WHERE domain = $1 AND tag = $2",
+                    domain,
+                    tag
+                )
+                .fetch_all(conn)
+                .await
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeleteResponse {
+    deleted: usize,
+}
+
+/// POST /links/delete
+///
+/// removes every listed code, or every link with the given tag, in one transaction,
+/// then evicts them from both caches. under `SOFT_DELETE` they're only marked
+/// deleted, see `soft_delete`
+pub async fn bulk_delete(
+    _: AdminAuth,
+    _: Writable,
+    State(ctx): State<AppCtx>,
+    Json(req): Json<DeleteRequest>,
+) -> impl IntoResponse {
+    let matching = match &req.tag {
+        Some(tag) if req.short_codes.is_empty() => {
+            println!("/links/delete POST <-- tag {}", tag);
+            Matching::Tag(tag)
+        }
+        Some(_) => {
+            println!("/links/delete POST <-- both codes and a tag");
+            return (
+                StatusCode::BAD_REQUEST,
+                "Give either short_codes or a tag, not both".to_owned(),
+            )
+                .into_response();
+        }
+        None => {
+            println!("/links/delete POST <-- {} codes", req.short_codes.len());
+            Matching::Codes(&req.short_codes)
+        }
+    };
+
+    let removed = if ctx.config.soft_delete {
+        soft_delete::delete(&ctx, &req.domain, &matching).await
+    } else {
+        delete_entries(&req.domain, &matching, &ctx.pool)
+            .await
+            .inspect(|removed| evict(&ctx, removed))
+    };
+    let removed = match removed {
+        Ok(removed) => removed,
+        Err(e) => {
+            eprintln!("Failed to delete entries: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+
+    invalidate::broadcast(
+        &ctx,
+        &req.domain,
+        removed
+            .iter()
+            .map(|url| Changed {
+                short_code: url.short_code.clone(),
+                long_url: url.long_url.clone(),
+            })
+            .collect(),
+    );
+
+    Json(DeleteResponse {
+        deleted: removed.len(),
+    })
+    .into_response()
+}
+
+/// drop every trace of links removed from the db from memory
+pub fn evict(ctx: &AppCtx, removed: &[Url]) {
+    {
+        let mut targeted = ctx.targeted.write().unwrap();
+        let mut redirect_statuses = ctx.redirect_statuses.write().unwrap();
+        let mut expiries = ctx.expiries.write().unwrap();
+        let mut deleted = ctx.deleted.write().unwrap();
+        let mut append_params = ctx.append_params.write().unwrap();
+        let mut forward_suffix = ctx.forward_suffix.write().unwrap();
+        let mut broken = ctx.broken.write().unwrap();
+        let mut pending_clicks = ctx.pending_clicks.lock().unwrap();
+        for url in removed {
+            pending_clicks.remove(&domain::scoped(&url.domain, &url.short_code));
+            targeted.remove(&domain::scoped(&url.domain, &url.short_code));
+            redirect_statuses.remove(&domain::scoped(&url.domain, &url.short_code));
+            expiries.remove(&domain::scoped(&url.domain, &url.short_code));
+            deleted.remove(&domain::scoped(&url.domain, &url.short_code));
+            append_params.remove(&domain::scoped(&url.domain, &url.short_code));
+            forward_suffix.remove(&domain::scoped(&url.domain, &url.short_code));
+            broken.remove(&domain::scoped(&url.domain, &url.short_code));
+            ctx.short_to_long_cache
+                .remove(&domain::scoped(&url.domain, &url.short_code));
+            // an alias beside the url's code was never what it dedups to
+            if url.reusable {
+                ctx.long_to_short_cache
+                    .remove(&domain::scoped(&url.domain, &url.long_url));
+            }
+        }
+        println!("\tevicted {} entries from caches", removed.len());
+    }
+    if !removed.is_empty() {
+        ctx.links_cache.bust();
+    }
+}
+
+/// S -> D : delete(short_codes | tag) . D -> S : ok(removed: [URL])
+async fn delete_entries(
+    domain: &str,
+    matching: &Matching<'_>,
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<Url>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut removed = Vec::new();
+
+    // a tag's selected first, the rows it deletes are what gets evicted
+    for short_code in &matching.short_codes(domain, &mut tx).await? {
+        let row = sqlx::query_as!(
+            Url,
+            "DELETE FROM url WHERE domain = $1 AND short_code = $2 RETURNING *",
+            domain,
+            short_code
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if row.is_some() {
+            targets::delete_targets(domain, short_code, &mut tx).await?;
+            clicks::delete_daily(domain, short_code, &mut tx).await?;
+        }
+        removed.extend(row.map(compress::restore));
+    }
+
+    tx.commit().await?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        domain::{self, DEFAULT_DOMAIN},
+        tests::{Reply, admin, call, ctx, get, shorten},
+    };
+
+    async fn shorten_tagged(ctx: &AppCtx, long_url: &str, tag: &str) -> String {
+        let req = Request::post(format!("/shorten?q={}&tag={}", long_url, tag))
+            .body(Body::empty())
+            .unwrap();
+        let reply = call(ctx, req).await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+        reply.body
+    }
+
+    fn cached(ctx: &AppCtx, short_code: &str) -> bool {
+        ctx.short_to_long_cache
+            .get(&domain::scoped(DEFAULT_DOMAIN, short_code))
+            .is_some()
+            || ctx
+                .long_to_short_cache
+                .to_map()
+                .values()
+                .any(|code| code == short_code)
+    }
+
+    async fn bulk_delete(ctx: &AppCtx, body: serde_json::Value) -> Reply {
+        admin(ctx, Method::POST, "/links/delete", Some(body)).await
+    }
+
+    #[tokio::test]
+    async fn deletes_listed_codes_and_evicts_them() {
+        let ctx = ctx().await;
+        let gone = shorten(&ctx, "https://example.com/gone").await;
+        let kept = shorten(&ctx, "https://example.com/kept").await;
+        assert!(cached(&ctx, &gone));
+
+        let reply = bulk_delete(&ctx, json!({ "short_codes": [gone] })).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.json()["deleted"], 1);
+
+        assert!(!cached(&ctx, &gone));
+        let redirect = get(&ctx, &format!("/redirect/{}", gone)).await;
+        assert_eq!(redirect.status, StatusCode::NOT_FOUND);
+        let redirect = get(&ctx, &format!("/redirect/{}", kept)).await;
+        assert!(redirect.status.is_redirection());
+    }
+
+    #[tokio::test]
+    async fn deletes_every_link_with_a_tag_and_evicts_them() {
+        let ctx = ctx().await;
+        let first = shorten_tagged(&ctx, "https://example.com/1", "campaign-q3").await;
+        let second = shorten_tagged(&ctx, "https://example.com/2", "campaign-q3").await;
+        let other = shorten_tagged(&ctx, "https://example.com/3", "campaign-q4").await;
+        let untagged = shorten(&ctx, "https://example.com/4").await;
+        assert!(cached(&ctx, &first) && cached(&ctx, &second));
+
+        let reply = bulk_delete(&ctx, json!({ "tag": "campaign-q3" })).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.json()["deleted"], 2);
+
+        for short_code in [&first, &second] {
+            assert!(!cached(&ctx, short_code));
+            let redirect = get(&ctx, &format!("/redirect/{}", short_code)).await;
+            assert_eq!(redirect.status, StatusCode::NOT_FOUND);
+        }
+        for short_code in [&other, &untagged] {
+            let redirect = get(&ctx, &format!("/redirect/{}", short_code)).await;
+            assert!(redirect.status.is_redirection());
+        }
+    }
+
+    #[tokio::test]
+    async fn codes_and_a_tag_together_are_refused() {
+        let ctx = ctx().await;
+        let short_code = shorten_tagged(&ctx, "https://example.com/1", "campaign-q3").await;
+
+        let reply = bulk_delete(
+            &ctx,
+            json!({ "short_codes": [short_code], "tag": "campaign-q3" }),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        let redirect = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert!(redirect.status.is_redirection());
+    }
+}
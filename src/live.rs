@@ -0,0 +1,122 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, broadcast};
+
+use crate::AppCtx;
+
+/// Running totals since startup, bumped on the request path
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub redirects: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+}
+
+/// bump one of the `Counters`
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+struct LiveStats {
+    total_redirects: u64,
+    redirects_per_sec: u64,
+    cache_hit_ratio: f64,
+    short_to_long_cache_size: usize,
+    long_to_short_cache_size: usize,
+}
+
+/// every connected socket gets the same snapshot at this interval
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// compute a snapshot every second and fan it out to all `/ws/stats` sockets
+pub fn spawn_publisher(ctx: AppCtx) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(INTERVAL);
+        let mut last_redirects = ctx.counters.redirects.load(Ordering::Relaxed);
+
+        loop {
+            interval.tick().await;
+
+            let total_redirects = ctx.counters.redirects.load(Ordering::Relaxed);
+            let hits = ctx.counters.cache_hits.load(Ordering::Relaxed);
+            let misses = ctx.counters.cache_misses.load(Ordering::Relaxed);
+
+            let stats = LiveStats {
+                total_redirects,
+                redirects_per_sec: total_redirects - last_redirects,
+                cache_hit_ratio: if hits + misses == 0 {
+                    0.0
+                } else {
+                    hits as f64 / (hits + misses) as f64
+                },
+                short_to_long_cache_size: ctx.short_to_long_cache.len(),
+                long_to_short_cache_size: ctx.long_to_short_cache.len(),
+            };
+            last_redirects = total_redirects;
+
+            // nobody listening is fine, the snapshot is just dropped
+            let _ = ctx.live_stats.send(serde_json::to_string(&stats).unwrap());
+        }
+    });
+}
+
+/// GET /ws/stats
+///
+/// upgrades to a websocket that receives a JSON snapshot of live counters every second
+pub async fn ws_stats(State(ctx): State<AppCtx>, ws: WebSocketUpgrade) -> Response {
+    println!("/ws/stats GET <--");
+
+    let Ok(permit) = ctx.ws_slots.clone().try_acquire_owned() else {
+        println!("\ttoo many live stats sockets");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many live stats connections".to_owned(),
+        )
+            .into_response();
+    };
+
+    let updates = ctx.live_stats.subscribe();
+    ws.on_upgrade(move |socket| stream_stats(socket, updates, permit))
+}
+
+async fn stream_stats(
+    mut socket: WebSocket,
+    mut updates: broadcast::Receiver<String>,
+    // held for as long as the socket is open
+    _permit: OwnedSemaphorePermit,
+) {
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(stats) => {
+                    if socket.send(Message::Text(stats.into())).await.is_err() {
+                        break;
+                    }
+                }
+                // fell behind, the next snapshot supersedes the missed ones anyway
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+
+            // nothing is expected from the client, just notice when it goes away
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+    println!("/ws/stats socket closed");
+}
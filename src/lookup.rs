@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::{AppCtx, compress, domain, expiry, keys::ApiKey, normalize, privacy};
+
+#[derive(Serialize)]
+struct Found {
+    /// the one resubmitting the url gets back comes first, then the rest oldest first
+    short_codes: Vec<String>,
+}
+
+/// GET /lookup?url=
+///
+/// the codes already pointing at `url`, normalized the way `shorten` would. looks
+/// in the same domain `shorten` would put the link in, 404 when there are none
+pub async fn lookup(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    ApiKey(api_key): ApiKey,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let Some(url) = params
+        .get("url")
+        .map(|u| u.trim())
+        .filter(|u| !u.is_empty())
+    else {
+        println!("/lookup GET <--");
+        return (StatusCode::BAD_REQUEST, "URL was not provided".to_owned()).into_response();
+    };
+    println!("/lookup GET <-- {}", privacy::log_url(&ctx.config, url));
+
+    let tenant = api_key
+        .as_deref()
+        .and_then(|key| domain::tenant_of_key(&ctx.config, key));
+    let domain = match domain::for_shorten(&ctx.config, params.get("domain"), &headers, tenant) {
+        Ok(domain) => domain,
+        Err(e) => return e.into_response(),
+    };
+    let long_url = normalize::normalize_url(&ctx.config, url);
+
+    let short_codes = match lookup_codes(&domain, &long_url, &ctx.pool).await {
+        Ok(short_codes) => short_codes,
+        Err(e) => {
+            eprintln!("Failed to look up codes: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+    // expired links don't redirect, so as far as anyone can tell they're gone
+    let short_codes = short_codes
+        .into_iter()
+        .filter(|short_code| !expiry::is_expired(&ctx, &domain, short_code))
+        .collect::<Vec<_>>();
+    println!("\t{} codes", short_codes.len());
+
+    if short_codes.is_empty() {
+        return (StatusCode::NOT_FOUND, "No link for that URL".to_owned()).into_response();
+    }
+    Json(Found { short_codes }).into_response()
+}
+
+/// S -> D : lookup_codes(long_url) . D -> S : ok([short_code])
+async fn lookup_codes(
+    domain: &str,
+    long_url: &str,
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<String>, sqlx::Error> {
+    // stored as it is, or by its key when it was compressed
+    let key = compress::key(long_url);
+    sqlx::query_scalar!(
+        "SELECT short_code FROM url WHERE domain = $1 AND long_url IN ($2, $3) AND deleted_at IS NULL
+         ORDER BY reusable DESC, rowid",
+        domain,
+        long_url,
+        key
+    )
+    .fetch_all(pool)
+    .await
+}
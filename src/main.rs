@@ -1,94 +1,785 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     hash::{DefaultHasher, Hash, Hasher},
-    sync::{Arc, Mutex},
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock, atomic::AtomicBool},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
     Router,
-    extract::{Path, Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect, Response},
-    routing::{get, post},
+    body::Bytes,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{MethodRouter, get, post, put},
 };
-use sqlx::{FromRow, Pool, Sqlite, SqlitePool};
+use sqlx::{FromRow, Pool, Sqlite};
+use tokio::sync::{Semaphore, broadcast};
+
+use crate::{
+    blocklist::Blocklist,
+    bloom::BloomFilter,
+    cache::ShardedCache,
+    code_gen::CodeGenerator,
+    config::Config,
+    dedup::DedupPolicy,
+    health::PoolHealth,
+    keyed_lock::KeyedLocks,
+    keys::ApiKey,
+    live::Counters,
+    metrics::{RedirectLatency, Served},
+    query::StrictQuery,
+    rate_limit::RateLimiter,
+    read_only::Writable,
+    redirect_status::RedirectStatus,
+    targets::Clock,
+    trace::Tracer,
+    wordlist::Wordlist,
+};
+
+mod accept;
+mod access_log;
+mod admin;
+mod append;
+mod assets;
+mod blocklist;
+mod bloom;
+mod bulk;
+mod cache;
+mod clicks;
+mod client_ip;
+mod code_gen;
+mod compress;
+mod config;
+mod db;
+mod dedup;
+mod domain;
+mod errors;
+mod expiry;
+mod fetch;
+mod health;
+mod invalidate;
+mod keyed_lock;
+mod keys;
+mod link_check;
+mod links;
+mod live;
+mod lookup;
+mod metrics;
+mod normalize;
+mod not_found;
+mod overload;
+mod prefix;
+mod privacy;
+mod query;
+mod rate_limit;
+mod read_only;
+mod redirect_status;
+mod resolve;
+mod retry_after;
+mod rotate;
+mod scheme;
+mod serve;
+mod snapshot;
+mod soft_delete;
+mod stats;
+mod suffix;
+mod targets;
+mod tasks;
+#[cfg(test)]
+mod tests;
+mod trace;
+mod version;
+mod wordlist;
 
 #[derive(Debug, Clone)]
 struct AppCtx {
+    config: Config,
     pool: Pool<Sqlite>,
-    short_to_long_cache: Arc<Mutex<HashMap<String, String>>>,
-    long_to_short_cache: Arc<Mutex<HashMap<String, String>>>,
+    short_to_long_cache: Arc<ShardedCache>,
+    long_to_short_cache: Arc<ShardedCache>,
+    /// every short code in the db, lets lookups skip the db for codes that were never stored
+    code_filter: Arc<RwLock<BloomFilter>>,
+    /// scoped codes with rotating targets, the rest never need to look for any
+    targeted: Arc<RwLock<HashSet<String>>>,
+    /// scoped codes shortened with their own redirect status
+    redirect_statuses: Arc<RwLock<HashMap<String, RedirectStatus>>>,
+    /// query params each scoped code adds on redirect, for links shortened with their own
+    append_params: Arc<RwLock<HashMap<String, append::Params>>>,
+    /// scoped codes that append the path past them to their target, see `suffix`
+    forward_suffix: Arc<RwLock<HashSet<String>>>,
+    /// unix seconds each scoped code stops redirecting, for links created with an expiry
+    expiries: Arc<RwLock<HashMap<String, i64>>>,
+    /// unix seconds each soft deleted scoped code was deleted at, see `soft_delete`
+    deleted: Arc<RwLock<HashMap<String, i64>>>,
+    /// scoped codes whose target the link checker last found broken
+    broken: Arc<RwLock<HashSet<String>>>,
+    /// writes are refused with 503 while set, see `read_only`
+    read_only: Arc<AtomicBool>,
+    /// latest background db check, see `health::spawn_monitor`
+    pool_health: Arc<RwLock<PoolHealth>>,
+    /// how each background task's runs have gone, see `tasks`
+    task_health: Arc<tasks::TaskHealth>,
+    /// redirects per scoped code since the last click flush, kept out of the caches
+    /// so evicting a hot entry never drops its unflushed clicks
+    pending_clicks: Arc<Mutex<HashMap<String, i64>>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// hits per scoped code, `None` unless `LINK_RATE_LIMIT` is set
+    link_rate_limiter: Option<Arc<RateLimiter>>,
+    /// per-url locks serializing concurrent shortens of the same new url
+    shorten_locks: Arc<KeyedLocks>,
+    /// one permit per request being served, `None` when there's no limit
+    in_flight: Option<Arc<Semaphore>>,
+    /// targets `shorten` refuses, swapped out whole on reload
+    blocklist: Arc<RwLock<Blocklist>>,
+    favicon: Option<Bytes>,
+    /// `/robots.txt` from `ROBOTS_TXT_PATH`, `None` serves the default
+    robots_txt: Option<String>,
+    counters: Arc<Counters>,
+    /// how long redirects take to find their target, for `/metrics`
+    redirect_latency: Arc<RedirectLatency>,
+    /// latest JSON snapshot for `/ws/stats` subscribers
+    live_stats: broadcast::Sender<String>,
+    ws_slots: Arc<Semaphore>,
+    /// outbound client for fetching link targets, ssrf-guarded
+    http: reqwest::Client,
+    /// client for `PEER_URLS`, not ssrf-guarded since peers are usually internal
+    peer_http: reqwest::Client,
+    /// OTLP span exporter, `None` unless `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+    tracer: Option<Arc<Tracer>>,
+    /// makes the codes of links shortened without an alias, see `CODE_GENERATOR`
+    code_generator: Arc<dyn CodeGenerator>,
+    /// words no code may spell, see `CODE_WORDLIST_PATH`
+    code_wordlist: Arc<Wordlist>,
+    /// recently served `/links` pages, see `LINKS_CACHE_TTL_SECS`
+    links_cache: Arc<links::PageCache>,
+    /// what `targets::now` reads, the system clock unless a test steps it
+    clock: Clock,
 }
 
 impl AppCtx {
-    fn new(pool: Pool<Sqlite>) -> AppCtx {
+    fn new(config: Config, pool: Pool<Sqlite>) -> AppCtx {
+        let clock = Clock::system();
+        // peers say when a link changes, without them an entry is only trusted for so long
+        let cache_ttl = (config.peer_urls.is_empty() && config.cache_ttl_secs > 0)
+            .then(|| Duration::from_secs(config.cache_ttl_secs));
         AppCtx {
-            short_to_long_cache: Arc::new(Mutex::new(HashMap::new())),
-            long_to_short_cache: Arc::new(Mutex::new(HashMap::new())),
+            short_to_long_cache: Arc::new(ShardedCache::new(
+                config.cache_shards,
+                config.cache_max_bytes,
+                cache_ttl,
+            )),
+            long_to_short_cache: Arc::new(ShardedCache::new(
+                config.cache_shards,
+                config.cache_max_bytes,
+                cache_ttl,
+            )),
+            code_filter: Arc::new(RwLock::new(BloomFilter::new(
+                config.bloom_expected_codes,
+                0.01,
+            ))),
+            targeted: Arc::new(RwLock::new(HashSet::new())),
+            redirect_statuses: Arc::new(RwLock::new(HashMap::new())),
+            append_params: Arc::new(RwLock::new(HashMap::new())),
+            forward_suffix: Arc::new(RwLock::new(HashSet::new())),
+            expiries: Arc::new(RwLock::new(HashMap::new())),
+            deleted: Arc::new(RwLock::new(HashMap::new())),
+            broken: Arc::new(RwLock::new(HashSet::new())),
+            read_only: Arc::new(AtomicBool::new(config.read_only)),
+            pool_health: Arc::new(RwLock::new(PoolHealth::new())),
+            task_health: Arc::new(tasks::TaskHealth::new(clock.now())),
+            pending_clicks: Arc::new(Mutex::new(HashMap::new())),
+            rate_limiter: config.rate_limit.map(|limit| {
+                Arc::new(RateLimiter::new(
+                    config.rate_limit_strategy,
+                    limit,
+                    Duration::from_secs(config.rate_limit_window_secs),
+                ))
+            }),
+            // counted over the trailing minute, so a link hammered all minute stays
+            // limited all minute without a burst each time a window starts
+            link_rate_limiter: config.link_rate_limit.map(|limit| {
+                Arc::new(RateLimiter::new(
+                    rate_limit::Strategy::SlidingWindow,
+                    limit,
+                    Duration::from_secs(60),
+                ))
+            }),
+            shorten_locks: Arc::new(KeyedLocks::default()),
+            in_flight: config
+                .max_in_flight
+                .map(|max| Arc::new(Semaphore::new(max))),
+            blocklist: Arc::new(RwLock::new(Blocklist::default())),
+            favicon: None,
+            robots_txt: None,
+            counters: Arc::new(Counters::default()),
+            redirect_latency: Arc::new(RedirectLatency::default()),
+            live_stats: broadcast::channel(16).0,
+            ws_slots: Arc::new(Semaphore::new(config.ws_max_connections)),
+            http: fetch::client(&config),
+            peer_http: invalidate::client(),
+            tracer: trace::from_config(&config).map(Arc::new),
+            code_generator: code_gen::from_config(&config),
+            code_wordlist: Arc::new(Wordlist::default()),
+            links_cache: Arc::new(links::PageCache::default()),
+            clock,
+            config,
             pool,
         }
     }
+
+    /// seed the bloom filter with every code currently in the db
+    async fn load_code_filter(&self) -> Result<(), sqlx::Error> {
+        let codes = sqlx::query!("SELECT domain, short_code FROM url")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut code_filter = self.code_filter.write().unwrap();
+        for code in &codes {
+            code_filter.insert(&domain::scoped(&code.domain, &code.short_code));
+        }
+        println!("loaded {} codes into bloom filter", codes.len());
+        Ok(())
+    }
 }
 
 #[derive(FromRow)]
-struct URL {
+struct Url {
     long_url: String,
     short_code: String,
+    domain: String,
+    created_by: Option<String>,
+    title: Option<String>,
+    og_title: Option<String>,
+    og_description: Option<String>,
+    og_image: Option<String>,
+    redirect_status: Option<i64>,
+    /// salted hash, see `privacy::hash_ip`
+    submitted_ip: Option<String>,
+    submitted_user_agent: Option<String>,
+    /// see `link_check`, `None` until the link has been checked
+    last_status: Option<i64>,
+    last_checked_at: Option<i64>,
+    /// redirects flushed so far, see `clicks`
+    clicks: i64,
+    /// whether resubmitting the url gets this code back, see `dedup`
+    reusable: bool,
+    /// unix seconds the link's stats last changed
+    updated_at: Option<i64>,
+    /// unix seconds the link stops redirecting, see `expiry`
+    expires_at: Option<i64>,
+    /// creator's own note about the link
+    description: Option<String>,
+    /// query params added on redirect, see `append`
+    append_params: Option<String>,
+    /// whether paths past the code are appended to the target, see `suffix`
+    forward_suffix: bool,
+    /// the target under `COMPRESS_URLS`, `long_url` is then its key until `compress::restore`
+    long_url_compressed: Option<Vec<u8>>,
+    /// the channel the link came in through, `api` unless the client named another
+    source: String,
+    /// when the link was soft deleted, it answers 410 until restored or purged
+    deleted_at: Option<i64>,
+    /// label `/links/delete` can remove the link by
+    tag: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let pool = SqlitePool::connect("sqlite:urlshortener.db").await?; // ! expects the file to already exist
+    let config = Config::from_env();
+    let pool = db::connect(&config).await?;
+    if db::is_in_memory(&config.database_url) {
+        println!("using an in-memory db, nothing will survive a restart");
+    }
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    if let Err(e) = db::migrate(&pool).await {
+        // main's own error would come out debug-formatted, quotes and all
+        eprintln!("Failed to migrate db: {}", e);
+        std::process::exit(1);
+    }
+    // `url_shortener migrate` stops here, for deploys that migrate before starting
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        println!("migrated db");
+        return Ok(());
+    }
 
     println!("created db");
 
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/shorten", post(shorten)) // passing the long url as a query param
-        .route("/redirect/{short_code}", get(redirect))
-        .route("/expand/{short_code}", get(expand))
-        .with_state(AppCtx::new(pool));
+    let ctx = build_ctx(config, pool).await?;
+    live::spawn_publisher(ctx.clone());
+    link_check::spawn_checker(ctx.clone());
+    clicks::spawn_flusher(ctx.clone());
+    soft_delete::spawn_purger(ctx.clone());
+    health::spawn_monitor(ctx.clone());
+    let app = build_app(ctx.clone());
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
     println!("listening on port 3000...\n");
-    axum::serve(listener, app).await.unwrap();
+    serve::serve(&ctx.config, listener, app, shutdown_signal()).await;
+
+    println!("shutting down");
+    clicks::flush_on_shutdown(&ctx).await?;
+    if let Some(path) = &ctx.config.cache_snapshot_path {
+        snapshot::save(&ctx, path)?;
+    }
+    if let Some(tracer) = &ctx.tracer {
+        tracer.shutdown();
+    }
+
     Ok(())
 }
 
+/// everything `AppCtx` needs loaded before the first request: favicon, bloom filter and any cache snapshot
+async fn build_ctx(config: Config, pool: Pool<Sqlite>) -> Result<AppCtx, Box<dyn Error>> {
+    let mut ctx = AppCtx::new(config, pool);
+    if let Some(path) = &ctx.config.favicon_path {
+        ctx.favicon = Some(assets::load_favicon(path)?);
+    }
+    if let Some(path) = &ctx.config.robots_txt_path {
+        ctx.robots_txt = Some(assets::load_robots_txt(path)?);
+    }
+    if let Some(path) = &ctx.config.blocklist_path {
+        let blocklist = blocklist::load(path)?;
+        println!("loaded {} blocklist entries", blocklist.len());
+        *ctx.blocklist.write().unwrap() = blocklist;
+    }
+    if let Some(path) = &ctx.config.code_wordlist_path {
+        let wordlist = wordlist::load(path)?;
+        println!("loaded {} wordlist entries", wordlist.len());
+        ctx.code_wordlist = Arc::new(wordlist);
+    }
+    ctx.load_code_filter().await?;
+    targets::load(&ctx).await?;
+    redirect_status::load(&ctx).await?;
+    expiry::load(&ctx).await?;
+    soft_delete::load(&ctx).await?;
+    append::load(&ctx).await?;
+    suffix::load(&ctx).await?;
+    link_check::load(&ctx).await?;
+
+    if let Some(path) = &ctx.config.cache_snapshot_path {
+        snapshot::load(&ctx, path).await?;
+    }
+
+    Ok(ctx)
+}
+
+/// the full router, separate from `main` so it can be driven without binding a socket
+///
+/// the rate limiter reads the peer address, so serve it with
+/// `into_make_service_with_connect_info::<SocketAddr>` (or `MockConnectInfo` in tests)
+fn build_app(ctx: AppCtx) -> Router {
+    let router = Router::new()
+        .route("/", get(root))
+        .route("/favicon.ico", get(assets::favicon))
+        .route("/robots.txt", get(assets::robots_txt))
+        .route("/livez", get(health::livez))
+        .route("/health", get(health::livez))
+        .route("/ping", get(health::ping))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(version::version))
+        .route("/metrics", get(metrics::metrics))
+        .route("/shorten", post(shorten)) // passing the long url as a query param
+        .route("/shorten/bulk", post(bulk::shorten))
+        .route("/expand/{short_code}", get(expand))
+        .route("/preview/{short_code}", get(preview))
+        .route("/stats/{short_code}", get(stats::stats))
+        .route("/stats/{short_code}/daily", get(stats::daily))
+        .route("/resolve", post(resolve::resolve))
+        .route("/lookup", get(lookup::lookup))
+        .route("/ws/stats", get(live::ws_stats))
+        .route("/links", get(links::list))
+        .route("/links/delete", post(links::bulk_delete))
+        .route("/links/import", post(bulk::import))
+        .route("/links/broken", get(link_check::broken))
+        .route("/links/{short_code}/targets", put(targets::set))
+        .route("/links/{short_code}/rotate", post(rotate::rotate))
+        .route("/links/{short_code}/restore", post(soft_delete::restore))
+        .route("/admin/stats", get(admin::stats))
+        .route("/admin/links/{short_code}", get(admin::link))
+        .route("/admin/debug/hash", get(admin::debug_hash))
+        .route("/admin/keys/{key}/usage", get(keys::usage))
+        .route("/admin/blocklist/reload", post(blocklist::reload))
+        .route("/admin/cache/invalidate", post(invalidate::invalidate))
+        .route("/admin/readonly", post(read_only::set));
+
+    // at the root, codes only get the paths no route claimed, so routes always win
+    let router = if ctx.config.redirect_prefix.is_root() {
+        router.fallback(redirect_methods(redirect_at_root))
+    } else {
+        router
+            .route(
+                &ctx.config.redirect_prefix.route(),
+                redirect_methods(redirect),
+            )
+            .route(
+                &ctx.config.redirect_prefix.suffix_route(),
+                redirect_methods(redirect),
+            )
+    };
+
+    router
+        // after every route, so each one gets it, axum still fills in `Allow`
+        .method_not_allowed_fallback(method_not_allowed)
+        .layer(middleware::from_fn_with_state(
+            ctx.clone(),
+            rate_limit::limit,
+        ))
+        // ahead of the rate limiter and every handler, so shed requests cost as
+        // little as possible. the layers below only add a span, an envelope and a log line
+        .layer(middleware::from_fn_with_state(ctx.clone(), overload::shed))
+        // shed and rate limited requests get a span too
+        .layer(middleware::from_fn_with_state(ctx.clone(), trace::layer))
+        // shed and rate limited requests are errors like any other
+        .layer(middleware::from_fn(errors::envelope))
+        // around everything, so shed and rate limited requests are logged too
+        .layer(middleware::from_fn_with_state(ctx.clone(), access_log::log))
+        .layer(middleware::from_fn(access_log::request_id))
+        .with_state(ctx)
+}
+
+/// short links answer more than GET, so api endpoints shortened behind a
+/// 307/308 link can still be POSTed to through it
+fn redirect_methods<H, T>(handler: H) -> MethodRouter<AppCtx>
+where
+    H: axum::handler::Handler<T, AppCtx>,
+    T: 'static,
+{
+    get(handler.clone())
+        .post(handler.clone())
+        .put(handler.clone())
+        .patch(handler.clone())
+        .delete(handler)
+}
+
+/// resolves on ctrl-c or SIGTERM so the caches can be snapshotted before exit
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 async fn root() -> impl IntoResponse {
     println!("/ GET <--");
-    (StatusCode::OK, "Hello, World!".to_string());
+    (StatusCode::OK, "Hello, World!".to_string())
 }
 
-fn hash_url(long_url: &String) -> String {
+async fn method_not_allowed() -> impl IntoResponse {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        "Method not allowed".to_owned(),
+    )
+}
+
+fn hash_url(long_url: &str) -> String {
     let mut s = DefaultHasher::new();
     long_url.hash(&mut s);
     format!("{:x}", s.finish())
 }
 
-/// C -> S : shorten(long_url) ... S -> C : success(short_code)
+/// `source` of links made through `shorten` that don't name their own
+const API_SOURCE: &str = "api";
+
+/// whether `tag` is usable as a link's `source` or `tag`, like `web-form` or `import`
+fn is_valid_tag(tag: &str) -> bool {
+    (1..=32).contains(&tag.len())
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+}
+
+/// longest `description` accepted on `shorten`, in characters
+const MAX_DESCRIPTION_CHARS: usize = 512;
+
+/// fresh codes tried before giving up, a clash is already vanishingly unlikely
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+/// a new code to replace `old_code`, unlike `hash_url` different every time
+fn fresh_code(old_code: &str, attempt: u32) -> String {
+    let mut s = DefaultHasher::new();
+    old_code.hash(&mut s);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+        .hash(&mut s);
+    attempt.hash(&mut s);
+    format!("{:x}", s.finish())
+}
+
+/// the code for a link shortened without an alias, trying from `attempt` on
+/// and skipping any the wordlist rules out
+fn generated_code(ctx: &AppCtx, long_url: &str, reuse: bool, attempt: &mut u32) -> Option<String> {
+    wordlist::first_unlisted(ctx, attempt, |attempt| {
+        if reuse {
+            ctx.code_generator.generate(long_url, attempt)
+        } else {
+            // a deterministic generator would land on the code the url already has
+            fresh_code(&hash_url(long_url), attempt)
+        }
+    })
+}
+
+/// the code a new hashed link for `long_url` gets first, wordlist retries included.
+/// `None` when there's no telling ahead, under `always_new` or a random generator
+fn predicted_code(ctx: &AppCtx, long_url: &str, reuse: bool) -> Option<String> {
+    if !(reuse && ctx.code_generator.deterministic()) {
+        return None;
+    }
+    generated_code(ctx, long_url, reuse, &mut 0)
+}
+
+/// a submitted url as `shorten` takes it. `?q=` and `?q=%20` are as good as no url,
+/// and would redirect to an empty `Location`
+fn submitted_url(long_url: Option<&String>) -> Option<String> {
+    long_url
+        .map(|long_url| long_url.trim().to_owned())
+        .filter(|long_url| !long_url.is_empty())
+}
+
+fn wordlist_exhausted() -> Response {
+    eprintln!("Failed to generate a code: every attempt was on the wordlist");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Something went wrong on our end".to_owned(),
+    )
+        .into_response()
+}
+
+/// whether `code` is usable as a short code, url-safe and at most 64 chars
+fn is_valid_code(code: &str) -> bool {
+    (1..=64).contains(&code.len())
+        && code
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// C -> S : shorten(long_url) ... S -> C : {
+///     created(short_code),
+///     success(short_code)
+/// }
 async fn shorten(
     State(ctx): State<AppCtx>,
-    Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-    let Some(long_url) = params.get("q").map(|q| q.to_owned()) else {
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiKey(api_key): ApiKey,
+    _: Writable,
+    StrictQuery(params): StrictQuery,
+) -> Response {
+    create_link(ctx, peer, headers, api_key, params, API_SOURCE).await
+}
+
+/// everything `shorten` does for one link, `params` being its query string.
+/// the bulk endpoints go through it too, links without a `source` param get `default_source`
+async fn create_link(
+    ctx: AppCtx,
+    peer: SocketAddr,
+    headers: HeaderMap,
+    api_key: Option<String>,
+    params: HashMap<String, String>,
+    default_source: &str,
+) -> Response {
+    let Some(long_url) = submitted_url(params.get("q")) else {
         println!("/shorten POST <--");
-        return (StatusCode::BAD_REQUEST, "URL was not provided".to_owned());
+        return (StatusCode::BAD_REQUEST, "URL was not provided".to_owned()).into_response();
     };
 
-    println!("/shorten POST <-- {}", &long_url);
+    println!(
+        "/shorten POST <-- {}",
+        privacy::log_url(&ctx.config, &long_url)
+    );
 
+    // a custom alias replaces the hashed code, everything else about the link is the same
+    let alias = params.get("alias").map(|a| a.to_owned());
+    if let Some(alias) = &alias
+        && !is_valid_code(alias)
+    {
+        println!("\tinvalid alias");
+        return (StatusCode::BAD_REQUEST, "Invalid alias".to_owned()).into_response();
+    }
+    // very short aliases are few enough to be grabbed up, keep them for the operator
+    if let Some(alias) = &alias
+        && alias.len() < ctx.config.min_alias_length
+    {
+        println!("\talias too short");
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Alias must be at least {} characters",
+                ctx.config.min_alias_length
+            ),
+        )
+            .into_response();
+    }
+    if let Some(alias) = &alias
+        && ctx.config.redirect_prefix.shadows(alias)
+    {
+        println!("\treserved alias");
+        return (StatusCode::BAD_REQUEST, "Alias is reserved".to_owned()).into_response();
+    }
+    if let Some(alias) = &alias
+        && wordlist::is_listed(&ctx, alias)
     {
+        println!("\talias is on the wordlist");
+        return (StatusCode::BAD_REQUEST, "Alias is not allowed".to_owned()).into_response();
+    }
+
+    let status = match params.get("status").map(|s| s.parse::<RedirectStatus>()) {
+        Some(Ok(status)) => Some(status),
+        Some(Err(())) => {
+            println!("\tinvalid redirect status");
+            return (
+                StatusCode::BAD_REQUEST,
+                "Invalid redirect status".to_owned(),
+            )
+                .into_response();
+        }
+        None => None,
+    };
+
+    // control characters would let a note break up log lines or json consumers' output
+    let description = params.get("description").map(|d| {
+        d.trim()
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect::<String>()
+    });
+    if description
+        .as_ref()
+        .is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_CHARS)
+    {
+        println!("\tdescription too long");
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Description is over {} characters", MAX_DESCRIPTION_CHARS),
+        )
+            .into_response();
+    }
+
+    let source = match params.get("source") {
+        Some(source) if is_valid_tag(source) => source.to_owned(),
+        Some(_) => {
+            println!("\tinvalid source");
+            return (StatusCode::BAD_REQUEST, "Invalid source".to_owned()).into_response();
+        }
+        None => default_source.to_owned(),
+    };
+    let tag = match params.get("tag") {
+        Some(tag) if is_valid_tag(tag) => Some(tag.to_owned()),
+        Some(_) => {
+            println!("\tinvalid tag");
+            return (StatusCode::BAD_REQUEST, "Invalid tag".to_owned()).into_response();
+        }
+        None => None,
+    };
+
+    // stored normalized, empty means this link adds nothing even with `APPEND_PARAMS` set
+    let append_params = match params.get("append_params").map(|p| append::parse(p)) {
+        Some(Some(append_params)) => Some(append::to_query(&append_params)),
+        Some(None) => {
+            println!("\tinvalid append params");
+            return (StatusCode::BAD_REQUEST, "Invalid append_params".to_owned()).into_response();
+        }
+        None => None,
+    };
+
+    // the rest of the path after the code is appended to the target, see `suffix`
+    let forward_suffix = params.get("forward_suffix").is_some_and(|v| v == "true");
+    let require_reachable = params.get("require_reachable").is_some_and(|v| v == "true");
+
+    let expires_at = match expiry::from_params(&params, targets::now(&ctx)) {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            println!("\tinvalid expiry");
+            return e.into_response();
+        }
+    };
+
+    // both the hashed and the alias path store the normalized form
+    let long_url = normalize::normalize_url(&ctx.config, &long_url);
+    if !scheme::is_allowed(&ctx.config, &long_url) {
+        println!("\tscheme not allowed");
+        return (
+            StatusCode::BAD_REQUEST,
+            "URL scheme is not allowed".to_owned(),
+        )
+            .into_response();
+    }
+    if blocklist::is_blocked(&ctx, &long_url) {
+        println!("\ttarget is blocklisted");
+        return (StatusCode::FORBIDDEN, "URL is blocklisted".to_owned()).into_response();
+    }
+
+    // catches typos in the host before they become links that never work
+    if ctx.config.validate_dns {
+        let checked = match reqwest::Url::parse(&long_url) {
+            Ok(url) => fetch::check_host(&ctx.config, &url).await,
+            Err(_) => Err(fetch::HostError::Unresolved),
+        };
+        match checked {
+            Ok(()) => {}
+            Err(fetch::HostError::Unresolved) => {
+                println!("\thost does not resolve");
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "URL host does not resolve".to_owned(),
+                )
+                    .into_response();
+            }
+            Err(fetch::HostError::NotPublic) => {
+                println!("\thost is not public");
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "URL host is not a public address".to_owned(),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    let tenant = api_key
+        .as_deref()
+        .and_then(|key| domain::tenant_of_key(&ctx.config, key));
+    let domain = match domain::for_shorten(&ctx.config, params.get("domain"), &headers, tenant) {
+        Ok(domain) => domain,
+        Err(e) => return e.into_response(),
+    };
+    let lts_key = domain::scoped(&domain, &long_url);
+    let reuse = ctx.config.dedup_policy == DedupPolicy::Reuse;
+
+    if params.get("dry_run").is_some_and(|v| v == "true") {
+        return dry_run(&ctx, &domain, &long_url, alias.as_deref(), reuse).await;
+    }
+
+    // an alias was asked for explicitly, so whatever code the url already has won't do
+    if alias.is_none() && reuse {
         // acquire lock
-        let long_to_short_cache = ctx.long_to_short_cache.lock().unwrap();
-        match long_to_short_cache.get(&long_url) {
+        let mut long_to_short_cache = ctx.long_to_short_cache.lock(&lts_key);
+        match long_to_short_cache.get(&lts_key) {
             Some(short_code) => {
                 println!("\tfound in cache");
                 // already in cache, means already in db, can just return
-                return (StatusCode::OK, short_code.to_owned());
+                return (StatusCode::OK, short_code.to_owned()).into_response();
             }
             None => {
                 println!("\tcache miss - new entry");
@@ -98,118 +789,709 @@ async fn shorten(
         // lock is released
     }
 
-    // not in cache, so add it
-    let short_code = hash_url(&long_url);
+    // concurrent shortens of the same url take turns, so only the first one inserts
+    let (_url_lock, waited) = ctx.shorten_locks.lock(&lts_key).await;
+    if waited
+        && alias.is_none()
+        && reuse
+        && let Ok(Some(existing_code)) = lookup_code_for_url(&domain, &long_url, &ctx.pool).await
+    {
+        println!("\tstored by another request while we waited");
+        return (StatusCode::OK, existing_code).into_response();
+    }
+
+    // under the url's lock, so two aliases made at once can't both squeeze under the cap
+    if let (Some(alias), Some(cap)) = (&alias, ctx.config.max_codes_per_url) {
+        match count_codes_for_url(&domain, &long_url, alias, &ctx.pool).await {
+            Ok(count) if count >= cap => {
+                println!("\turl at its code cap ({}/{})", count, cap);
+                return (
+                    StatusCode::CONFLICT,
+                    format!("URL already has {} codes, the most allowed", cap),
+                )
+                    .into_response();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to count codes for url: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our end".to_owned(),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    // not in cache, so it's a new link and counts toward the key's quota
+    if let (Some(key), Some(limit)) = (&api_key, ctx.config.api_key_quota) {
+        match keys::links_created_by(key, targets::now(&ctx), &ctx.pool).await {
+            Ok(count) if count >= limit => {
+                println!("\tkey over quota ({}/{})", count, limit);
+                return (StatusCode::FORBIDDEN, "API key quota exceeded".to_owned())
+                    .into_response();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to count links for key: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our end".to_owned(),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    // only new links are checked, a url already stored was fine when it was shortened
+    if require_reachable {
+        let refused = match link_check::check(&ctx.config, &ctx.http, &long_url).await {
+            Some(status) if (200..400).contains(&status) => None,
+            Some(link_check::UNREACHABLE) => Some("Target is unreachable".to_owned()),
+            Some(status) => Some(format!("Target answered {}", status)),
+            None => Some("Target can't be checked".to_owned()),
+        };
+        if let Some(refused) = refused {
+            println!("\ttarget not reachable");
+            return (StatusCode::UNPROCESSABLE_ENTITY, refused).into_response();
+        }
+    }
+
+    // one fetch covers both kinds of metadata
+    let page = if ctx.config.fetch_title || ctx.config.fetch_open_graph {
+        fetch::get_page(&ctx.config, &ctx.http, &long_url).await
+    } else {
+        None
+    };
+    let title = page
+        .as_deref()
+        .filter(|_| ctx.config.fetch_title)
+        .and_then(fetch::extract_title);
+    let og = page
+        .as_deref()
+        .filter(|_| ctx.config.fetch_open_graph)
+        .map(fetch::extract_open_graph)
+        .unwrap_or_default();
+
+    let (submitted_ip, submitted_user_agent) = if ctx.config.capture_submitter {
+        (
+            Some(privacy::hash_ip(
+                &ctx.config,
+                client_ip::resolve(&ctx.config, peer, &headers),
+            )),
+            headers
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(privacy::trim_user_agent),
+        )
+    } else {
+        (None, None)
+    };
+
+    // counts every code generated, clashes and wordlist matches alike
+    let mut attempt = 0;
+    let short_code = match &alias {
+        Some(alias) => alias.clone(),
+        None => match generated_code(&ctx, &long_url, reuse, &mut attempt) {
+            Some(short_code) => short_code,
+            None => return wordlist_exhausted(),
+        },
+    };
     println!("\tshortened to: {}", &short_code);
 
-    let url = URL {
+    let mut url = Url {
         long_url: long_url.clone(),
-        short_code: short_code.clone(),
+        short_code,
+        domain: domain.clone(),
+        created_by: api_key,
+        title,
+        og_title: og.title,
+        og_description: og.description,
+        og_image: og.image,
+        redirect_status: status.map(RedirectStatus::code),
+        submitted_ip,
+        submitted_user_agent,
+        last_status: None,
+        last_checked_at: None,
+        clicks: 0,
+        reusable: reuse,
+        updated_at: Some(targets::now(&ctx)),
+        expires_at,
+        description: description.filter(|d| !d.is_empty()),
+        append_params,
+        forward_suffix,
+        long_url_compressed: ctx
+            .config
+            .compress_urls
+            .then(|| compress::compress(&long_url))
+            .flatten(),
+        source,
+        deleted_at: None,
+        tag,
     };
 
-    match store_entry(url, &ctx.pool).await {
-        Ok(_) => {
-            {
-                // acquire lock
-                let mut long_to_short_cache = ctx.long_to_short_cache.lock().unwrap();
-                long_to_short_cache.insert(long_url.clone(), short_code.clone());
-                println!("\tstoring in lts cache");
-                // release lock
+    // a hashed code can clash with an alias or another url's code, an alias can't move
+    let mut clashes = 0;
+    // what the db says it stored, not what we asked for
+    let short_code = loop {
+        match reserve_code(&url, &ctx.pool).await {
+            Ok(Reserved::Created(short_code)) => break short_code,
+            Ok(Reserved::Existing(existing_code)) if alias.is_none() => {
+                // another request stored it between our cache check and now
+                println!("\talready stored in db");
+                return (StatusCode::OK, existing_code).into_response();
             }
-
-            {
-                // acquire lock
-                let mut short_to_long_cache = ctx.short_to_long_cache.lock().unwrap();
-                short_to_long_cache.insert(short_code.clone(), long_url.clone());
-                println!("\tstoring in stl cache");
-                // release lock
+            Ok(Reserved::Existing(existing_code)) if Some(&existing_code) == alias.as_ref() => {
+                println!("\talias already points here");
+                return (StatusCode::OK, existing_code).into_response();
+            }
+            Ok(Reserved::Existing(_)) => {
+                // the url keeps its code for dedup, the alias is stored beside it
+                // as a second code that resubmissions never hand back
+                println!("\turl already has a code, storing alias alongside it");
+                url.reusable = false;
+            }
+            Ok(Reserved::CodeTaken) if alias.is_some() => {
+                println!("\talias taken");
+                return (StatusCode::CONFLICT, "Alias already in use".to_owned()).into_response();
             }
+            Ok(Reserved::CodeTaken) if clashes + 1 < MAX_CODE_ATTEMPTS => {
+                clashes += 1;
+                attempt += 1;
+                url.short_code = match generated_code(&ctx, &url.long_url, reuse, &mut attempt) {
+                    Some(short_code) => short_code,
+                    None => return wordlist_exhausted(),
+                };
+                println!("\tcode taken, trying: {}", url.short_code);
+            }
+            Ok(Reserved::CodeTaken) | Err(_) => {
+                // anything else happened so we just return an error
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our end".to_owned(),
+                )
+                    .into_response();
+            }
+        }
+    };
+
+    let stl_key = domain::scoped(&domain, &short_code);
+    ctx.code_filter.write().unwrap().insert(&stl_key);
+    ctx.links_cache.bust();
+    expiry::set(&ctx, &domain, &short_code, expires_at);
+    append::set(&ctx, &domain, &short_code, url.append_params.as_deref());
+    suffix::set(&ctx, &domain, &short_code, url.forward_suffix);
+    if let Some(status) = status {
+        ctx.redirect_statuses
+            .write()
+            .unwrap()
+            .insert(stl_key.clone(), status);
+    }
 
-            println!("\tsaved to db");
-            (StatusCode::OK, short_code)
+    // otherwise the caches only fill from redirects
+    if ctx.config.cache_on_write {
+        // a url with several codes has none to hand back from the cache, and an
+        // alias stored beside the url's code mustn't replace it there
+        if url.reusable {
+            // acquire lock
+            let mut long_to_short_cache = ctx.long_to_short_cache.lock(&lts_key);
+            long_to_short_cache.insert(lts_key, short_code.clone());
+            println!("\tstoring in lts cache");
+            // release lock
         }
 
-        Err(e) => {
-            eprintln!("Failed to store entry: {}", e);
-            // in the window between lock release and acquisition
-            // it's possible that another thread added the short code into the db
-            // and so we are violating the uniqueness constraint
+        {
+            // acquire lock
+            let mut short_to_long_cache = ctx.short_to_long_cache.lock(&stl_key);
+            short_to_long_cache.insert(stl_key, long_url.clone());
+            println!("\tstoring in stl cache");
+            // release lock
+        }
+    }
+
+    println!("\tsaved to db");
+
+    // peers' bloom filters have never seen the code, they'd answer 404 for it until told
+    invalidate::broadcast(
+        &ctx,
+        &domain,
+        vec![invalidate::Changed {
+            short_code: short_code.clone(),
+            long_url: long_url.clone(),
+        }],
+    );
+
+    let location = format!(
+        "{}{}",
+        domain::base_url(&ctx.config, &domain).unwrap_or_default(),
+        ctx.config.redirect_prefix.path(&short_code)
+    );
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        short_code,
+    )
+        .into_response()
+}
+
+#[derive(serde::Serialize)]
+struct DryRun {
+    /// `None` when it can't be known ahead, under `always_new` or when the hash is taken
+    short_code: Option<String>,
+    /// the link is already stored, a real shorten would return it with 200
+    exists: bool,
+}
+
+/// what `shorten` would answer for `long_url`, read-only: nothing is inserted or cached
+async fn dry_run(
+    ctx: &AppCtx,
+    domain: &str,
+    long_url: &str,
+    alias: Option<&str>,
+    reuse: bool,
+) -> Response {
+    println!("\tdry run");
 
-            // in that case we just check the cache again
-            // to see if the other thread added the short code
+    let outcome = async {
+        let existing_code = if reuse {
+            lookup_code_for_url(domain, long_url, &ctx.pool).await?
+        } else {
+            None
+        };
+        let short_code = match alias {
+            Some(alias) => alias.to_owned(),
+            None if !reuse => return Ok(Ok((None, false))),
+            None => match existing_code {
+                Some(existing_code) => return Ok(Ok((Some(existing_code), true))),
+                None => match predicted_code(ctx, long_url, reuse) {
+                    Some(short_code) => short_code,
+                    // there's no telling which code it'll get
+                    None => return Ok(Ok((None, false))),
+                },
+            },
+        };
 
-            let long_to_short_cache = ctx.long_to_short_cache.lock().unwrap();
-            if let Some(existing_code) = long_to_short_cache.get(&long_url) {
-                println!("\tother thread already stored short code");
-                return (StatusCode::OK, existing_code.to_owned());
+        let occupant = lookup_entry(domain, &short_code, &ctx.pool).await?;
+        Ok::<_, sqlx::Error>(match occupant {
+            Some(url) if url.long_url == long_url && url.deleted_at.is_none() => {
+                Ok((Some(short_code), true))
             }
+            Some(_) if alias.is_some() => Err("Alias already in use"),
+            // a real shorten would move on to a fresh random code
+            Some(_) => Ok((None, false)),
+            None => Ok((Some(short_code), false)),
+        })
+    };
 
-            // otherwise something else happened so we just return an error
+    match outcome.await {
+        Ok(Ok((short_code, exists))) => axum::Json(DryRun { short_code, exists }).into_response(),
+        Ok(Err(conflict)) => (StatusCode::CONFLICT, conflict.to_owned()).into_response(),
+        Err(e) => {
+            eprintln!("Failed to look up entry: {}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Something went wrong on our end".to_owned(),
             )
+                .into_response()
         }
     }
 }
 
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_unique_violation())
+}
+
+/// GET /{short_code}
+///
+/// `redirect` for codes at the root, anything that doesn't start with a
+/// code-shaped segment, or that names a route, is a plain not found
+async fn redirect_at_root(
+    State(ctx): State<AppCtx>,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+    query: Query<HashMap<String, String>>,
+) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let segment = path.split_once('/').map_or(path, |(segment, _)| segment);
+    if !is_valid_code(segment) || ctx.config.redirect_prefix.shadows(segment) {
+        return not_found::unknown_code(&headers);
+    }
+    let short_code = CodePath {
+        short_code: segment.to_owned(),
+    };
+    redirect(
+        State(ctx),
+        method,
+        headers,
+        uri.clone(),
+        Path(short_code),
+        query,
+    )
+    .await
+}
+
+/// the code in a redirect path, a suffix after it is read raw off the `Uri`
+#[derive(serde::Deserialize)]
+struct CodePath {
+    short_code: String,
+}
+
+#[derive(serde::Serialize)]
+struct Resolved {
+    long_url: String,
+}
+
 /// C -> S : redirect(short_code) ...  S -> C : {
 ///     found(long_url),
 ///     not_found()
 /// }
-async fn redirect(State(ctx): State<AppCtx>, Path(short_code): Path<String>) -> Response {
-    println!("/redirect GET <-- {}", short_code);
+///
+/// `?raw=true` or an `Accept` preferring `application/json` gets `200 {"long_url"}`
+/// instead of the redirect, for clients that only want to resolve the code.
+/// Those aren't counted as redirects in the live stats, nothing was followed.
+///
+/// Any method is redirected, only a link with a 307/308 status keeps it.
+///
+/// A path past the code, like `/redirect/docs/guide/intro`, only resolves for links
+/// shortened with `forward_suffix`, it's appended to the target along with the query.
+async fn redirect(
+    State(ctx): State<AppCtx>,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(CodePath { short_code }): Path<CodePath>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    // scanner noise, can't be a code we issued so don't touch the cache or db for it
+    if !is_valid_code(&short_code) {
+        println!("/redirect {} <-- invalid code", method);
+        return not_found::unknown_code(&headers);
+    }
+    println!("/redirect {} <-- {}", method, short_code);
+
+    let domain = domain::from_host(&ctx.config, &headers);
+    let suffix = ctx.config.redirect_prefix.suffix(uri.path(), &short_code);
+    if let Some(suffix) = suffix {
+        println!("\tsuffix: {}", suffix);
+        if !suffix::forwards(&ctx, &domain, &short_code) || !suffix::is_safe(suffix) {
+            return not_found::unknown_code(&headers);
+        }
+    }
+
+    // browsers list text/html first, so only clients asking for json specifically get it
+    let raw = params.get("raw").is_some_and(|v| v == "true")
+        || accept::preferred(&headers, &["text/html", "application/json"])
+            == Some("application/json");
+    if !raw {
+        live::inc(&ctx.counters.redirects);
+    }
+
+    let started = Instant::now();
+    let found = lookup_with_cache(&ctx, &domain, &short_code).await;
+    let lookup_time = started.elapsed();
+    match &found {
+        Ok((_, served)) => metrics::observe(&ctx, *served, lookup_time),
+        Err((StatusCode::NOT_FOUND, _)) => metrics::observe(&ctx, Served::NotFound, lookup_time),
+        Err(_) => {}
+    }
+    let resolved_from = match &found {
+        Ok((_, Served::Cache)) => Some("cache"),
+        Ok(_) => Some("db"),
+        // the filter only ever gains codes, so it rules out now whatever it ruled out then
+        Err((StatusCode::NOT_FOUND, _))
+            if !ctx
+                .code_filter
+                .read()
+                .unwrap()
+                .might_contain(&domain::scoped(&domain, &short_code)) =>
+        {
+            Some("negative-cache")
+        }
+        Err((StatusCode::NOT_FOUND, _)) => Some("db"),
+        Err(_) => None,
+    };
+    let mut click_counted = false;
+
+    let mut res = match found {
+        // only known codes get a counter, so made-up ones can't crowd out the real ones
+        Ok(_) if let Err(retry_after) = link_rate_limit(&ctx, &domain, &short_code) => {
+            println!("\tlink rate limited");
+            let mut res = (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests for this link".to_owned(),
+            )
+                .into_response();
+            retry_after::set(&ctx.config, &mut res, retry_after);
+            res
+        }
+        Ok((long_url, _)) => {
+            let visitor = targets::Visitor::from_headers(&ctx.config, &headers);
+            let long_url = targets::resolve(&ctx, &domain, &short_code, long_url, &visitor).await;
+            let long_url = match suffix {
+                Some(suffix) => {
+                    let query = uri.query().map(suffix::forwarded_query);
+                    suffix::join(&long_url, suffix, query.as_deref())
+                }
+                None => long_url,
+            };
+            if let Err(e) = blocklist::check_redirect(&ctx, &long_url) {
+                e.into_response()
+            } else if raw {
+                axum::Json(Resolved { long_url }).into_response()
+            } else if let Some(res) = link_check::intercept(&ctx, &domain, &short_code, &long_url) {
+                res
+            } else {
+                clicks::record(&ctx, &domain, &short_code);
+                click_counted = true;
+                let long_url = append::for_link(&ctx, &domain, &short_code, &long_url);
+                let status = redirect_status::for_link(&ctx, &domain, &short_code);
+                if targets::rotates(&ctx, &domain, &short_code)
+                    || expiry::expires(&ctx, &domain, &short_code)
+                {
+                    // a permanent redirect would stick in browsers past the target's window or the expiry
+                    status.temporary().redirect(&long_url)
+                } else {
+                    status.redirect(&long_url)
+                }
+            }
+        }
+        Err((StatusCode::NOT_FOUND, _)) => {
+            match rotate::is_gone(&ctx, &domain, &short_code).await {
+                Ok(true) => {
+                    println!("\tcode was rotated away");
+                    (StatusCode::GONE, "Short code has been retired".to_owned()).into_response()
+                }
+                // clients resolving the code want to know it's unknown, not be sent elsewhere
+                Ok(false) if raw => not_found::unknown_code(&headers),
+                Ok(false) => not_found::fallback(&ctx.config, &headers),
+                Err(e) => {
+                    eprintln!("Failed to look up tombstone: {}", e);
+                    lookup_error(&ctx, health::unavailable())
+                }
+            }
+        }
+        Err(e) => lookup_error(&ctx, e),
+    };
+    // the redirect is permanent, caches mustn't hand it to a json client or vice versa
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    if ctx.config.debug_headers && headers.get("x-debug").is_some_and(|v| v == "true") {
+        let debug = res.headers_mut();
+        if let Some(resolved_from) = resolved_from {
+            debug.insert("x-resolved-from", HeaderValue::from_static(resolved_from));
+        }
+        debug.insert(
+            "x-lookup-micros",
+            HeaderValue::from(lookup_time.as_micros() as u64),
+        );
+        debug.insert(
+            "x-click-counted",
+            HeaderValue::from_static(if click_counted { "true" } else { "false" }),
+        );
+        // a cache handing this to the next visitor would show them our internals
+        debug.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    // link checkers asking for a fresh answer get one that nothing downstream keeps either
+    if wants_no_cache(&headers) {
+        res.headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    // nor one visitor's target to another
+    if targets::rotates(&ctx, &domain, &short_code) {
+        res.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-language"));
+        if let Some(country_header) = &ctx.config.country_header
+            && let Ok(country_header) = HeaderValue::from_str(country_header)
+        {
+            res.headers_mut().append(header::VARY, country_header);
+        }
+    }
+    res
+}
+
+/// an error from `lookup_with_cache` as a response, a db that's down says when to try again
+fn lookup_error(ctx: &AppCtx, e: (StatusCode, String)) -> Response {
+    let unavailable = e.0 == StatusCode::SERVICE_UNAVAILABLE;
+    let mut res = e.into_response();
+    if unavailable {
+        retry_after::set(&ctx.config, &mut res, health::retry_in(ctx));
+    }
+    res
+}
 
-    match lookup_with_cache(&ctx, &short_code).await {
-        Ok(long_url) => Redirect::permanent(&long_url).into_response(),
-        Err(e) => e.into_response(),
+/// count a hit on `short_code` against `LINK_RATE_LIMIT`, `Err` holds how long
+/// until the link answers again
+fn link_rate_limit(ctx: &AppCtx, domain: &str, short_code: &str) -> Result<(), Duration> {
+    match &ctx.link_rate_limiter {
+        Some(limiter) => limiter.check(&domain::scoped(domain, short_code), Instant::now()),
+        None => Ok(()),
     }
 }
 
+/// whether the request's `Cache-Control` has a `no-cache` directive
+fn wants_no_cache(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+}
+
 /// C -> S : expand(short_code) ...  S -> C : {
 ///     found(long_url),
 ///     not_found()
 /// }
-async fn expand(State(ctx): State<AppCtx>, Path(short_code): Path<String>) -> impl IntoResponse {
+async fn expand(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Path(short_code): Path<String>,
+) -> impl IntoResponse {
     println!("/expand GET <-- {}", short_code);
 
-    match lookup_with_cache(&ctx, &short_code).await {
-        Ok(long_url) => (StatusCode::OK, long_url).into_response(),
-        Err(e) => e.into_response(),
+    let domain = domain::from_host(&ctx.config, &headers);
+    match lookup_with_cache(&ctx, &domain, &short_code).await {
+        Ok((long_url, _)) => {
+            let visitor = targets::Visitor::from_headers(&ctx.config, &headers);
+            let long_url = targets::resolve(&ctx, &domain, &short_code, long_url, &visitor).await;
+            match blocklist::check_redirect(&ctx, &long_url) {
+                Ok(()) => (StatusCode::OK, long_url).into_response(),
+                Err(e) => e.into_response(),
+            }
+        }
+        Err(e) => lookup_error(&ctx, e),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Preview {
+    short_code: String,
+    long_url: String,
+    title: Option<String>,
+    open_graph: OpenGraphPreview,
+}
+
+#[derive(serde::Serialize)]
+struct OpenGraphPreview {
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+}
+
+/// C -> S : preview(short_code) ...  S -> C : {
+///     found(long_url, title, open_graph),
+///     not_found()
+/// }
+async fn preview(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Path(short_code): Path<String>,
+) -> Response {
+    println!("/preview GET <-- {}", short_code);
+
+    // metadata isn't cached, so this always goes to the db
+    let domain = domain::from_host(&ctx.config, &headers);
+    match lookup_entry(&domain, &short_code, &ctx.pool).await {
+        Ok(Some(url)) if url.expires_at.is_some_and(|at| at <= targets::now(&ctx)) => {
+            (StatusCode::GONE, "Link has expired".to_owned()).into_response()
+        }
+        Ok(Some(url)) if url.deleted_at.is_some() => {
+            (StatusCode::GONE, "Link has been deleted".to_owned()).into_response()
+        }
+        Ok(Some(url)) => axum::Json(Preview {
+            short_code: url.short_code,
+            long_url: url.long_url,
+            title: url.title,
+            open_graph: OpenGraphPreview {
+                title: url.og_title,
+                description: url.og_description,
+                image: url.og_image,
+            },
+        })
+        .into_response(),
+
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            "Short code not recognised".to_owned(),
+        )
+            .into_response(),
+
+        Err(e) => {
+            eprintln!("Failed to lookup entry: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
     }
 }
 
+/// long url for `short_code`, and whether the cache or the db had it
 async fn lookup_with_cache(
     ctx: &AppCtx,
-    short_code: &String,
-) -> Result<String, (StatusCode, String)> {
+    domain: &str,
+    short_code: &str,
+) -> Result<(String, Served), (StatusCode, String)> {
+    // the cache outlives expiries, so this goes first
+    if expiry::is_expired(ctx, domain, short_code) {
+        println!("\tlink has expired");
+        return Err((StatusCode::GONE, "Link has expired".to_owned()));
+    }
+    if soft_delete::is_deleted(ctx, domain, short_code) {
+        println!("\tlink was deleted");
+        return Err((StatusCode::GONE, "Link has been deleted".to_owned()));
+    }
+
+    let stl_key = domain::scoped(domain, short_code);
+    trace::record("short_code", short_code);
+
     {
         // acquire lock on stl
-        let short_to_long_cache = ctx.short_to_long_cache.lock().unwrap();
-        match short_to_long_cache.get(short_code) {
+        let mut short_to_long_cache = ctx.short_to_long_cache.lock(&stl_key);
+        let hit = short_to_long_cache.get(&stl_key);
+        trace::record_bool("cache.hit", hit.is_some());
+        match hit {
             Some(long_url) => {
                 println!("\tfound in cache");
-                return Ok(long_url.to_owned());
+                live::inc(&ctx.counters.cache_hits);
+                return Ok((long_url.to_owned(), Served::Cache));
             }
             None => {
                 println!("\tcache miss - looking in db");
+                live::inc(&ctx.counters.cache_misses);
                 // not in cache, let's check db
             }
         }
         // release lock on stl
     }
 
-    match lookup_entry(&short_code, &ctx.pool).await {
+    if !ctx.code_filter.read().unwrap().might_contain(&stl_key) {
+        println!("\tnot in bloom filter - skipping db");
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Short code not recognised".to_owned(),
+        ));
+    }
+    // cache hits are served above whatever state the db is in, only misses need it
+    if health::is_down(ctx) {
+        println!("\tdb is down");
+        return Err(health::unavailable());
+    }
+
+    match lookup_entry(domain, short_code, &ctx.pool).await {
         Ok(Some(url)) => {
             println!("\tfound in db");
             {
                 // acquire lock
-                let mut short_to_long_cache = ctx.short_to_long_cache.lock().unwrap();
-                short_to_long_cache.insert(short_code.clone(), url.long_url.clone());
+                let mut short_to_long_cache = ctx.short_to_long_cache.lock(&stl_key);
+                short_to_long_cache.insert(stl_key, url.long_url.clone());
                 println!("\tstoring in stl cache");
                 // release lock
             }
-            Ok(url.long_url.to_owned())
+            Ok((url.long_url.to_owned(), Served::Db))
         }
 
         Ok(None) => {
@@ -220,30 +1502,157 @@ async fn lookup_with_cache(
             ))
         }
 
+        // the query can't go wrong by itself, so the db is what failed
         Err(e) => {
             eprintln!("Failed to lookup entry: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Something went wrong on our end".to_owned(),
-            ))
+            Err(health::unavailable())
         }
     }
 }
 
-/// S -> D : store(URL) . D -> S : ok() . D -> S : ok() . end,
-async fn store_entry(url: URL, pool: &sqlx::SqlitePool) -> Result<(), sqlx::Error> {
-    let long_url = &url.long_url;
+/// How claiming a code for a new link turned out
+enum Reserved {
+    /// the link was inserted, under this code
+    Created(String),
+    /// the url was already stored in its domain, under this code
+    Existing(String),
+    /// the code belongs to a different url
+    CodeTaken,
+}
+
+/// S -> D : reserve(URL) . D -> S : {
+///     created(short_code)
+///     existing(short_code)
+///     code_taken()
+/// }
+///
+/// the one way new links get into the db. a uniqueness clash is classified
+/// here, so every path deciding what to do about one sees the same thing
+async fn reserve_code(url: &Url, pool: &sqlx::SqlitePool) -> Result<Reserved, sqlx::Error> {
+    // a url that's already stored comes back as such, only the code itself can still clash
+    let e = match store_entry(url, pool).await {
+        Ok(Stored {
+            short_code,
+            created: true,
+        }) => return Ok(Reserved::Created(short_code)),
+        Ok(Stored { short_code, .. }) => return Ok(Reserved::Existing(short_code)),
+        Err(e) if is_unique_violation(&e) => e,
+        Err(e) => {
+            eprintln!("Failed to store entry: {}", e);
+            return Err(e);
+        }
+    };
+
+    match lookup_entry(&url.domain, &url.short_code, pool).await? {
+        // a soft deleted link keeps its code until the purge, but isn't handed out again
+        Some(existing) if existing.long_url == url.long_url && existing.deleted_at.is_none() => {
+            Ok(Reserved::Existing(existing.short_code))
+        }
+        Some(_) => Ok(Reserved::CodeTaken),
+        // whatever clashed was deleted again in the meantime
+        None => {
+            eprintln!("Failed to store entry: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// What `store_entry` left in the db for a link's url
+struct Stored {
+    /// the code the url is stored under, the one asked for or the one it already had
+    short_code: String,
+    /// whether this call inserted it
+    created: bool,
+}
+
+/// S -> D : store(URL) . D -> S : {
+///     ok(short_code)
+///     existing(short_code)
+/// }
+async fn store_entry(url: &Url, pool: &sqlx::SqlitePool) -> Result<Stored, sqlx::Error> {
+    // the index only sees one form of the url, the other one could already be stored
+    // from before `COMPRESS_URLS` was switched
+    if url.reusable
+        && let Some(short_code) = lookup_code_for_url(&url.domain, &url.long_url, pool).await?
+    {
+        return Ok(Stored {
+            short_code,
+            created: false,
+        });
+    }
+
+    let long_url = &match url.long_url_compressed {
+        Some(_) => compress::key(&url.long_url),
+        None => url.long_url.clone(),
+    };
     let short_code = &url.short_code;
+    let domain = &url.domain;
+    let created_by = &url.created_by;
+    let title = &url.title;
+    let og_title = &url.og_title;
+    let og_description = &url.og_description;
+    let og_image = &url.og_image;
+    let redirect_status = &url.redirect_status;
+    let submitted_ip = &url.submitted_ip;
+    let submitted_user_agent = &url.submitted_user_agent;
+    let reusable = url.reusable;
+    let updated_at = url.updated_at;
+    let expires_at = url.expires_at;
+    let description = &url.description;
+    let append_params = &url.append_params;
+    let forward_suffix = url.forward_suffix;
+    let long_url_compressed = &url.long_url_compressed;
+    let source = &url.source;
+    let tag = &url.tag;
 
-    sqlx::query!(
-        "INSERT INTO url (long_url, short_code) VALUES ($1, $2)",
+    // a reusable url that's already there is left alone, its code is looked up instead
+    let insert = sqlx::query_scalar!(
+        "INSERT INTO url (long_url, short_code, domain, created_by, title, og_title, og_description, og_image, redirect_status, submitted_ip, submitted_user_agent, reusable, updated_at, expires_at, description, append_params, forward_suffix, long_url_compressed, source, tag)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20)
+        ON CONFLICT (domain, long_url) WHERE reusable DO NOTHING
+        RETURNING short_code",
         long_url,
-        short_code
+        short_code,
+        domain,
+        created_by,
+        title,
+        og_title,
+        og_description,
+        og_image,
+        redirect_status,
+        submitted_ip,
+        submitted_user_agent,
+        reusable,
+        updated_at,
+        expires_at,
+        description,
+        append_params,
+        forward_suffix,
+        long_url_compressed,
+        source,
+        tag
+    );
+    let inserted = trace::db(
+        "store_entry",
+        &[("short_code", short_code)],
+        insert.fetch_optional(pool),
     )
-    .execute(pool)
     .await?;
+    if let Some(short_code) = inserted {
+        return Ok(Stored {
+            short_code,
+            created: true,
+        });
+    }
 
-    Ok(())
+    match lookup_code_for_url(domain, long_url, pool).await? {
+        Some(short_code) => Ok(Stored {
+            short_code,
+            created: false,
+        }),
+        // the row we clashed with was deleted in between, nothing is stored
+        None => Err(sqlx::Error::RowNotFound),
+    }
 }
 
 /// S -> D : lookup(short_code) . D -> S : {
@@ -251,12 +1660,67 @@ async fn store_entry(url: URL, pool: &sqlx::SqlitePool) -> Result<(), sqlx::Erro
 ///     ok(URL)
 /// }
 async fn lookup_entry(
-    short_code: &String,
+    domain: &str,
+    short_code: &str,
+    pool: &sqlx::SqlitePool,
+) -> Result<Option<Url>, sqlx::Error> {
+    let res = trace::db(
+        "lookup_entry",
+        &[("short_code", short_code)],
+        sqlx::query_as!(
+            Url,
+            "SELECT * FROM url WHERE domain = $1 AND short_code = $2",
+            domain,
+            short_code
+        )
+        .fetch_optional(pool),
+    )
+    .await?;
+
+    Ok(res.map(compress::restore))
+}
+
+/// S -> D : lookup_code(long_url) . D -> S : {
+///     not_found()
+///     ok(short_code)
+/// }
+async fn lookup_code_for_url(
+    domain: &str,
+    long_url: &str,
     pool: &sqlx::SqlitePool,
-) -> Result<Option<URL>, sqlx::Error> {
-    let res = sqlx::query_as!(URL, "SELECT * FROM url WHERE short_code = $1", short_code)
-        .fetch_optional(pool)
-        .await?;
+) -> Result<Option<String>, sqlx::Error> {
+    // stored as it is, or by its key when it was compressed
+    let key = compress::key(long_url);
+    let res = sqlx::query_scalar!(
+        "SELECT short_code FROM url WHERE domain = $1 AND long_url IN ($2, $3) AND reusable",
+        domain,
+        long_url,
+        key
+    )
+    .fetch_optional(pool)
+    .await?;
 
     Ok(res)
 }
+
+/// S -> D : count_codes(long_url, except) . D -> S : ok(count)
+///
+/// every code pointing at `long_url` besides `except`, so an alias already
+/// pointing there doesn't count against itself
+async fn count_codes_for_url(
+    domain: &str,
+    long_url: &str,
+    except: &str,
+    pool: &sqlx::SqlitePool,
+) -> Result<i64, sqlx::Error> {
+    let key = compress::key(long_url);
+    sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM url WHERE domain = $1 AND long_url IN ($2, $3) AND short_code != $4",
+        domain,
+        long_url,
+        key,
+        except
+    )
+    .fetch_one(pool)
+    .await
+}
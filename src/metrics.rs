@@ -0,0 +1,257 @@
+use std::{fmt::Write, sync::atomic::Ordering, time::Duration};
+
+use axum::{
+    extract::State,
+    http::{HeaderValue, header},
+    response::IntoResponse,
+};
+
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusRecorder};
+
+use crate::{AppCtx, cache::ShardedCache, tasks::TaskStats};
+
+/// upper bounds of the latency buckets, in ms. cache hits land in the first few,
+/// db lookups further up
+const BUCKETS_MS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0,
+];
+
+const REDIRECT_DURATION: &str = "url_shortener_redirect_duration_ms";
+
+/// Where a redirect's target came from, each gets its own histogram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Served {
+    Cache,
+    Db,
+    NotFound,
+}
+
+impl Served {
+    fn label(self) -> &'static str {
+        match self {
+            Served::Cache => "cache",
+            Served::Db => "db",
+            Served::NotFound => "not_found",
+        }
+    }
+}
+
+/// Redirect lookup latency, by where the target came from.
+///
+/// each `AppCtx` records into its own recorder rather than the global one, so
+/// several apps in one process (as in tests) don't count each other's redirects
+#[derive(Debug)]
+pub struct RedirectLatency {
+    recorder: PrometheusRecorder,
+}
+
+impl Default for RedirectLatency {
+    fn default() -> RedirectLatency {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full(REDIRECT_DURATION.to_owned()), BUCKETS_MS)
+            .expect("buckets are not empty")
+            .build_recorder();
+        ::metrics::with_local_recorder(&recorder, || {
+            ::metrics::describe_histogram!(
+                REDIRECT_DURATION,
+                "Time redirects spent finding their target, in milliseconds"
+            );
+        });
+        RedirectLatency { recorder }
+    }
+}
+
+/// record how long a redirect took to find its target
+pub fn observe(ctx: &AppCtx, served: Served, elapsed: Duration) {
+    ::metrics::with_local_recorder(&ctx.redirect_latency.recorder, || {
+        ::metrics::histogram!(REDIRECT_DURATION, "served" => served.label())
+            .record(elapsed.as_secs_f64() * 1000.0);
+    });
+}
+
+/// GET /metrics
+///
+/// counters and the redirect latency histogram in the Prometheus text format
+pub async fn metrics(State(ctx): State<AppCtx>) -> impl IntoResponse {
+    let counters = &ctx.counters;
+    let mut out = String::new();
+
+    for (name, help, counter) in [
+        (
+            "url_shortener_redirects_total",
+            "Redirect requests served, not counting raw lookups",
+            &counters.redirects,
+        ),
+        (
+            "url_shortener_cache_hits_total",
+            "Short code lookups answered from the cache",
+            &counters.cache_hits,
+        ),
+        (
+            "url_shortener_cache_misses_total",
+            "Short code lookups the cache couldn't answer",
+            &counters.cache_misses,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+
+    out.push_str(&ctx.redirect_latency.recorder.handle().render());
+
+    let health = ctx.pool_health.read().unwrap().clone();
+    for (name, help, value) in [
+        (
+            "url_shortener_db_healthy",
+            "1 while the background db checks pass, 0 once too many in a row have failed",
+            health.healthy as u64,
+        ),
+        (
+            "url_shortener_db_connections",
+            "Open db connections as of the last check",
+            health.connections as u64,
+        ),
+        (
+            "url_shortener_db_idle_connections",
+            "Idle db connections as of the last check",
+            health.idle_connections as u64,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    let tasks = ctx.task_health.snapshot();
+    for (name, kind, help, measure) in [
+        (
+            "url_shortener_task_last_run_timestamp_seconds",
+            "gauge",
+            "Unix time each background task last finished a run, 0 before the first",
+            (|stats| stats.last_run_at as u64) as fn(&TaskStats) -> u64,
+        ),
+        (
+            "url_shortener_task_last_run_duration_ms",
+            "gauge",
+            "How long each background task's last run took, in milliseconds",
+            |stats| stats.last_duration_ms,
+        ),
+        (
+            "url_shortener_task_runs_succeeded_total",
+            "counter",
+            "Background task runs that succeeded",
+            |stats| stats.successes,
+        ),
+        (
+            "url_shortener_task_runs_failed_total",
+            "counter",
+            "Background task runs that failed",
+            |stats| stats.failures,
+        ),
+        (
+            "url_shortener_task_items_total",
+            "counter",
+            "Items background tasks handled, links flushed, checked, purged or sent to peers",
+            |stats| stats.items,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (task, stats) in &tasks {
+            let _ = writeln!(out, "{}{{task=\"{}\"}} {}", name, task, measure(stats));
+        }
+    }
+
+    // both caches keep running totals, so this never walks their entries
+    for (name, help, measure) in [
+        (
+            "url_shortener_cache_entries",
+            "Entries held in each cache",
+            ShardedCache::len as fn(&ShardedCache) -> usize,
+        ),
+        (
+            "url_shortener_cache_bytes",
+            "Approximate memory held by each cache, keys and values plus a fixed per-entry overhead",
+            ShardedCache::bytes,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (cache_name, cache) in [
+            ("short_to_long", &ctx.short_to_long_cache),
+            ("long_to_short", &ctx.long_to_short_cache),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}{{cache=\"{}\"}} {}",
+                name,
+                cache_name,
+                measure(cache)
+            );
+        }
+    }
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        out,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        domain::{self, DEFAULT_DOMAIN},
+        tests::{ctx, get, shorten},
+    };
+
+    /// the value of the series `line` starts with, `0` when it isn't there
+    fn sample(metrics: &str, series: &str) -> u64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn redirects_are_counted_by_where_they_were_served_from() {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com").await;
+        let redirect = format!("/redirect/{}", short_code);
+
+        for _ in 0..2 {
+            assert!(get(&ctx, &redirect).await.status.is_redirection());
+        }
+        // dropped from the cache, the next one comes from the db and refills it
+        ctx.short_to_long_cache
+            .remove(&domain::scoped(DEFAULT_DOMAIN, &short_code));
+        get(&ctx, &redirect).await;
+        get(&ctx, &redirect).await;
+        for _ in 0..3 {
+            get(&ctx, "/redirect/missing").await;
+        }
+
+        let metrics = get(&ctx, "/metrics").await.body;
+        assert!(metrics.contains("# TYPE url_shortener_redirect_duration_ms histogram"));
+        for (served, count) in [("cache", 3), ("db", 1), ("not_found", 3)] {
+            let series = |suffix: &str, extra: &str| {
+                format!(
+                    "url_shortener_redirect_duration_ms_{}{{served=\"{}\"{}}}",
+                    suffix, served, extra
+                )
+            };
+            assert_eq!(sample(&metrics, &series("count", "")), count, "{}", served);
+            // every sample lands in a bucket, the last one holds them all
+            assert_eq!(
+                sample(&metrics, &series("bucket", ",le=\"+Inf\"")),
+                count,
+                "{}",
+                served
+            );
+            assert!(metrics.contains(&series("bucket", ",le=\"0.05\"")));
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::config::Config;
+
+/// Canonical form of a submitted long url, so equivalent urls share a code.
+///
+/// Every rewrite here is opt-in, since some servers treat the differences as
+/// meaningful.
+pub fn normalize_url(config: &Config, long_url: &str) -> String {
+    let Some((prefix, path, rest)) = split(long_url) else {
+        // not something we know how to take apart, leave it be
+        return long_url.to_owned();
+    };
+
+    let (mut path, mut rest) = (path.to_owned(), rest.to_owned());
+
+    // decoding first lets `%2E%2E` be resolved as `..` below, per RFC 3986 5.2
+    if config.normalize_percent_encoding {
+        path = normalize_percent_encoding(&path);
+        rest = normalize_percent_encoding(&rest);
+    }
+    if config.normalize_path {
+        path = normalize_path(&path);
+    }
+
+    format!("{}{}{}", prefix, path, rest)
+}
+
+/// `scheme://authority`, path, then `?query#fragment`
+fn split(url: &str) -> Option<(&str, &str, &str)> {
+    let authority_start = url.find("://")? + 3;
+    let path_start = url[authority_start..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |i| authority_start + i);
+    let rest_start = url[path_start..]
+        .find(['?', '#'])
+        .map_or(url.len(), |i| path_start + i);
+
+    Some((
+        &url[..path_start],
+        &url[path_start..rest_start],
+        &url[rest_start..],
+    ))
+}
+
+/// RFC 3986 6.2.2: decode escaped unreserved characters and uppercase the hex
+/// digits of every other escape, reserved characters stay encoded so `%2F`
+/// and `/` remain different urls
+fn normalize_percent_encoding(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = s.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            if is_unreserved(byte) {
+                out.push(byte as char);
+            } else {
+                out.push('%');
+                out.push_str(&hex.to_ascii_uppercase());
+            }
+            i += 3;
+            continue;
+        }
+
+        // copy the whole char so multi-byte input isn't split
+        let c = s[i..].chars().next().unwrap();
+        out.push(c);
+        i += c.len_utf8();
+    }
+    out
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// collapse `//` and resolve `.`/`..` segments, keeping any trailing slash
+fn normalize_path(path: &str) -> String {
+    let Some(path) = path.strip_prefix('/') else {
+        return path.to_owned();
+    };
+
+    let segments = path.split('/').collect::<Vec<_>>();
+    let mut out: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        match *segment {
+            "" | "." => trailing_slash = last,
+            ".." => {
+                out.pop();
+                trailing_slash = last;
+            }
+            segment => out.push(segment),
+        }
+    }
+
+    if out.is_empty() {
+        return "/".to_owned();
+    }
+    let mut normalized = format!("/{}", out.join("/"));
+    if trailing_slash {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreserved_escapes_are_decoded() {
+        assert_eq!(normalize_percent_encoding("/%7Euser"), "/~user");
+        assert_eq!(normalize_percent_encoding("/a%2Db%5fc"), "/a-b_c");
+    }
+
+    #[test]
+    fn other_escapes_get_uppercase_hex() {
+        assert_eq!(normalize_percent_encoding("/a%2fb%3a"), "/a%2Fb%3A");
+    }
+
+    #[test]
+    fn reserved_escapes_stay_encoded() {
+        assert_eq!(normalize_percent_encoding("/a%2Fb"), "/a%2Fb");
+        assert_ne!(normalize_percent_encoding("/a%2Fb"), "/a/b");
+    }
+
+    #[test]
+    fn normalize_url_only_decodes_when_asked() {
+        let mut config = Config {
+            normalize_percent_encoding: false,
+            normalize_path: false,
+            ..Config::from_env()
+        };
+        let long_url = "https://example.com/%7euser?q=%2f";
+        assert_eq!(normalize_url(&config, long_url), long_url);
+
+        config.normalize_percent_encoding = true;
+        assert_eq!(
+            normalize_url(&config, long_url),
+            "https://example.com/~user?q=%2F"
+        );
+    }
+
+    fn normalizing_paths() -> Config {
+        Config {
+            normalize_percent_encoding: false,
+            normalize_path: true,
+            ..Config::from_env()
+        }
+    }
+
+    #[test]
+    fn duplicate_slashes_collapse() {
+        assert_eq!(normalize_path("//a//b"), "/a/b");
+        assert_eq!(normalize_path("/a///b/"), "/a/b/");
+    }
+
+    #[test]
+    fn dot_segments_are_resolved() {
+        assert_eq!(normalize_path("/a/./b"), "/a/b");
+        assert_eq!(normalize_path("/a/../b"), "/b");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/a/b/./"), "/a/b/");
+    }
+
+    #[test]
+    fn dot_dot_stops_at_the_root() {
+        assert_eq!(normalize_path("/../a"), "/a");
+        assert_eq!(normalize_path("/a/../../.."), "/");
+    }
+
+    #[test]
+    fn normalize_url_leaves_the_query_and_fragment_alone() {
+        assert_eq!(
+            normalize_url(
+                &normalizing_paths(),
+                "https://example.com//a/./b/../c?next=//x/../y#/./z"
+            ),
+            "https://example.com/a/c?next=//x/../y#/./z"
+        );
+        // segments that only look like dots stay, `..` isn't `%2E%2E` without decoding
+        let long_url = "https://example.com/a/.../%2E%2E/b.";
+        assert_eq!(normalize_url(&normalizing_paths(), long_url), long_url);
+    }
+
+    #[test]
+    fn paths_are_left_alone_with_the_flag_off() {
+        let config = Config {
+            normalize_path: false,
+            ..normalizing_paths()
+        };
+        let long_url = "https://example.com//a/./b/../c/";
+        assert_eq!(normalize_url(&config, long_url), long_url);
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{accept, config::Config, redirect_status::RedirectStatus};
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+
+const PAGE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>Link not found</title>
+    <style>
+        body { font-family: system-ui, sans-serif; text-align: center; padding: 4rem 1rem; color: #333; }
+        h1 { font-size: 2rem; margin-bottom: 0.5rem; }
+    </style>
+</head>
+<body>
+    <h1>Link not found</h1>
+    <p>This short link doesn't exist, or it may have been removed.</p>
+</body>
+</html>
+"#;
+
+/// 404 for an unknown short code, as a page for browsers and the usual error envelope otherwise
+pub fn unknown_code(headers: &HeaderMap) -> Response {
+    match accept::preferred(headers, &["text/plain", "text/html", "application/json"]) {
+        Some("text/html") => (StatusCode::NOT_FOUND, Html(PAGE)).into_response(),
+        _ => (
+            StatusCode::NOT_FOUND,
+            "Short code not recognised".to_owned(),
+        )
+            .into_response(),
+    }
+}
+
+/// what `redirect` answers for an unknown code, a 302 to `NOT_FOUND_REDIRECT` when one is set
+pub fn fallback(config: &Config, headers: &HeaderMap) -> Response {
+    match &config.not_found_redirect {
+        Some(url) => RedirectStatus::Found.redirect(url),
+        None => unknown_code(headers),
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{AppCtx, health, retry_after};
+
+/// how long a shed client is told to wait, the overload is usually momentary
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// middleware turning requests away with 503 once `MAX_IN_FLIGHT` are already being served
+pub async fn shed(State(ctx): State<AppCtx>, req: Request, next: Next) -> Response {
+    let Some(in_flight) = &ctx.in_flight else {
+        return next.run(req).await;
+    };
+    if health::is_probe(req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    // held until the response is ready
+    let Ok(_permit) = in_flight.clone().try_acquire_owned() else {
+        println!("\tshedding {} {}", req.method(), req.uri().path());
+        let mut res = (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is overloaded".to_owned(),
+        )
+            .into_response();
+        retry_after::set(&ctx.config, &mut res, RETRY_AFTER);
+        return res;
+    };
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        tests::{self, ctx_with, get},
+    };
+
+    #[tokio::test]
+    async fn requests_past_the_limit_get_503_but_probes_still_answer() {
+        let ctx = ctx_with(Config {
+            max_in_flight: Some(1),
+            ..tests::config()
+        })
+        .await;
+
+        assert!(get(&ctx, "/version").await.status.is_success());
+
+        // stands in for a request still being served
+        let busy = ctx.in_flight.clone().unwrap().try_acquire_owned().unwrap();
+        let reply = get(&ctx, "/version").await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply.header(header::RETRY_AFTER), Some("1"));
+        assert_eq!(reply.json()["error"]["message"], "Server is overloaded");
+        for probe in ["/health", "/livez", "/ping", "/readyz"] {
+            assert_eq!(get(&ctx, probe).await.status, StatusCode::OK, "{}", probe);
+        }
+
+        drop(busy);
+        assert!(get(&ctx, "/version").await.status.is_success());
+    }
+}
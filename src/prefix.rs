@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+/// First path segments of every route besides `redirect`, which can't hold
+/// short codes when codes live at the root. Every new top-level route goes here
+pub const RESERVED: &[&str] = &[
+    "favicon.ico",
+    "robots.txt",
+    "livez",
+    "health",
+    "ping",
+    "readyz",
+    "version",
+    "metrics",
+    "shorten",
+    "expand",
+    "preview",
+    "stats",
+    "resolve",
+    "lookup",
+    "ws",
+    "links",
+    "admin",
+];
+
+/// Where `redirect` is mounted, `/redirect` unless `REDIRECT_PREFIX` says otherwise.
+///
+/// Stored without a trailing slash, so the root is the empty string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectPrefix(String);
+
+impl Default for RedirectPrefix {
+    fn default() -> Self {
+        RedirectPrefix("/redirect".to_owned())
+    }
+}
+
+impl FromStr for RedirectPrefix {
+    type Err = ();
+
+    /// `/r`, `/r/` and `/go/to` are all fine, `/` puts codes at the root.
+    /// a prefix under another route's path would shadow it, so those are refused
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prefix = s.trim().trim_end_matches('/');
+        if !prefix.is_empty() && !prefix.starts_with('/') {
+            return Err(());
+        }
+
+        let segments = prefix.split('/').skip(1).collect::<Vec<_>>();
+        let valid = segments.iter().all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        });
+        if !valid
+            || segments
+                .first()
+                .is_some_and(|first| RESERVED.contains(first))
+        {
+            return Err(());
+        }
+
+        Ok(RedirectPrefix(prefix.to_owned()))
+    }
+}
+
+impl RedirectPrefix {
+    /// axum route pattern for `redirect`
+    pub fn route(&self) -> String {
+        format!("{}/{{short_code}}", self.0)
+    }
+
+    /// axum route pattern for `redirect` with a path past the code, see `suffix`
+    pub fn suffix_route(&self) -> String {
+        format!("{}/{{short_code}}/{{*suffix}}", self.0)
+    }
+
+    /// the raw, still percent-encoded, part of `path` past `short_code`'s own path
+    pub fn suffix<'a>(&self, path: &'a str, short_code: &str) -> Option<&'a str> {
+        path.strip_prefix(&self.path(short_code))?
+            .strip_prefix('/')
+            .filter(|suffix| !suffix.is_empty())
+    }
+
+    /// path `short_code` redirects from
+    pub fn path(&self, short_code: &str) -> String {
+        format!("{}/{}", self.0, short_code)
+    }
+
+    /// whether codes live at `/{short_code}`
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// whether `code` would be unreachable because another route has its path
+    pub fn shadows(&self, code: &str) -> bool {
+        self.is_root() && RESERVED.contains(&code)
+    }
+}
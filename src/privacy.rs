@@ -0,0 +1,77 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+/// How much of a long url may end up in the logs, urls can carry tokens or emails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogUrls {
+    /// the whole url, only for environments where logs are private
+    Full,
+    /// scheme and host, everything after is dropped
+    #[default]
+    Redacted,
+    /// an opaque hash, enough to correlate lines about the same url
+    Hash,
+}
+
+impl FromStr for LogUrls {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogUrls::Full),
+            "redacted" => Ok(LogUrls::Redacted),
+            "hash" => Ok(LogUrls::Hash),
+            _ => Err(()),
+        }
+    }
+}
+
+/// `long_url` as it should appear in a log line
+pub fn log_url(config: &Config, long_url: &str) -> String {
+    match config.log_urls {
+        LogUrls::Full => long_url.to_owned(),
+        LogUrls::Redacted => match long_url.split_once("://") {
+            Some((scheme, rest)) => {
+                let host = rest.split(['/', '?', '#']).next().unwrap_or("");
+                // userinfo is as sensitive as the path
+                let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+                format!("{}://{}/...", scheme, host)
+            }
+            None => "<redacted>".to_owned(),
+        },
+        LogUrls::Hash => {
+            let mut s = DefaultHasher::new();
+            long_url.hash(&mut s);
+            format!("url#{:x}", s.finish())
+        }
+    }
+}
+
+/// longest `User-Agent` kept for a submitter, anything past it is noise
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// `ip` as it's stored against a link, salted and hashed so it can only be compared, not read
+pub fn hash_ip(config: &Config, ip: IpAddr) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(config.submitter_ip_salt.as_deref().unwrap_or("").as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+    hasher.finalize()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// `User-Agent` as it's stored against a link
+pub fn trim_user_agent(user_agent: &str) -> String {
+    match user_agent.char_indices().nth(MAX_USER_AGENT_LEN) {
+        Some((end, _)) => user_agent[..end].to_owned(),
+        None => user_agent.to_owned(),
+    }
+}
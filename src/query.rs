@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::{StatusCode, request::Parts},
+};
+
+use crate::AppCtx;
+
+/// `Query<HashMap<String, String>>` that refuses a query string with broken
+/// percent-encoding. the plain extractor passes a stray `%` through as it is and
+/// swaps invalid utf-8 for `�`, so a hand-built request would quietly store the wrong url
+pub struct StrictQuery(pub HashMap<String, String>);
+
+impl FromRequestParts<AppCtx> for StrictQuery {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _: &AppCtx) -> Result<Self, Self::Rejection> {
+        let malformed = || (StatusCode::BAD_REQUEST, "Malformed query string".to_owned());
+        if !is_well_formed(parts.uri.query().unwrap_or_default()) {
+            println!("\tmalformed query string");
+            return Err(malformed());
+        }
+        Query::try_from_uri(&parts.uri)
+            .map(|Query(params)| StrictQuery(params))
+            .map_err(|_| malformed())
+    }
+}
+
+/// whether every `%` starts an escape and every name and value decodes to utf-8
+fn is_well_formed(query: &str) -> bool {
+    query
+        .split(['&', '='])
+        .all(|part| decode(part).is_some_and(|bytes| String::from_utf8(bytes).is_ok()))
+}
+
+fn decode(part: &str) -> Option<Vec<u8>> {
+    let mut bytes = part.bytes();
+    let mut decoded = Vec::with_capacity(part.len());
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hi = (bytes.next()? as char).to_digit(16)?;
+        let lo = (bytes.next()? as char).to_digit(16)?;
+        decoded.push((hi * 16 + lo) as u8);
+    }
+    Some(decoded)
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{AppCtx, client_ip, health, keys::API_KEY_HEADER, retry_after};
+
+/// How requests are counted against `RATE_LIMIT`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// bucket of `limit` tokens refilled evenly over the window, allows bursts
+    #[default]
+    TokenBucket,
+    /// at most `limit` requests in any trailing window, no burst at window boundaries
+    SlidingWindow,
+}
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token_bucket" => Ok(Strategy::TokenBucket),
+            "sliding_window" => Ok(Strategy::SlidingWindow),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Entry {
+    Bucket { tokens: f64, refilled_at: Instant },
+    Window(VecDeque<Instant>),
+}
+
+/// Request limiter keyed by client, by API key or IP, or for `LINK_RATE_LIMIT` by scoped code
+#[derive(Debug)]
+pub struct RateLimiter {
+    strategy: Strategy,
+    limit: u32,
+    window: Duration,
+    clients: Mutex<HashMap<String, Entry>>,
+}
+
+/// clients tracked before idle ones are swept out, codes counted for
+/// `LINK_RATE_LIMIT` included
+const SWEEP_THRESHOLD: usize = 10_000;
+
+impl RateLimiter {
+    pub fn new(strategy: Strategy, limit: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            strategy,
+            limit,
+            window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// count a request from `client`, `Err` holds how long until it may retry
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        // config never asks for 0, but a bucket that never refills has no next
+        // token to wait for and a window with no hits has no oldest one
+        if self.limit == 0 {
+            return Err(self.window);
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() > SWEEP_THRESHOLD {
+            clients.retain(|_, entry| !self.is_idle(entry, now));
+        }
+
+        let entry = clients
+            .entry(client.to_owned())
+            .or_insert_with(|| match self.strategy {
+                Strategy::TokenBucket => Entry::Bucket {
+                    tokens: self.limit as f64,
+                    refilled_at: now,
+                },
+                Strategy::SlidingWindow => Entry::Window(VecDeque::new()),
+            });
+
+        match entry {
+            Entry::Bucket {
+                tokens,
+                refilled_at,
+            } => {
+                let rate = self.limit as f64 / self.window.as_secs_f64();
+                let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+                *tokens = (*tokens + elapsed * rate).min(self.limit as f64);
+                *refilled_at = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    Ok(())
+                } else {
+                    Err(Duration::from_secs_f64((1.0 - *tokens) / rate))
+                }
+            }
+
+            Entry::Window(hits) => {
+                // drop everything that has slid out of the window
+                while let Some(&oldest) = hits.front() {
+                    if now.duration_since(oldest) < self.window {
+                        break;
+                    }
+                    hits.pop_front();
+                }
+
+                if hits.len() < self.limit as usize {
+                    hits.push_back(now);
+                    Ok(())
+                } else {
+                    // a slot frees up once the oldest hit leaves the window
+                    let oldest = hits[0];
+                    Err(self.window - now.duration_since(oldest))
+                }
+            }
+        }
+    }
+
+    fn is_idle(&self, entry: &Entry, now: Instant) -> bool {
+        match entry {
+            Entry::Bucket { refilled_at, .. } => now.duration_since(*refilled_at) >= self.window,
+            Entry::Window(hits) => hits
+                .back()
+                .is_none_or(|&last| now.duration_since(last) >= self.window),
+        }
+    }
+}
+
+/// middleware rejecting clients over their limit with 429
+pub async fn limit(
+    State(ctx): State<AppCtx>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &ctx.rate_limiter else {
+        return next.run(req).await;
+    };
+    if health::is_probe(req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    // only known keys get their own bucket, otherwise made-up keys would dodge the limit
+    let client = match req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|key| ctx.config.api_keys.iter().any(|k| k == key))
+    {
+        Some(key) => format!("key:{}", key),
+        None => format!(
+            "ip:{}",
+            client_ip::resolve(&ctx.config, peer, req.headers())
+        ),
+    };
+
+    match limiter.check(&client, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            println!("\trate limited {}", client);
+            let mut res = (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests".to_owned(),
+            )
+                .into_response();
+            retry_after::set(&ctx.config, &mut res, retry_after);
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        tests::{self, call, ctx_with},
+    };
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn secs(start: Instant, secs: f64) -> Instant {
+        start + Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn sliding_window_counts_the_trailing_window() {
+        let limiter = RateLimiter::new(Strategy::SlidingWindow, 2, WINDOW);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("ip:a", start), Ok(()));
+        assert_eq!(limiter.check("ip:a", secs(start, 5.0)), Ok(()));
+        // a slot frees up when the first hit is 10s old, not before
+        assert_eq!(
+            limiter.check("ip:a", secs(start, 9.0)),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(limiter.check("ip:a", secs(start, 10.0)), Ok(()));
+        // the second hit is still in the window
+        assert_eq!(
+            limiter.check("ip:a", secs(start, 11.0)),
+            Err(Duration::from_secs(4))
+        );
+        assert_eq!(limiter.check("ip:a", secs(start, 15.0)), Ok(()));
+
+        // other clients are counted on their own
+        assert_eq!(limiter.check("ip:b", secs(start, 15.0)), Ok(()));
+    }
+
+    #[test]
+    fn sliding_window_has_no_burst_at_the_boundary() {
+        let limiter = RateLimiter::new(Strategy::SlidingWindow, 2, WINDOW);
+        let start = Instant::now();
+
+        // both hits at the end of one window...
+        assert_eq!(limiter.check("ip:a", secs(start, 9.5)), Ok(()));
+        assert_eq!(limiter.check("ip:a", secs(start, 9.9)), Ok(()));
+        // ...still count just after it, where a fixed window would have started over
+        assert!(limiter.check("ip:a", secs(start, 10.1)).is_err());
+        assert!(limiter.check("ip:a", secs(start, 19.0)).is_err());
+        assert_eq!(limiter.check("ip:a", secs(start, 19.5)), Ok(()));
+    }
+
+    #[test]
+    fn token_bucket_refills_evenly() {
+        let limiter = RateLimiter::new(Strategy::TokenBucket, 2, WINDOW);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("ip:a", start), Ok(()));
+        assert_eq!(limiter.check("ip:a", start), Ok(()));
+        assert_eq!(limiter.check("ip:a", start), Err(Duration::from_secs(5)));
+        assert_eq!(limiter.check("ip:a", secs(start, 5.0)), Ok(()));
+    }
+
+    #[test]
+    fn a_limit_of_0_refuses_everything_without_panicking() {
+        for strategy in [Strategy::TokenBucket, Strategy::SlidingWindow] {
+            let limiter = RateLimiter::new(strategy, 0, WINDOW);
+            let start = Instant::now();
+            assert_eq!(limiter.check("ip:a", start), Err(WINDOW), "{:?}", strategy);
+            assert_eq!(
+                limiter.check("ip:a", secs(start, 60.0)),
+                Err(WINDOW),
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn a_limit_of_1_allows_one_per_window() {
+        for strategy in [Strategy::TokenBucket, Strategy::SlidingWindow] {
+            let limiter = RateLimiter::new(strategy, 1, WINDOW);
+            let start = Instant::now();
+            assert_eq!(limiter.check("ip:a", start), Ok(()), "{:?}", strategy);
+            assert_eq!(
+                limiter.check("ip:a", secs(start, 1.0)),
+                Err(Duration::from_secs(9)),
+                "{:?}",
+                strategy
+            );
+            assert_eq!(
+                limiter.check("ip:a", secs(start, 10.0)),
+                Ok(()),
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_get_429() {
+        let ctx = ctx_with(Config {
+            rate_limit: Some(1),
+            rate_limit_strategy: Strategy::SlidingWindow,
+            ..tests::config()
+        })
+        .await;
+        let shorten = || {
+            Request::post("/shorten?q=https%3A%2F%2Fexample.com%2F")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert!(call(&ctx, shorten()).await.status.is_success());
+        let reply = call(&ctx, shorten()).await;
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(reply.header(axum::http::header::RETRY_AFTER).is_some());
+    }
+
+    #[tokio::test]
+    async fn probes_are_never_rate_limited() {
+        let ctx = ctx_with(Config {
+            rate_limit: Some(1),
+            rate_limit_strategy: Strategy::SlidingWindow,
+            ..tests::config()
+        })
+        .await;
+
+        for _ in 0..3 {
+            for probe in ["/health", "/livez", "/ping"] {
+                assert_eq!(tests::get(&ctx, probe).await.status, StatusCode::OK);
+            }
+        }
+        // and they didn't use up the client's allowance either
+        assert!(tests::get(&ctx, "/version").await.status.is_success());
+        let reply = tests::get(&ctx, "/version").await;
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
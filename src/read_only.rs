@@ -0,0 +1,55 @@
+use std::sync::atomic::Ordering;
+
+use axum::{
+    Json,
+    extract::{FromRequestParts, State},
+    http::{StatusCode, request::Parts},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppCtx, admin::AdminAuth};
+
+/// whether writes are currently refused
+pub fn is_on(ctx: &AppCtx) -> bool {
+    ctx.read_only.load(Ordering::Relaxed)
+}
+
+/// Guard for every route that writes to the db, 503 while in read-only mode
+pub struct Writable;
+
+impl FromRequestParts<AppCtx> for Writable {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(_: &mut Parts, ctx: &AppCtx) -> Result<Self, Self::Rejection> {
+        if is_on(ctx) {
+            println!("\trefused, read-only");
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service in read-only mode".to_owned(),
+            ));
+        }
+        Ok(Writable)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReadOnly {
+    read_only: bool,
+}
+
+/// POST /admin/readonly
+///
+/// body is `{"read_only": bool}`, switches write refusal on or off until the next restart
+pub async fn set(
+    _: AdminAuth,
+    State(ctx): State<AppCtx>,
+    Json(req): Json<ReadOnly>,
+) -> impl IntoResponse {
+    println!("/admin/readonly POST <-- {}", req.read_only);
+
+    ctx.read_only.store(req.read_only, Ordering::Relaxed);
+    Json(ReadOnly {
+        read_only: is_on(&ctx),
+    })
+}
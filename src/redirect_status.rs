@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+use axum::{
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+
+use crate::{AppCtx, domain};
+
+/// Status code a link redirects with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedirectStatus {
+    /// 301, permanent, clients may switch to GET
+    MovedPermanently,
+    /// 302, temporary, clients may switch to GET
+    Found,
+    /// 307, temporary, the method and body are kept
+    TemporaryRedirect,
+    /// 308, permanent, the method and body are kept
+    #[default]
+    PermanentRedirect,
+}
+
+impl FromStr for RedirectStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().ok().and_then(RedirectStatus::from_code).ok_or(())
+    }
+}
+
+impl RedirectStatus {
+    pub fn from_code(code: i64) -> Option<RedirectStatus> {
+        match code {
+            301 => Some(RedirectStatus::MovedPermanently),
+            302 => Some(RedirectStatus::Found),
+            307 => Some(RedirectStatus::TemporaryRedirect),
+            308 => Some(RedirectStatus::PermanentRedirect),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> i64 {
+        self.status_code().as_u16() as i64
+    }
+
+    fn status_code(self) -> StatusCode {
+        match self {
+            RedirectStatus::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            RedirectStatus::Found => StatusCode::FOUND,
+            RedirectStatus::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            RedirectStatus::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    /// the temporary counterpart, with the same rules about changing the method
+    pub fn temporary(self) -> RedirectStatus {
+        match self {
+            RedirectStatus::MovedPermanently => RedirectStatus::Found,
+            RedirectStatus::PermanentRedirect => RedirectStatus::TemporaryRedirect,
+            temporary => temporary,
+        }
+    }
+
+    /// redirect to `long_url` with this status
+    pub fn redirect(self, long_url: &str) -> Response {
+        (self.status_code(), [(header::LOCATION, long_url)]).into_response()
+    }
+}
+
+/// status `short_code` redirects with, its own if it has one or else the configured default
+pub fn for_link(ctx: &AppCtx, domain: &str, short_code: &str) -> RedirectStatus {
+    ctx.redirect_statuses
+        .read()
+        .unwrap()
+        .get(&domain::scoped(domain, short_code))
+        .copied()
+        .unwrap_or(ctx.config.redirect_status)
+}
+
+/// seed the per-link overrides, links without one aren't kept in memory
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT domain, short_code, redirect_status FROM url WHERE redirect_status IS NOT NULL"
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let mut redirect_statuses = ctx.redirect_statuses.write().unwrap();
+    for row in &rows {
+        if let Some(status) = row.redirect_status.and_then(RedirectStatus::from_code) {
+            redirect_statuses.insert(domain::scoped(&row.domain, &row.short_code), status);
+        }
+    }
+    println!("loaded {} per-link redirect statuses", rows.len());
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::IntoResponse,
+};
+
+use crate::{AppCtx, accept, blocklist, compress, domain, expiry, live, soft_delete, targets};
+
+/// codes accepted in one `/resolve` call
+const MAX_BATCH: usize = 1000;
+
+/// POST /resolve
+///
+/// body is a JSON array of short codes, responds with each one's long url in
+/// the same order, `null` for codes that aren't known. never counts as a redirect.
+/// an `Accept` preferring `application/msgpack` gets the same array as MessagePack
+pub async fn resolve(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Json(short_codes): Json<Vec<String>>,
+) -> impl IntoResponse {
+    println!("/resolve POST <-- {} codes", short_codes.len());
+
+    if short_codes.len() > MAX_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("At most {} codes per request", MAX_BATCH),
+        )
+            .into_response();
+    }
+
+    let domain = domain::from_host(&ctx.config, &headers);
+    let mut found = HashMap::new();
+    let mut misses = Vec::new();
+
+    {
+        let code_filter = ctx.code_filter.read().unwrap();
+        for short_code in &short_codes {
+            let stl_key = domain::scoped(&domain, short_code);
+            if let Some(long_url) = ctx.short_to_long_cache.get(&stl_key) {
+                live::inc(&ctx.counters.cache_hits);
+                found.insert(short_code.clone(), long_url);
+            } else {
+                live::inc(&ctx.counters.cache_misses);
+                // anything the filter rules out can't be in the db either
+                if code_filter.might_contain(&stl_key) {
+                    misses.push(short_code.as_str());
+                }
+            }
+        }
+    }
+    println!("\t{} from cache, {} to look up", found.len(), misses.len());
+
+    if !misses.is_empty() {
+        let rows = match lookup_entries(&domain, &misses, &ctx.pool).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                eprintln!("Failed to look up entries: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our end".to_owned(),
+                )
+                    .into_response();
+            }
+        };
+
+        for (short_code, long_url) in rows {
+            ctx.short_to_long_cache
+                .insert(domain::scoped(&domain, &short_code), long_url.clone());
+            found.insert(short_code, long_url);
+        }
+    }
+
+    let visitor = targets::Visitor::from_headers(&ctx.config, &headers);
+    let mut resolved = Vec::with_capacity(short_codes.len());
+    for short_code in &short_codes {
+        let long_url = match found.get(short_code) {
+            Some(_) if expiry::is_expired(&ctx, &domain, short_code) => None,
+            Some(_) if soft_delete::is_deleted(&ctx, &domain, short_code) => None,
+            Some(long_url) => {
+                let long_url =
+                    targets::resolve(&ctx, &domain, short_code, long_url.clone(), &visitor).await;
+                blocklist::check_redirect(&ctx, &long_url)
+                    .is_ok()
+                    .then_some(long_url)
+            }
+            None => None,
+        };
+        resolved.push(long_url);
+    }
+
+    let mut res = match accept::preferred(
+        &headers,
+        &[
+            "application/json",
+            "application/msgpack",
+            "application/x-msgpack",
+        ],
+    ) {
+        Some(media @ ("application/msgpack" | "application/x-msgpack")) => {
+            match rmp_serde::to_vec(&resolved) {
+                Ok(body) => ([(header::CONTENT_TYPE, media)], body).into_response(),
+                Err(e) => {
+                    eprintln!("Failed to encode msgpack: {}", e);
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Something went wrong on our end".to_owned(),
+                    )
+                        .into_response();
+                }
+            }
+        }
+        _ => Json(resolved).into_response(),
+    };
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    res
+}
+
+/// S -> D : lookup_many(short_codes) . D -> S : ok([(short_code, long_url)])
+async fn lookup_entries(
+    domain: &str,
+    short_codes: &[&str],
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let mut query = sqlx::QueryBuilder::new(
+        "SELECT short_code, long_url, long_url_compressed FROM url WHERE domain = ",
+    );
+    query.push_bind(domain);
+    query.push(" AND short_code IN (");
+    let mut codes = query.separated(", ");
+    for short_code in short_codes {
+        codes.push_bind(*short_code);
+    }
+    codes.push_unseparated(")");
+
+    let rows: Vec<(String, String, Option<Vec<u8>>)> =
+        query.build_query_as().fetch_all(pool).await?;
+    Ok(rows
+        .into_iter()
+        .map(|(short_code, long_url, compressed)| {
+            (
+                short_code,
+                compress::restored(long_url, compressed.as_deref()),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, header},
+    };
+
+    use super::*;
+    use crate::tests::{Reply, call, ctx, shorten};
+
+    async fn resolve(ctx: &AppCtx, accept: &str, short_codes: &[&str]) -> Reply {
+        let req = Request::post("/resolve")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCEPT, accept)
+            .body(Body::from(serde_json::to_string(short_codes).unwrap()))
+            .unwrap();
+        call(ctx, req).await
+    }
+
+    #[tokio::test]
+    async fn msgpack_decodes_to_the_same_array_as_json() {
+        let ctx = ctx().await;
+        let first = shorten(&ctx, "https://example.com/first").await;
+        let long = format!("https://example.com/{}", "a".repeat(300));
+        let second = shorten(&ctx, &long).await;
+        let short_codes = [first.as_str(), "missing", second.as_str()];
+
+        let json = resolve(&ctx, "application/json", &short_codes).await;
+        assert_eq!(json.status, StatusCode::OK);
+        let from_json: Vec<Option<String>> = serde_json::from_str(&json.body).unwrap();
+        assert_eq!(
+            from_json,
+            vec![
+                Some("https://example.com/first".to_owned()),
+                None,
+                Some(long)
+            ]
+        );
+
+        for media in ["application/msgpack", "application/x-msgpack"] {
+            let reply = resolve(&ctx, media, &short_codes).await;
+            assert_eq!(reply.status, StatusCode::OK);
+            assert_eq!(reply.header(header::CONTENT_TYPE), Some(media));
+            assert_eq!(reply.header(header::VARY), Some("accept"));
+            let decoded: Vec<Option<String>> = rmp_serde::from_slice(&reply.bytes).unwrap();
+            assert_eq!(decoded, from_json);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_is_the_default() {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com").await;
+        let reply = resolve(&ctx, "*/*", &[&short_code]).await;
+        assert_eq!(reply.header(header::CONTENT_TYPE), Some("application/json"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    http::{HeaderValue, header},
+    response::Response,
+};
+
+use crate::config::Config;
+
+/// How `Retry-After` is written, see `RETRY_AFTER_FORMAT`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryAfterFormat {
+    /// `Retry-After: 30`
+    #[default]
+    Seconds,
+    /// `Retry-After: Wed, 14 Oct 2026 12:00:30 GMT`, for clients that only read dates
+    HttpDate,
+}
+
+impl FromStr for RetryAfterFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seconds" => Ok(RetryAfterFormat::Seconds),
+            "http_date" => Ok(RetryAfterFormat::HttpDate),
+            _ => Err(()),
+        }
+    }
+}
+
+/// tell the client to come back in `wait`, rounded up to whole seconds so it
+/// never retries early. every 429 and 503 that knows how long to wait goes through here
+pub fn set(config: &Config, res: &mut Response, wait: Duration) {
+    let secs = wait.as_secs_f64().ceil() as u64;
+    let value = match config.retry_after_format {
+        RetryAfterFormat::Seconds => HeaderValue::from(secs),
+        RetryAfterFormat::HttpDate => {
+            let at = SystemTime::now() + Duration::from_secs(secs);
+            match HeaderValue::from_str(&httpdate::fmt_http_date(at)) {
+                Ok(value) => value,
+                Err(_) => HeaderValue::from(secs),
+            }
+        }
+    };
+    res.headers_mut().insert(header::RETRY_AFTER, value);
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::{
+    AppCtx, MAX_CODE_ATTEMPTS, Url,
+    admin::AdminAuth,
+    compress,
+    domain::{self, DEFAULT_DOMAIN},
+    fresh_code,
+    invalidate::{self, Changed},
+    is_unique_violation,
+    read_only::Writable,
+    targets, wordlist,
+};
+
+#[derive(Serialize)]
+struct Rotated {
+    short_code: String,
+    long_url: String,
+}
+
+/// POST /links/{short_code}/rotate
+///
+/// moves the link to a freshly generated code, same target. with `?grace_secs=`
+/// the old code answers 410 for that long, otherwise it's simply unknown.
+/// `?domain=` picks the domain when it isn't the default one
+pub async fn rotate(
+    _: AdminAuth,
+    _: Writable,
+    State(ctx): State<AppCtx>,
+    Path(short_code): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    println!("/links/rotate POST <-- {}", short_code);
+
+    let domain = params
+        .get("domain")
+        .map_or(DEFAULT_DOMAIN, |d| d.as_str())
+        .to_owned();
+    let grace_secs = match params.get("grace_secs").map(|g| g.parse::<i64>()) {
+        Some(Ok(grace_secs)) if grace_secs >= 0 => grace_secs,
+        Some(_) => {
+            return (StatusCode::BAD_REQUEST, "Invalid grace_secs".to_owned()).into_response();
+        }
+        None => 0,
+    };
+
+    let mut attempt = 0;
+    let mut clashes = 0;
+    let (url, new_code) = loop {
+        let Some(new_code) = wordlist::first_unlisted(&ctx, &mut attempt, |attempt| {
+            fresh_code(&short_code, attempt)
+        }) else {
+            eprintln!("Failed to generate a code: every attempt was on the wordlist");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        };
+        match move_entry(
+            &domain,
+            &short_code,
+            &new_code,
+            grace_secs,
+            targets::now(&ctx),
+            &ctx.pool,
+        )
+        .await
+        {
+            Ok(Some(url)) => break (url, new_code),
+            Ok(None) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    "Short code not recognised".to_owned(),
+                )
+                    .into_response();
+            }
+            Err(e) if is_unique_violation(&e) && clashes + 1 < MAX_CODE_ATTEMPTS => {
+                println!("\tfresh code taken, trying another");
+                clashes += 1;
+                attempt += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to rotate entry: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our end".to_owned(),
+                )
+                    .into_response();
+            }
+        }
+    };
+    println!("\trotated to: {}", new_code);
+
+    let old_key = domain::scoped(&domain, &short_code);
+    let new_key = domain::scoped(&domain, &new_code);
+    ctx.code_filter.write().unwrap().insert(&new_key);
+
+    ctx.short_to_long_cache.remove(&old_key);
+    ctx.links_cache.bust();
+    {
+        let lts_key = domain::scoped(&domain, &url.long_url);
+        // acquire lock
+        let mut long_to_short_cache = ctx.long_to_short_cache.lock(&lts_key);
+        // only when it's this code the url dedups to, not an alias beside it
+        if long_to_short_cache.get(&lts_key) == Some(&short_code) {
+            long_to_short_cache.insert(lts_key, new_code.clone());
+        }
+        // release lock
+    }
+
+    // the per-link state follows the link to its new code
+    {
+        let mut redirect_statuses = ctx.redirect_statuses.write().unwrap();
+        if let Some(status) = redirect_statuses.remove(&old_key) {
+            redirect_statuses.insert(new_key.clone(), status);
+        }
+    }
+    {
+        let mut append_params = ctx.append_params.write().unwrap();
+        if let Some(params) = append_params.remove(&old_key) {
+            append_params.insert(new_key.clone(), params);
+        }
+    }
+    {
+        let mut expiries = ctx.expiries.write().unwrap();
+        if let Some(expires_at) = expiries.remove(&old_key) {
+            expiries.insert(new_key.clone(), expires_at);
+        }
+    }
+    {
+        // clicks not yet flushed would otherwise be written to a code that's gone
+        let mut pending_clicks = ctx.pending_clicks.lock().unwrap();
+        if let Some(clicks) = pending_clicks.remove(&old_key) {
+            *pending_clicks.entry(new_key.clone()).or_default() += clicks;
+        }
+    }
+    {
+        let mut forward_suffix = ctx.forward_suffix.write().unwrap();
+        if forward_suffix.remove(&old_key) {
+            forward_suffix.insert(new_key.clone());
+        }
+    }
+    {
+        let mut deleted = ctx.deleted.write().unwrap();
+        if let Some(deleted_at) = deleted.remove(&old_key) {
+            deleted.insert(new_key.clone(), deleted_at);
+        }
+    }
+    {
+        let mut broken = ctx.broken.write().unwrap();
+        if broken.remove(&old_key) {
+            broken.insert(new_key.clone());
+        }
+    }
+    if targets::rotates(&ctx, &domain, &short_code) {
+        let mut targeted = ctx.targeted.write().unwrap();
+        targeted.remove(&old_key);
+        targeted.insert(new_key);
+    }
+
+    // both, so peers drop the old code and pick up the new one
+    invalidate::broadcast(
+        &ctx,
+        &domain,
+        vec![
+            Changed {
+                short_code,
+                long_url: url.long_url.clone(),
+            },
+            Changed {
+                short_code: new_code.clone(),
+                long_url: url.long_url.clone(),
+            },
+        ],
+    );
+
+    Json(Rotated {
+        short_code: new_code,
+        long_url: url.long_url,
+    })
+    .into_response()
+}
+
+/// S -> D : move(short_code, new_code, now) . D -> S : {
+///     not_found()
+///     ok(URL)
+/// }
+async fn move_entry(
+    domain: &str,
+    short_code: &str,
+    new_code: &str,
+    grace_secs: i64,
+    now: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<Option<Url>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let Some(url) = sqlx::query_as!(
+        Url,
+        "UPDATE url SET short_code = $3 WHERE domain = $1 AND short_code = $2 RETURNING *",
+        domain,
+        short_code,
+        new_code
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let url = compress::restore(url);
+
+    sqlx::query!(
+        "UPDATE link_target SET short_code = $3 WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code,
+        new_code
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE click_daily SET short_code = $3 WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code,
+        new_code
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM tombstone WHERE expires_at <= $1", now)
+        .execute(&mut *tx)
+        .await?;
+    if grace_secs > 0 {
+        let expires_at = now + grace_secs;
+        sqlx::query!(
+            "INSERT OR REPLACE INTO tombstone (domain, short_code, expires_at) VALUES ($1, $2, $3)",
+            domain,
+            short_code,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(Some(url))
+}
+
+/// whether `short_code` was rotated away from recently enough to answer 410
+pub async fn is_gone(ctx: &AppCtx, domain: &str, short_code: &str) -> Result<bool, sqlx::Error> {
+    // a retired code was stored once, so anything the filter rules out never was
+    if !ctx
+        .code_filter
+        .read()
+        .unwrap()
+        .might_contain(&domain::scoped(domain, short_code))
+    {
+        return Ok(false);
+    }
+
+    let now = targets::now(ctx);
+    let gone = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM tombstone WHERE domain = $1 AND short_code = $2 AND expires_at > $3",
+        domain,
+        short_code,
+        now
+    )
+    .fetch_one(&ctx.pool)
+    .await?;
+    Ok(gone > 0)
+}
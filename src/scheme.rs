@@ -0,0 +1,31 @@
+use crate::config::Config;
+
+/// what `ALLOWED_SCHEMES` is when unset
+pub const DEFAULT_SCHEMES: [&str; 2] = ["http", "https"];
+
+/// schemes that run in or read from the browser instead of going somewhere,
+/// `*` never covers these, they have to be named
+const DANGEROUS: [&str; 4] = ["javascript", "data", "vbscript", "file"];
+
+/// the lowercased scheme of `url`, RFC 3986 3.1, `None` when it doesn't start with one
+pub fn of(url: &str) -> Option<String> {
+    let (scheme, _) = url.split_once(':')?;
+    let mut chars = scheme.chars();
+    if !chars.next()?.is_ascii_alphabetic()
+        || !chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    {
+        return None;
+    }
+    Some(scheme.to_ascii_lowercase())
+}
+
+/// whether `ALLOWED_SCHEMES` lets a link point at `url`
+pub fn is_allowed(config: &Config, url: &str) -> bool {
+    let Some(scheme) = of(url) else {
+        return false;
+    };
+    config
+        .allowed_schemes
+        .iter()
+        .any(|allowed| *allowed == scheme || (allowed == "*" && !DANGEROUS.contains(&&*scheme)))
+}
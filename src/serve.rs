@@ -0,0 +1,182 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr, pin::pin, time::Duration};
+
+use axum::{Router, extract::connect_info::IntoMakeServiceWithConnectInfo};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::{net::TcpListener, sync::watch};
+use tower::Service;
+
+use crate::config::Config;
+
+/// `axum::serve` with the connection settings exposed, it takes hyper's defaults,
+/// which keep an idle keep-alive connection open forever
+///
+/// each connection speaks http/1.1 or cleartext http/2, whichever the client opens
+/// with. http/2 needs prior knowledge, there's no `Upgrade: h2c`
+pub async fn serve(
+    config: &Config,
+    listener: TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut make_service: IntoMakeServiceWithConnectInfo<Router, SocketAddr> =
+        app.into_make_service_with_connect_info::<SocketAddr>();
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.http_keep_alive)
+        // also runs while a kept-alive connection waits for its next request,
+        // so it doubles as the idle timeout
+        .header_read_timeout(
+            (config.http_idle_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.http_idle_timeout_secs)),
+        );
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(
+            config
+                .http2_keep_alive_interval_secs
+                .map(Duration::from_secs),
+        );
+
+    // connections hold a receiver each, so the sender closing means they're all done
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let (done_tx, done_rx) = watch::channel(());
+    let mut shutdown = pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // out of file descriptors and the like, back off instead of spinning
+                    eprintln!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = make_service
+            .call(peer)
+            .await
+            .unwrap_or_else(|e: Infallible| match e {});
+        let conn = builder
+            // `/ws/stats` upgrades to a websocket
+            .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+            .into_owned();
+        let mut shutdown_rx = shutdown_rx.clone();
+        let done_rx = done_rx.clone();
+
+        tokio::spawn(async move {
+            let mut conn = pin!(conn);
+            loop {
+                tokio::select! {
+                    // clients hanging up mid-request aren't worth a log line
+                    _ = conn.as_mut() => break,
+                    _ = shutdown_rx.changed() => conn.as_mut().graceful_shutdown(),
+                }
+            }
+            drop(done_rx);
+        });
+    }
+
+    // stop accepting, let requests in flight finish, then close every connection
+    drop(listener);
+    drop(shutdown_rx);
+    let _ = shutdown_tx.send(());
+    drop(done_rx);
+    done_tx.closed().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{self, Body},
+        http::{Request, StatusCode, header},
+    };
+    use hyper::client::conn::{http1, http2};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        build_app,
+        tests::{self, ctx, shorten},
+    };
+
+    const REQUESTS: usize = 100;
+
+    /// `serve` on a loopback port until the test ends, responds with its address
+    /// and a code to redirect with
+    async fn serving() -> (SocketAddr, String) {
+        let ctx = ctx().await;
+        let short_code = shorten(&ctx, "https://example.com").await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = tests::config();
+        tokio::spawn(async move {
+            serve(&config, listener, build_app(ctx), std::future::pending()).await
+        });
+        (addr, short_code)
+    }
+
+    fn redirect(short_code: &str) -> Request<Body> {
+        Request::get(format!("/redirect/{}", short_code))
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn http1_serves_many_requests_over_one_connection() {
+        let (addr, short_code) = serving().await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+
+        for _ in 0..REQUESTS {
+            sender.ready().await.unwrap();
+            let res = sender.send_request(redirect(&short_code)).await.unwrap();
+            assert!(res.status().is_redirection(), "got {}", res.status());
+            // the body has to be read before the connection takes the next request
+            body::to_bytes(Body::new(res.into_body()), usize::MAX)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn http2_multiplexes_many_requests_over_one_connection() {
+        let (addr, short_code) = serving().await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let in_flight = (0..REQUESTS)
+            .map(|_| {
+                let mut sender = sender.clone();
+                let req = redirect(&short_code);
+                tokio::spawn(async move { sender.send_request(req).await.unwrap().status() })
+            })
+            .collect::<Vec<_>>();
+        for request in in_flight {
+            let status = request.await.unwrap();
+            assert!(status.is_redirection(), "got {}", status);
+        }
+
+        let mut sender = sender;
+        let req = Request::get("/livez").body(Body::empty()).unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.version(), axum::http::Version::HTTP_2);
+    }
+}
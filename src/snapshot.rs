@@ -0,0 +1,203 @@
+use std::{collections::HashMap, error::Error, fs};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AppCtx, domain, lookup_entry};
+
+/// On-disk copy of both caches, written on shutdown and read back on startup
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    short_to_long: HashMap<String, String>,
+    long_to_short: HashMap<String, String>,
+}
+
+/// dump both caches to `path`, all at once: a crash partway leaves the old
+/// snapshot in place rather than half of a new one
+pub fn save(ctx: &AppCtx, path: &str) -> Result<(), Box<dyn Error>> {
+    let snapshot = Snapshot {
+        short_to_long: ctx.short_to_long_cache.to_map(),
+        long_to_short: ctx.long_to_short_cache.to_map(),
+    };
+
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_vec(&snapshot)?)?;
+    fs::rename(&tmp_path, path)?;
+    println!(
+        "saved cache snapshot ({} entries) to {}",
+        snapshot.short_to_long.len(),
+        path
+    );
+    Ok(())
+}
+
+/// warm both caches from `path`, keeping only entries that still match the db
+pub async fn load(ctx: &AppCtx, path: &str) -> Result<(), Box<dyn Error>> {
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("no cache snapshot at {}, starting cold", path);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // a snapshot is only ever an optimisation, one that can't be read isn't worth failing startup
+    let snapshot: Snapshot = match serde_json::from_slice(&raw) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!(
+                "Failed to read cache snapshot at {}, starting cold: {}",
+                path, e
+            );
+            return Ok(());
+        }
+    };
+
+    // the db is the source of truth, the snapshot may be older than it
+    let mut short_to_long = HashMap::new();
+    for (stl_key, long_url) in snapshot.short_to_long {
+        let Some((domain, short_code)) = domain::unscope(&stl_key) else {
+            continue;
+        };
+        if let Some(url) = lookup_entry(domain, short_code, &ctx.pool).await?
+            && url.long_url == long_url
+        {
+            short_to_long.insert(stl_key, long_url);
+        }
+    }
+
+    let long_to_short = snapshot
+        .long_to_short
+        .into_iter()
+        .filter(|(lts_key, short_code)| {
+            let Some((domain, long_url)) = domain::unscope(lts_key) else {
+                return false;
+            };
+            short_to_long
+                .get(&domain::scoped(domain, short_code))
+                .map(|l| l.as_str())
+                == Some(long_url)
+        })
+        .collect::<HashMap<_, _>>();
+
+    println!(
+        "loaded cache snapshot from {} ({} stl, {} lts entries)",
+        path,
+        short_to_long.len(),
+        long_to_short.len()
+    );
+
+    ctx.short_to_long_cache.fill(short_to_long);
+    ctx.long_to_short_cache.fill(long_to_short);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        domain::DEFAULT_DOMAIN,
+        tests::{SharedDb, TempFile, admin, ctx_with, get, shorten},
+    };
+
+    fn with_snapshot(db: &SharedDb, snapshot: &TempFile) -> Config {
+        Config {
+            cache_snapshot_path: Some(snapshot.path()),
+            ..db.config()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_restart_comes_back_warm() {
+        let (db, file) = (SharedDb::new(), TempFile::new("json"));
+        let before = ctx_with(with_snapshot(&db, &file)).await;
+        let short_code = shorten(&before, "https://example.com/warm").await;
+        save(&before, &file.path()).unwrap();
+        before.pool.close().await;
+
+        let after = ctx_with(with_snapshot(&db, &file)).await;
+        let key = domain::scoped(DEFAULT_DOMAIN, &short_code);
+        assert_eq!(
+            after.short_to_long_cache.get(&key).as_deref(),
+            Some("https://example.com/warm")
+        );
+        let reply = get(&after, &format!("/redirect/{}", short_code)).await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+    }
+
+    #[tokio::test]
+    async fn entries_gone_from_the_db_are_dropped_on_load() {
+        let (db, file) = (SharedDb::new(), TempFile::new("json"));
+        let before = ctx_with(with_snapshot(&db, &file)).await;
+        let kept = shorten(&before, "https://example.com/kept").await;
+        let deleted = shorten(&before, "https://example.com/deleted").await;
+        save(&before, &file.path()).unwrap();
+
+        // deleted after the snapshot was taken, so it's still in there
+        let reply = admin(
+            &before,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [deleted] })),
+        )
+        .await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+        before.pool.close().await;
+
+        let after = ctx_with(with_snapshot(&db, &file)).await;
+        assert!(
+            after
+                .short_to_long_cache
+                .get(&domain::scoped(DEFAULT_DOMAIN, &kept))
+                .is_some()
+        );
+        assert_eq!(
+            after
+                .short_to_long_cache
+                .get(&domain::scoped(DEFAULT_DOMAIN, &deleted)),
+            None
+        );
+        assert!(
+            !after
+                .long_to_short_cache
+                .to_map()
+                .values()
+                .any(|short_code| *short_code == deleted)
+        );
+        assert_eq!(
+            get(&after, &format!("/redirect/{}", deleted)).await.status,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_snapshot_starts_cold() {
+        let (db, file) = (SharedDb::new(), TempFile::new("json"));
+        // what a write cut short would have left
+        fs::write(
+            file.path(),
+            br#"{"short_to_long": {"default:abc": "https://exa"#,
+        )
+        .unwrap();
+
+        let ctx = ctx_with(with_snapshot(&db, &file)).await;
+        assert_eq!(ctx.short_to_long_cache.len(), 0);
+        assert_eq!(ctx.long_to_short_cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn save_leaves_no_temp_file_behind() {
+        let (db, file) = (SharedDb::new(), TempFile::new("json"));
+        let ctx = ctx_with(with_snapshot(&db, &file)).await;
+        shorten(&ctx, "https://example.com/saved").await;
+
+        save(&ctx, &file.path()).unwrap();
+        assert!(fs::metadata(file.path()).is_ok());
+        assert!(fs::metadata(format!("{}.tmp", file.path())).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    AppCtx, Url,
+    admin::AdminAuth,
+    clicks, compress,
+    domain::{self, DEFAULT_DOMAIN},
+    invalidate::{self, Changed},
+    links::{self, Matching},
+    lookup_entry, read_only,
+    read_only::Writable,
+    targets,
+    tasks::{self, Task},
+};
+
+/// how often soft deleted links past `SOFT_DELETE_GRACE_SECS` are looked for
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// whether `short_code` is soft deleted and waiting to be restored or purged
+pub fn is_deleted(ctx: &AppCtx, domain: &str, short_code: &str) -> bool {
+    ctx.deleted
+        .read()
+        .unwrap()
+        .contains_key(&domain::scoped(domain, short_code))
+}
+
+/// keep `ctx.deleted` in line with a link's stored `deleted_at`
+pub fn set(ctx: &AppCtx, domain: &str, short_code: &str, deleted_at: Option<i64>) {
+    let key = domain::scoped(domain, short_code);
+    let mut deleted = ctx.deleted.write().unwrap();
+    match deleted_at {
+        Some(deleted_at) => deleted.insert(key, deleted_at),
+        None => deleted.remove(&key),
+    };
+}
+
+/// seed the soft deleted links, live links aren't kept in memory
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT domain, short_code, deleted_at AS "deleted_at!" FROM url WHERE deleted_at IS NOT NULL"#
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let mut deleted = ctx.deleted.write().unwrap();
+    for row in &rows {
+        deleted.insert(domain::scoped(&row.domain, &row.short_code), row.deleted_at);
+    }
+    println!("loaded {} soft deleted links", rows.len());
+    Ok(())
+}
+
+/// mark every matching link deleted and take it out of the caches, its row,
+/// targets and clicks stay until the purge
+pub async fn delete(
+    ctx: &AppCtx,
+    domain: &str,
+    matching: &Matching<'_>,
+) -> Result<Vec<Url>, sqlx::Error> {
+    let now = targets::now(ctx);
+    let marked = mark_entries(domain, matching, now, &ctx.pool).await?;
+
+    for url in &marked {
+        set(ctx, &url.domain, &url.short_code, Some(now));
+        ctx.short_to_long_cache
+            .remove(&domain::scoped(&url.domain, &url.short_code));
+
+        // the row is no longer reusable, so only the cache says which code this was
+        let lts_key = domain::scoped(&url.domain, &url.long_url);
+        // acquire lock
+        let mut long_to_short_cache = ctx.long_to_short_cache.lock(&lts_key);
+        if long_to_short_cache.get(&lts_key) == Some(&url.short_code) {
+            long_to_short_cache.remove(&lts_key);
+        }
+        // release lock
+    }
+    if !marked.is_empty() {
+        ctx.links_cache.bust();
+    }
+    println!("\tsoft deleted {} entries", marked.len());
+    Ok(marked)
+}
+
+/// S -> D : mark_deleted(short_codes | tag, now) . D -> S : ok(marked: [URL])
+async fn mark_entries(
+    domain: &str,
+    matching: &Matching<'_>,
+    now: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<Url>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut marked = Vec::new();
+
+    for short_code in &matching.short_codes(domain, &mut tx).await? {
+        // a deleted link stops being what its url dedups to, so a resubmission
+        // gets a new code instead of one that answers 410
+        let row = sqlx::query_as!(
+            Url,
+            "UPDATE url SET deleted_at = $3, reusable = 0
+            WHERE domain = $1 AND short_code = $2 AND deleted_at IS NULL
+            RETURNING *",
+            domain,
+            short_code,
+            now
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        marked.extend(row.map(compress::restore));
+    }
+
+    tx.commit().await?;
+    Ok(marked)
+}
+
+#[derive(Serialize)]
+struct Restored {
+    short_code: String,
+    long_url: String,
+}
+
+/// POST /links/{short_code}/restore
+///
+/// undoes a soft delete, the link redirects again with everything it had.
+/// a link already purged is gone for good and answers 404. `?domain=` picks the
+/// domain when it isn't the default one
+pub async fn restore(
+    _: AdminAuth,
+    _: Writable,
+    State(ctx): State<AppCtx>,
+    Path(short_code): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    println!("/links/restore POST <-- {}", short_code);
+
+    let domain = params.get("domain").map_or(DEFAULT_DOMAIN, |d| d.as_str());
+    let restored = async {
+        match restore_entry(domain, &short_code, &ctx.pool).await? {
+            Some(url) => Ok::<_, sqlx::Error>(Ok(url)),
+            // only a soft deleted link can be restored, tell a live one from a purged one
+            None => Ok(Err(lookup_entry(domain, &short_code, &ctx.pool)
+                .await?
+                .is_some())),
+        }
+    };
+    let url = match restored.await {
+        Ok(Ok(url)) => url,
+        Ok(Err(true)) => {
+            println!("\tlink isn't deleted");
+            return (StatusCode::CONFLICT, "Link is not deleted".to_owned()).into_response();
+        }
+        Ok(Err(false)) => {
+            return (
+                StatusCode::NOT_FOUND,
+                "Short code not recognised".to_owned(),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            eprintln!("Failed to restore entry: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+    println!("\trestored");
+
+    set(&ctx, domain, &short_code, None);
+    ctx.links_cache.bust();
+    // a link that was worth restoring is about to be visited, so it goes straight back in
+    if url.reusable {
+        let lts_key = domain::scoped(domain, &url.long_url);
+        // acquire lock
+        let mut long_to_short_cache = ctx.long_to_short_cache.lock(&lts_key);
+        long_to_short_cache.insert(lts_key, short_code.clone());
+        // release lock
+    }
+    {
+        let stl_key = domain::scoped(domain, &short_code);
+        // acquire lock
+        let mut short_to_long_cache = ctx.short_to_long_cache.lock(&stl_key);
+        short_to_long_cache.insert(stl_key, url.long_url.clone());
+        // release lock
+    }
+
+    invalidate::broadcast(
+        &ctx,
+        domain,
+        vec![Changed {
+            short_code: short_code.clone(),
+            long_url: url.long_url.clone(),
+        }],
+    );
+
+    Json(Restored {
+        short_code,
+        long_url: url.long_url,
+    })
+    .into_response()
+}
+
+/// S -> D : restore(short_code) . D -> S : {
+///     not_found()
+///     ok(URL)
+/// }
+async fn restore_entry(
+    domain: &str,
+    short_code: &str,
+    pool: &sqlx::SqlitePool,
+) -> Result<Option<Url>, sqlx::Error> {
+    // it's what the url dedups to again, unless the url was shortened anew meanwhile
+    let url = sqlx::query_as!(
+        Url,
+        "UPDATE url SET deleted_at = NULL,
+            reusable = NOT EXISTS (
+                SELECT 1 FROM url AS other
+                WHERE other.domain = url.domain AND other.long_url = url.long_url AND other.reusable
+            )
+        WHERE domain = $1 AND short_code = $2 AND deleted_at IS NOT NULL
+        RETURNING *",
+        domain,
+        short_code
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(url.map(compress::restore))
+}
+
+/// purge soft deleted links every minute, once they're past `SOFT_DELETE_GRACE_SECS`.
+/// runs without `SOFT_DELETE` too, links deleted before it was switched off still go
+pub fn spawn_purger(ctx: AppCtx) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if read_only::is_on(&ctx) {
+                continue;
+            }
+            let started = Instant::now();
+            let res = purge(&ctx).await;
+            tasks::record(&ctx, Task::Purge, started, &res);
+            if let Err(e) = res {
+                eprintln!("Failed to purge deleted links: {}", e);
+            }
+        }
+    });
+}
+
+/// remove every soft deleted link past its grace period for good
+pub async fn purge(ctx: &AppCtx) -> Result<usize, sqlx::Error> {
+    let cutoff = targets::now(ctx) - ctx.config.soft_delete_grace_secs;
+    // the map is enough to tell nothing is due, without scanning the table
+    if !ctx
+        .deleted
+        .read()
+        .unwrap()
+        .values()
+        .any(|deleted_at| *deleted_at <= cutoff)
+    {
+        return Ok(0);
+    }
+
+    let removed = purge_entries(cutoff, &ctx.pool).await?;
+    println!("purged {} deleted links", removed.len());
+    links::evict(ctx, &removed);
+
+    let mut by_domain: HashMap<&str, Vec<Changed>> = HashMap::new();
+    for url in &removed {
+        by_domain.entry(&url.domain).or_default().push(Changed {
+            short_code: url.short_code.clone(),
+            long_url: url.long_url.clone(),
+        });
+    }
+    for (domain, changed) in by_domain {
+        invalidate::broadcast(ctx, domain, changed);
+    }
+    Ok(removed.len())
+}
+
+/// S -> D : purge(cutoff) . D -> S : ok(removed: [URL])
+async fn purge_entries(cutoff: i64, pool: &sqlx::SqlitePool) -> Result<Vec<Url>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // a link restored since the map was read no longer matches, so it stays
+    let removed = sqlx::query_as!(
+        Url,
+        "DELETE FROM url WHERE deleted_at <= $1 RETURNING *",
+        cutoff
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for url in &removed {
+        targets::delete_targets(&url.domain, &url.short_code, &mut tx).await?;
+        clicks::delete_daily(&url.domain, &url.short_code, &mut tx).await?;
+    }
+
+    tx.commit().await?;
+    Ok(removed.into_iter().map(compress::restore).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode, header},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        tests::{self, Reply, admin, ctx_with, get, shorten, stop_clock},
+    };
+
+    const GRACE_SECS: i64 = 60;
+
+    async fn soft_deleting() -> AppCtx {
+        ctx_with(Config {
+            soft_delete: true,
+            soft_delete_grace_secs: GRACE_SECS,
+            ..tests::config()
+        })
+        .await
+    }
+
+    async fn delete_link(ctx: &AppCtx, short_code: &str) {
+        let reply = admin(
+            ctx,
+            Method::POST,
+            "/links/delete",
+            Some(json!({ "short_codes": [short_code] })),
+        )
+        .await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+    }
+
+    async fn restore_link(ctx: &AppCtx, short_code: &str) -> Reply {
+        admin(
+            ctx,
+            Method::POST,
+            &format!("/links/{}/restore", short_code),
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn restore_after_delete_redirects_again() {
+        let ctx = soft_deleting().await;
+        let short_code = shorten(&ctx, "https://example.com/restored").await;
+        delete_link(&ctx, &short_code).await;
+
+        let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert_eq!(reply.status, StatusCode::GONE);
+
+        let reply = restore_link(&ctx, &short_code).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.json()["long_url"], "https://example.com/restored");
+
+        let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+        assert_eq!(
+            reply.header(header::LOCATION),
+            Some("https://example.com/restored")
+        );
+    }
+
+    #[tokio::test]
+    async fn restoring_a_live_link_conflicts() {
+        let ctx = soft_deleting().await;
+        let short_code = shorten(&ctx, "https://example.com/live").await;
+
+        let reply = restore_link(&ctx, &short_code).await;
+        assert_eq!(reply.status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn restore_after_purge_is_not_found() {
+        let mut ctx = soft_deleting().await;
+        let clock = stop_clock(&mut ctx, 1_700_000_000);
+        let short_code = shorten(&ctx, "https://example.com/purged").await;
+        delete_link(&ctx, &short_code).await;
+
+        // still inside the grace period, nothing goes
+        clock.advance(GRACE_SECS - 1);
+        assert_eq!(purge(&ctx).await.unwrap(), 0);
+
+        clock.advance(1);
+        assert_eq!(purge(&ctx).await.unwrap(), 1);
+
+        let reply = restore_link(&ctx, &short_code).await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn a_tag_marks_only_its_live_links_deleted() {
+        let ctx = soft_deleting().await;
+        let req = Request::post("/shorten?q=https://example.com/tagged&tag=campaign-q3")
+            .body(Body::empty())
+            .unwrap();
+        let short_code = tests::call(&ctx, req).await.body;
+        let untagged = shorten(&ctx, "https://example.com/untagged").await;
+
+        let delete_tag = json!({ "tag": "campaign-q3" });
+        let reply = admin(
+            &ctx,
+            Method::POST,
+            "/links/delete",
+            Some(delete_tag.clone()),
+        )
+        .await;
+        assert_eq!(reply.json()["deleted"], 1);
+        // already marked, a second delete has nothing left to match
+        let reply = admin(&ctx, Method::POST, "/links/delete", Some(delete_tag)).await;
+        assert_eq!(reply.json()["deleted"], 0);
+
+        let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert_eq!(reply.status, StatusCode::GONE);
+        let reply = get(&ctx, &format!("/redirect/{}", untagged)).await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+    }
+}
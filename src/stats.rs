@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::{AppCtx, domain, is_valid_code, not_found, targets};
+
+/// longest series `/stats/{short_code}/daily` serves, a year and a leap day
+const MAX_DAYS: i64 = 366;
+
+#[derive(Serialize)]
+struct LinkStats {
+    short_code: String,
+    description: Option<String>,
+    /// the channel the link came in through
+    source: String,
+    /// flushed clicks, see `clicks`
+    clicks: i64,
+    /// unix seconds
+    updated_at: i64,
+}
+
+/// GET /stats/{short_code}
+///
+/// a link's click count, for dashboards polling it. `If-None-Match` and
+/// `If-Modified-Since` get a 304 while nothing has changed since
+pub async fn stats(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Path(short_code): Path<String>,
+) -> Response {
+    if !is_valid_code(&short_code) {
+        println!("/stats GET <-- invalid code");
+        return not_found::unknown_code(&headers);
+    }
+    println!("/stats GET <-- {}", short_code);
+
+    let domain = domain::from_host(&ctx.config, &headers);
+    let stats = match lookup_stats(&domain, &short_code, &ctx.pool).await {
+        Ok(Some(stats)) => stats,
+        Ok(None) => return not_found::unknown_code(&headers),
+        Err(e) => {
+            eprintln!("Failed to look up stats: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+
+    let etag = format!("\"{}-{}\"", stats.updated_at, stats.clicks);
+    let last_modified =
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(stats.updated_at.max(0) as u64));
+
+    if not_modified(&headers, &etag, stats.updated_at) {
+        println!("\tnot modified");
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)],
+        )
+            .into_response();
+    }
+
+    (
+        [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)],
+        Json(stats),
+    )
+        .into_response()
+}
+
+/// whether the client's cached copy is still current. `If-None-Match` wins when
+/// both are sent, it can't be fooled by two changes in the same second
+fn not_modified(headers: &HeaderMap, etag: &str, updated_at: i64) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|v| {
+            v.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        });
+    }
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|since| updated_at <= since.as_secs() as i64)
+}
+
+/// S -> D : lookup_stats(short_code) . D -> S : {
+///     not_found()
+///     ok(LinkStats)
+/// }
+async fn lookup_stats(
+    domain: &str,
+    short_code: &str,
+    pool: &sqlx::SqlitePool,
+) -> Result<Option<LinkStats>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkStats,
+        r#"SELECT short_code, description, source, clicks, COALESCE(updated_at, 0) AS "updated_at!: i64"
+           FROM url WHERE domain = $1 AND short_code = $2"#,
+        domain,
+        short_code
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Day {
+    /// utc, `YYYY-MM-DD`
+    date: String,
+    clicks: i64,
+}
+
+/// GET /stats/{short_code}/daily?days=30
+///
+/// flushed clicks per utc day for the last `days` days including today, oldest
+/// first. days without clicks are 0, so the series can be charted as is
+pub async fn daily(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Path(short_code): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if !is_valid_code(&short_code) {
+        println!("/stats/daily GET <-- invalid code");
+        return not_found::unknown_code(&headers);
+    }
+    println!("/stats/daily GET <-- {}", short_code);
+
+    let days = match params.get("days").map(|d| d.parse::<i64>()) {
+        Some(Ok(days)) if (1..=MAX_DAYS).contains(&days) => days,
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("days must be between 1 and {}", MAX_DAYS),
+            )
+                .into_response();
+        }
+        None => 30,
+    };
+
+    let domain = domain::from_host(&ctx.config, &headers);
+    let series = async {
+        if lookup_stats(&domain, &short_code, &ctx.pool)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        lookup_daily(&domain, &short_code, days, targets::now(&ctx), &ctx.pool)
+            .await
+            .map(Some)
+    };
+    match series.await {
+        Ok(Some(series)) => Json(series).into_response(),
+        Ok(None) => not_found::unknown_code(&headers),
+        Err(e) => {
+            eprintln!("Failed to look up daily clicks: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// S -> D : lookup_daily(short_code, days, now) . D -> S : ok([Day])
+async fn lookup_daily(
+    domain: &str,
+    short_code: &str,
+    days: i64,
+    now: i64,
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<Day>, sqlx::Error> {
+    // every day in the window, whether or not it has a row, so gaps come back as 0
+    sqlx::query_as(
+        "WITH RECURSIVE ago(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM ago WHERE n + 1 < $3)
+        SELECT date($4, 'unixepoch', '-' || ago.n || ' days') AS date,
+               COALESCE(click_daily.count, 0) AS clicks
+        FROM ago
+        LEFT JOIN click_daily ON click_daily.domain = $1
+            AND click_daily.short_code = $2
+            AND click_daily.date = date($4, 'unixepoch', '-' || ago.n || ' days')
+        ORDER BY ago.n DESC",
+    )
+    .bind(domain)
+    .bind(short_code)
+    .bind(days)
+    .bind(now)
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        clicks,
+        tests::{ctx, get, shorten, stop_clock},
+    };
+
+    #[tokio::test]
+    async fn daily_clicks_land_on_the_day_they_were_flushed() {
+        let mut ctx = ctx().await;
+        // 2023-11-14 22:13:20 utc
+        let clock = stop_clock(&mut ctx, 1_700_000_000);
+        let short_code = shorten(&ctx, "https://example.com/daily").await;
+
+        get(&ctx, &format!("/redirect/{}", short_code)).await;
+        clicks::flush(&ctx).await.unwrap();
+
+        clock.advance(24 * 60 * 60);
+        for _ in 0..2 {
+            get(&ctx, &format!("/redirect/{}", short_code)).await;
+        }
+        clicks::flush(&ctx).await.unwrap();
+
+        let reply = get(&ctx, &format!("/stats/{}/daily?days=3", short_code)).await;
+        assert_eq!(
+            reply.json(),
+            json!([
+                { "date": "2023-11-13", "clicks": 0 },
+                { "date": "2023-11-14", "clicks": 1 },
+                { "date": "2023-11-15", "clicks": 2 },
+            ])
+        );
+    }
+}
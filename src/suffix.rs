@@ -0,0 +1,76 @@
+use url::Url;
+
+use crate::{AppCtx, domain};
+
+/// whether redirecting `suffix` can't climb out of the target's path, `..` and
+/// `.` are resolved by the url parser, percent-encoded or not
+pub fn is_safe(suffix: &str) -> bool {
+    suffix.split('/').all(|segment| {
+        let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+        segment != "." && segment != ".."
+    })
+}
+
+/// `long_url` with `suffix` appended to its path and `query` to its own query,
+/// a fragment the target has stays at the end
+pub fn join(long_url: &str, suffix: &str, query: Option<&str>) -> String {
+    let Ok(mut url) = Url::parse(long_url) else {
+        return long_url.to_owned();
+    };
+    if url.cannot_be_a_base() {
+        return long_url.to_owned();
+    }
+
+    let path = format!("{}/{}", url.path().trim_end_matches('/'), suffix);
+    url.set_path(&path);
+    if let Some(query) = query.filter(|q| !q.is_empty()) {
+        let query = match url.query() {
+            Some(own) if !own.is_empty() => format!("{}&{}", own, query),
+            _ => query.to_owned(),
+        };
+        url.set_query(Some(&query));
+    }
+    url.into()
+}
+
+/// the request's query to pass on, less the `raw` that `redirect` answers itself
+pub fn forwarded_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| pair.split('=').next() != Some("raw"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// whether `short_code` was shortened with `forward_suffix`
+pub fn forwards(ctx: &AppCtx, domain: &str, short_code: &str) -> bool {
+    ctx.forward_suffix
+        .read()
+        .unwrap()
+        .contains(&domain::scoped(domain, short_code))
+}
+
+/// keep `ctx.forward_suffix` in line with a link's stored `forward_suffix`
+pub fn set(ctx: &AppCtx, domain: &str, short_code: &str, forward_suffix: bool) {
+    let key = domain::scoped(domain, short_code);
+    let mut forwarding = ctx.forward_suffix.write().unwrap();
+    if forward_suffix {
+        forwarding.insert(key);
+    } else {
+        forwarding.remove(&key);
+    }
+}
+
+/// seed the set of links forwarding suffixes
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let codes = sqlx::query!("SELECT domain, short_code FROM url WHERE forward_suffix")
+        .fetch_all(&ctx.pool)
+        .await?;
+
+    let mut forwarding = ctx.forward_suffix.write().unwrap();
+    for code in &codes {
+        forwarding.insert(domain::scoped(&code.domain, &code.short_code));
+    }
+    println!("loaded {} links forwarding suffixes", codes.len());
+    Ok(())
+}
@@ -0,0 +1,475 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    AppCtx, accept,
+    admin::AdminAuth,
+    blocklist,
+    config::Config,
+    domain::{self, DEFAULT_DOMAIN},
+    health,
+    invalidate::{self, Changed},
+    lookup_entry, normalize,
+    read_only::Writable,
+    scheme,
+};
+
+/// One of a link's rotating destinations, live from `starts_at` until `ends_at`
+/// and only for visitors from `country` or speaking `language` when those are set
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Target {
+    pub long_url: String,
+    /// unix seconds, inclusive, unbounded when missing
+    pub starts_at: Option<i64>,
+    /// unix seconds, exclusive, unbounded when missing
+    pub ends_at: Option<i64>,
+    /// ISO 3166 alpha-2, uppercase, any country when missing
+    pub country: Option<String>,
+    /// BCP 47 tag, lowercase, any language when missing
+    pub language: Option<String>,
+}
+
+impl Target {
+    fn is_live(&self, now: i64) -> bool {
+        self.starts_at.is_none_or(|start| start <= now) && self.ends_at.is_none_or(|end| now < end)
+    }
+
+    fn is_for(&self, visitor: &Visitor) -> bool {
+        self.country
+            .as_ref()
+            .is_none_or(|country| visitor.country.as_ref() == Some(country))
+    }
+}
+
+/// What's known about whoever is following a link, for picking between targets
+#[derive(Debug, Default)]
+pub struct Visitor {
+    /// from `COUNTRY_HEADER`, uppercase
+    pub country: Option<String>,
+    /// `Accept-Language` ranges, most preferred first
+    pub languages: Vec<String>,
+}
+
+impl Visitor {
+    pub fn from_headers(config: &Config, headers: &HeaderMap) -> Visitor {
+        Visitor {
+            country: config
+                .country_header
+                .as_ref()
+                .and_then(|name| headers.get(name))
+                .and_then(|v| v.to_str().ok())
+                .filter(|country| is_country(country))
+                .map(|country| country.to_ascii_uppercase()),
+            languages: accept::languages(headers),
+        }
+    }
+}
+
+/// whether `country` looks like an ISO 3166 alpha-2 code
+fn is_country(country: &str) -> bool {
+    country.len() == 2 && country.bytes().all(|b| b.is_ascii_alphabetic())
+}
+
+/// whether `tag` looks like a BCP 47 language tag, `en`, `pt-BR`, `zh-Hant-TW`
+fn is_language(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    subtags.next().is_some_and(|primary| {
+        (1..=8).contains(&primary.len()) && primary.bytes().all(|b| b.is_ascii_alphabetic())
+    }) && subtags.all(|subtag| {
+        (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+    })
+}
+
+/// whether a visitor asking for `range` would take `tag`, either way round,
+/// `en` takes `en-GB` and `en-GB` takes `en`, both lowercase
+fn speaks(range: &str, tag: &str) -> bool {
+    let within = |narrow: &str, broad: &str| {
+        narrow
+            .strip_prefix(broad)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+    };
+    within(tag, range) || within(range, tag)
+}
+
+/// the target for `visitor` at `now`, out of those live and for their country:
+/// the first in the language they prefer most, otherwise the first without a language
+pub fn select<'a>(targets: &'a [Target], now: i64, visitor: &Visitor) -> Option<&'a Target> {
+    let candidates = targets
+        .iter()
+        .filter(|target| target.is_live(now) && target.is_for(visitor))
+        .collect::<Vec<_>>();
+
+    visitor
+        .languages
+        .iter()
+        .find_map(|range| {
+            candidates.iter().find(|target| {
+                target
+                    .language
+                    .as_deref()
+                    .is_some_and(|tag| speaks(range, tag))
+            })
+        })
+        .or_else(|| candidates.iter().find(|target| target.language.is_none()))
+        .copied()
+}
+
+/// Where `now` reads unix seconds from, the system clock outside of tests
+#[derive(Clone)]
+pub struct Clock(Arc<dyn Fn() -> i64 + Send + Sync>);
+
+impl Clock {
+    pub fn new(now: impl Fn() -> i64 + Send + Sync + 'static) -> Clock {
+        Clock(Arc::new(now))
+    }
+
+    pub fn system() -> Clock {
+        Clock::new(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64)
+        })
+    }
+
+    pub fn now(&self) -> i64 {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Clock({})", self.now())
+    }
+}
+
+/// current time as unix seconds on `ctx`'s clock, what target windows are compared against
+pub fn now(ctx: &AppCtx) -> i64 {
+    ctx.clock.now()
+}
+
+/// seed the set of links that have targets, so plain links never query for them
+pub async fn load(ctx: &AppCtx) -> Result<(), sqlx::Error> {
+    let codes = sqlx::query!("SELECT DISTINCT domain, short_code FROM link_target")
+        .fetch_all(&ctx.pool)
+        .await?;
+
+    let mut targeted = ctx.targeted.write().unwrap();
+    for code in &codes {
+        targeted.insert(domain::scoped(&code.domain, &code.short_code));
+    }
+    println!("loaded {} links with targets", codes.len());
+    Ok(())
+}
+
+/// where `short_code` should send visitors right now, `long_url` is the link's own target
+///
+/// the cached `long_url` is still what the link falls back to, so a failing
+/// target lookup degrades to that rather than failing the redirect
+pub async fn resolve(
+    ctx: &AppCtx,
+    domain: &str,
+    short_code: &str,
+    long_url: String,
+    visitor: &Visitor,
+) -> String {
+    let key = domain::scoped(domain, short_code);
+    // the link's own target beats waiting on a db that's down
+    if !ctx.targeted.read().unwrap().contains(&key) || health::is_down(ctx) {
+        return long_url;
+    }
+
+    match lookup_targets(domain, short_code, &ctx.pool).await {
+        Ok(targets) => match select(&targets, now(ctx), visitor) {
+            Some(target) => {
+                println!("\trotated to target");
+                target.long_url.clone()
+            }
+            None => long_url,
+        },
+        Err(e) => {
+            eprintln!("Failed to look up targets: {}", e);
+            long_url
+        }
+    }
+}
+
+/// whether `short_code` can point elsewhere over time, in which case redirects mustn't be permanent
+pub fn rotates(ctx: &AppCtx, domain: &str, short_code: &str) -> bool {
+    ctx.targeted
+        .read()
+        .unwrap()
+        .contains(&domain::scoped(domain, short_code))
+}
+
+#[derive(Deserialize)]
+pub struct SetTargets {
+    targets: Vec<Target>,
+    /// domain the code lives in, the default domain when omitted
+    #[serde(default = "default_domain")]
+    domain: String,
+}
+
+fn default_domain() -> String {
+    DEFAULT_DOMAIN.to_owned()
+}
+
+/// PUT /links/{short_code}/targets
+///
+/// replaces the link's targets, an empty list goes back to the link's own url
+pub async fn set(
+    _: AdminAuth,
+    _: Writable,
+    State(ctx): State<AppCtx>,
+    Path(short_code): Path<String>,
+    Json(req): Json<SetTargets>,
+) -> impl IntoResponse {
+    println!(
+        "/links/targets PUT <-- {} ({} targets)",
+        short_code,
+        req.targets.len()
+    );
+
+    if req
+        .targets
+        .iter()
+        .any(|t| matches!((t.starts_at, t.ends_at), (Some(start), Some(end)) if start >= end))
+    {
+        return (StatusCode::BAD_REQUEST, "Invalid target window".to_owned()).into_response();
+    }
+    if req
+        .targets
+        .iter()
+        .any(|t| t.country.as_deref().is_some_and(|c| !is_country(c)))
+    {
+        return (StatusCode::BAD_REQUEST, "Invalid target country".to_owned()).into_response();
+    }
+    if req
+        .targets
+        .iter()
+        .any(|t| t.language.as_deref().is_some_and(|l| !is_language(l)))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Invalid target language".to_owned(),
+        )
+            .into_response();
+    }
+
+    let url = match lookup_entry(&req.domain, &short_code, &ctx.pool).await {
+        Ok(Some(url)) => url,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                "Short code not recognised".to_owned(),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            eprintln!("Failed to look up entry: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response();
+        }
+    };
+
+    // same normalization as the link itself, so targets and links agree
+    let targets = req
+        .targets
+        .into_iter()
+        .map(|t| Target {
+            long_url: normalize::normalize_url(&ctx.config, &t.long_url),
+            country: t.country.map(|c| c.to_ascii_uppercase()),
+            language: t.language.map(|l| l.to_ascii_lowercase()),
+            ..t
+        })
+        .collect::<Vec<_>>();
+
+    if !targets
+        .iter()
+        .all(|t| scheme::is_allowed(&ctx.config, &t.long_url))
+    {
+        println!("\tscheme not allowed");
+        return (
+            StatusCode::BAD_REQUEST,
+            "URL scheme is not allowed".to_owned(),
+        )
+            .into_response();
+    }
+    if targets
+        .iter()
+        .any(|t| blocklist::is_blocked(&ctx, &t.long_url))
+    {
+        println!("\ttarget is blocklisted");
+        return (StatusCode::FORBIDDEN, "URL is blocklisted".to_owned()).into_response();
+    }
+
+    if let Err(e) = store_targets(&req.domain, &short_code, &targets, &ctx.pool).await {
+        eprintln!("Failed to store targets: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Something went wrong on our end".to_owned(),
+        )
+            .into_response();
+    }
+
+    {
+        let key = domain::scoped(&req.domain, &short_code);
+        let mut targeted = ctx.targeted.write().unwrap();
+        if targets.is_empty() {
+            targeted.remove(&key);
+        } else {
+            targeted.insert(key);
+        }
+    }
+    println!("\tsaved targets to db");
+
+    invalidate::broadcast(
+        &ctx,
+        &req.domain,
+        vec![Changed {
+            short_code,
+            long_url: url.long_url,
+        }],
+    );
+
+    Json(targets).into_response()
+}
+
+/// S -> D : lookup_targets(short_code) . D -> S : ok(targets: [Target])
+async fn lookup_targets(
+    domain: &str,
+    short_code: &str,
+    pool: &sqlx::SqlitePool,
+) -> Result<Vec<Target>, sqlx::Error> {
+    sqlx::query_as!(
+        Target,
+        "SELECT long_url, starts_at, ends_at, country, language FROM link_target
+         WHERE domain = $1 AND short_code = $2 ORDER BY position",
+        domain,
+        short_code
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// S -> D : store_targets(short_code, targets) . D -> S : ok()
+async fn store_targets(
+    domain: &str,
+    short_code: &str,
+    targets: &[Target],
+    pool: &sqlx::SqlitePool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM link_target WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    for (position, target) in targets.iter().enumerate() {
+        let position = position as i64;
+        sqlx::query!(
+            "INSERT INTO link_target (domain, short_code, position, long_url, starts_at, ends_at, country, language)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            domain,
+            short_code,
+            position,
+            target.long_url,
+            target.starts_at,
+            target.ends_at,
+            target.country,
+            target.language
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+/// S -> D : delete_targets(short_code) . D -> S : ok()
+pub async fn delete_targets(
+    domain: &str,
+    short_code: &str,
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM link_target WHERE domain = $1 AND short_code = $2",
+        domain,
+        short_code
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, header};
+    use serde_json::json;
+
+    use crate::{
+        AppCtx,
+        tests::{admin, ctx, get, shorten, stop_clock},
+    };
+
+    async fn location(ctx: &AppCtx, short_code: &str) -> Option<String> {
+        let reply = get(ctx, &format!("/redirect/{}", short_code)).await;
+        reply.header(header::LOCATION).map(str::to_owned)
+    }
+
+    #[tokio::test]
+    async fn redirect_follows_the_clock_across_a_window() {
+        let mut ctx = ctx().await;
+        let clock = stop_clock(&mut ctx, 1_000);
+
+        let short_code = shorten(&ctx, "https://example.com/own").await;
+        let reply = admin(
+            &ctx,
+            Method::PUT,
+            &format!("/links/{}/targets", short_code),
+            Some(json!({
+                "targets": [
+                    { "long_url": "https://example.com/before", "ends_at": 1_060 },
+                    { "long_url": "https://example.com/after", "starts_at": 1_060 },
+                ],
+            })),
+        )
+        .await;
+        assert!(reply.status.is_success(), "got {}", reply.status);
+
+        assert_eq!(
+            location(&ctx, &short_code).await.as_deref(),
+            Some("https://example.com/before")
+        );
+
+        // `ends_at` is exclusive, the second the window closes the next one is live
+        clock.advance(59);
+        assert_eq!(
+            location(&ctx, &short_code).await.as_deref(),
+            Some("https://example.com/before")
+        );
+        clock.advance(1);
+        assert_eq!(
+            location(&ctx, &short_code).await.as_deref(),
+            Some("https://example.com/after")
+        );
+    }
+}
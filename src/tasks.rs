@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{AppCtx, read_only, targets};
+
+/// a critical task that hasn't run for this many of its intervals makes `/readyz` fail
+const STALE_AFTER_INTERVALS: u32 = 3;
+
+/// never stale sooner than this, a slow round of a fast task isn't an outage
+const MIN_STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// The background tasks whose runs are tracked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Task {
+    ClickFlush,
+    DbHealth,
+    LinkCheck,
+    Purge,
+    Invalidation,
+}
+
+impl Task {
+    pub const ALL: [Task; 5] = [
+        Task::ClickFlush,
+        Task::DbHealth,
+        Task::LinkCheck,
+        Task::Purge,
+        Task::Invalidation,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::ClickFlush => "click_flush",
+            Task::DbHealth => "db_health",
+            Task::LinkCheck => "link_check",
+            Task::Purge => "purge",
+            Task::Invalidation => "invalidation",
+        }
+    }
+
+    /// how often a task that would be missed runs, `None` for the ones that can
+    /// stop without anyone noticing: the link checker and purge only tidy up, and
+    /// invalidations are sent when links change rather than on a timer
+    fn expected_every(self, ctx: &AppCtx) -> Option<Duration> {
+        match self {
+            Task::ClickFlush => Some(Duration::from_millis(
+                ctx.config.click_flush_interval_ms.max(1),
+            )),
+            Task::DbHealth if ctx.config.db_health_interval_secs > 0 => {
+                Some(Duration::from_secs(ctx.config.db_health_interval_secs))
+            }
+            _ => None,
+        }
+    }
+
+    /// whether the task sits out read-only mode on purpose
+    fn pauses_when_read_only(self) -> bool {
+        matches!(self, Task::ClickFlush | Task::LinkCheck | Task::Purge)
+    }
+}
+
+/// How a task's runs have gone since startup
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskStats {
+    /// unix seconds the last run finished, 0 before the first
+    pub last_run_at: i64,
+    pub last_duration_ms: u64,
+    pub successes: u64,
+    pub failures: u64,
+    /// links whose clicks were flushed, links checked or purged, links sent to peers
+    pub items: u64,
+}
+
+/// Every task's `TaskStats`, see `record`
+#[derive(Debug)]
+pub struct TaskHealth {
+    /// unix seconds the process started, what a task that never ran is measured from
+    started_at: i64,
+    tasks: Mutex<BTreeMap<Task, TaskStats>>,
+}
+
+impl TaskHealth {
+    pub fn new(started_at: i64) -> TaskHealth {
+        TaskHealth {
+            started_at,
+            tasks: Mutex::new(Task::ALL.map(|task| (task, TaskStats::default())).into()),
+        }
+    }
+
+    /// a copy of every task's stats, keyed by name
+    pub fn snapshot(&self) -> BTreeMap<&'static str, TaskStats> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(task, stats)| (task.name(), stats.clone()))
+            .collect()
+    }
+}
+
+/// count a run of `task` that began at `started`, `res` carrying how many items it handled
+pub fn record<E>(ctx: &AppCtx, task: Task, started: Instant, res: &Result<usize, E>) {
+    let mut tasks = ctx.task_health.tasks.lock().unwrap();
+    let stats = tasks.entry(task).or_default();
+    stats.last_run_at = targets::now(ctx);
+    stats.last_duration_ms = started.elapsed().as_millis() as u64;
+    match res {
+        Ok(items) => {
+            stats.successes += 1;
+            stats.items += *items as u64;
+        }
+        Err(_) => stats.failures += 1,
+    }
+}
+
+/// the first critical task that has gone quiet for too long and how long it's been,
+/// a task that's well but paused for read-only mode doesn't count
+pub fn stalled(ctx: &AppCtx) -> Option<(&'static str, i64)> {
+    let now = targets::now(ctx);
+    let read_only = read_only::is_on(ctx);
+    let tasks = ctx.task_health.tasks.lock().unwrap();
+
+    Task::ALL.into_iter().find_map(|task| {
+        let every = task.expected_every(ctx)?;
+        if read_only && task.pauses_when_read_only() {
+            return None;
+        }
+        let stale_after = (every * STALE_AFTER_INTERVALS).max(MIN_STALE_AFTER);
+        let last = tasks
+            .get(&task)
+            .map(|stats| stats.last_run_at)
+            .filter(|at| *at > 0)
+            .unwrap_or(ctx.task_health.started_at);
+        let quiet = now - last;
+        (quiet > stale_after.as_secs() as i64).then_some((task.name(), quiet))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::http::{Method, StatusCode};
+
+    use super::{Task, record};
+    use crate::{
+        clicks,
+        tests::{admin, ctx, get, shorten, stop_clock},
+    };
+
+    #[tokio::test]
+    async fn a_run_shows_up_in_admin_stats() {
+        let mut ctx = ctx().await;
+        stop_clock(&mut ctx, 1_700_000_000);
+        let short_code = shorten(&ctx, "https://example.com/tasks").await;
+        get(&ctx, &format!("/redirect/{}", short_code)).await;
+
+        let started = Instant::now();
+        let res = clicks::flush(&ctx).await;
+        record(&ctx, Task::ClickFlush, started, &res);
+
+        let stats = admin(&ctx, Method::GET, "/admin/stats", None).await.json();
+        let flush = &stats["tasks"]["click_flush"];
+        assert_eq!(flush["last_run_at"], 1_700_000_000);
+        assert_eq!(flush["successes"], 1);
+        assert_eq!(flush["failures"], 0);
+        assert_eq!(flush["items"], 1);
+    }
+
+    #[tokio::test]
+    async fn a_quiet_task_fails_readyz() {
+        let mut ctx = ctx().await;
+        let clock = stop_clock(&mut ctx, 1_700_000_000);
+        for task in [Task::ClickFlush, Task::DbHealth] {
+            record(&ctx, task, Instant::now(), &Ok::<_, ()>(0));
+        }
+        assert_eq!(get(&ctx, "/readyz").await.status, StatusCode::OK);
+
+        // neither runs more than once a second by default, so 30s is the floor
+        clock.advance(31);
+        let reply = get(&ctx, "/readyz").await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            reply.json()["error"]["message"],
+            "background task click_flush hasn't run for 31s"
+        );
+    }
+}
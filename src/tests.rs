@@ -0,0 +1,303 @@
+//! Builds the app on an in-memory db and drives it with `oneshot`, no socket involved.
+//! Module tests elsewhere use these helpers for anything that needs a running app
+
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{self, Body},
+    extract::connect_info::MockConnectInfo,
+    http::{HeaderMap, Method, Request, StatusCode, header},
+};
+use tower::ServiceExt;
+
+use crate::{AppCtx, build_app, build_ctx, config::Config, db, targets::Clock};
+
+/// the `ADMIN_TOKEN` every test app is built with
+pub const ADMIN_TOKEN: &str = "test-token";
+
+/// what the app answered
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+    /// `body` as it came, for the replies that aren't text
+    pub bytes: Vec<u8>,
+}
+
+impl Reply {
+    pub fn header(&self, name: header::HeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).expect("body isn't json")
+    }
+}
+
+/// the environment's config on a throwaway db, with admin unlocked
+pub fn config() -> Config {
+    Config {
+        database_url: "sqlite::memory:".to_owned(),
+        admin_token: Some(ADMIN_TOKEN.to_owned()),
+        ..Config::from_env()
+    }
+}
+
+/// an `AppCtx` the way `main` builds it, migrated and loaded, without the background tasks
+pub async fn ctx_with(config: Config) -> AppCtx {
+    let pool = db::connect(&config).await.expect("failed to open test db");
+    db::migrate(&pool).await.expect("failed to migrate test db");
+    build_ctx(config, pool)
+        .await
+        .map_err(|e| e.to_string())
+        .expect("failed to build ctx")
+}
+
+pub async fn ctx() -> AppCtx {
+    ctx_with(config()).await
+}
+
+/// A path in the temp dir no other test uses, removed on drop along with any
+/// sqlite `-wal` and `-shm` files next to it
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(extension: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::SeqCst);
+        TempFile(env::temp_dir().join(format!(
+            "url_shortener-test-{}-{}.{}",
+            process::id(),
+            n,
+            extension
+        )))
+    }
+
+    pub fn path(&self) -> String {
+        self.0.display().to_string()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", self.path(), suffix));
+        }
+    }
+}
+
+/// A db file for ctxs that have to share one, as instances behind a load balancer do
+pub struct SharedDb(TempFile);
+
+impl SharedDb {
+    pub fn new() -> SharedDb {
+        SharedDb(TempFile::new("db"))
+    }
+
+    /// `config()` on this db
+    pub fn config(&self) -> Config {
+        Config {
+            database_url: format!("sqlite:{}", self.0.path()),
+            ..config()
+        }
+    }
+}
+
+/// serve `ctx` on a loopback port so other ctxs can reach it, responds with its base url
+pub async fn serve(ctx: &AppCtx) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind test listener");
+    let addr = listener.local_addr().unwrap();
+    let app = build_app(ctx.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+/// whether `done` comes true within a couple of seconds, for what happens in the background
+pub async fn eventually<F: AsyncFnMut() -> bool>(mut done: F) -> bool {
+    for _ in 0..40 {
+        if done().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+/// A clock tests move by hand, see `stop_clock`
+#[derive(Clone)]
+pub struct TestClock(Arc<AtomicI64>);
+
+impl TestClock {
+    pub fn advance(&self, secs: i64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+/// stop `ctx`'s clock at `now`, it only moves when the returned clock is moved
+pub fn stop_clock(ctx: &mut AppCtx, now: i64) -> TestClock {
+    let at = Arc::new(AtomicI64::new(now));
+    let read = at.clone();
+    ctx.clock = Clock::new(move || read.load(Ordering::SeqCst));
+    TestClock(at)
+}
+
+/// run `req` through a fresh router on `ctx`, as if it came from 127.0.0.1
+pub async fn call(ctx: &AppCtx, req: Request<Body>) -> Reply {
+    let app =
+        build_app(ctx.clone()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+    let res = app.oneshot(req).await.expect("router is infallible");
+
+    let status = res.status();
+    let headers = res.headers().clone();
+    let bytes = body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .expect("failed to read body");
+    Reply {
+        status,
+        headers,
+        body: String::from_utf8_lossy(&bytes).into_owned(),
+        bytes: bytes.to_vec(),
+    }
+}
+
+pub async fn get(ctx: &AppCtx, uri: &str) -> Reply {
+    call(ctx, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+/// an admin request, with a JSON body when there is one
+pub async fn admin(
+    ctx: &AppCtx,
+    method: Method,
+    uri: &str,
+    json: Option<serde_json::Value>,
+) -> Reply {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN));
+    let req = match json {
+        Some(json) => req
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json.to_string())),
+        None => req.body(Body::empty()),
+    };
+    call(ctx, req.unwrap()).await
+}
+
+/// `POST /shorten` for `long_url`, responds with the code it got
+pub async fn shorten(ctx: &AppCtx, long_url: &str) -> String {
+    let q = url::form_urlencoded::byte_serialize(long_url.as_bytes()).collect::<String>();
+    let req = Request::post(format!("/shorten?q={}", q))
+        .body(Body::empty())
+        .unwrap();
+    let reply = call(ctx, req).await;
+    assert!(
+        reply.status.is_success(),
+        "shorten answered {}: {}",
+        reply.status,
+        reply.body
+    );
+    reply.body
+}
+
+#[tokio::test]
+async fn shorten_then_redirect() {
+    let ctx = ctx().await;
+
+    let short_code = shorten(&ctx, "https://example.com/some/page").await;
+    let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+
+    assert!(reply.status.is_redirection(), "got {}", reply.status);
+    assert_eq!(
+        reply.header(header::LOCATION),
+        Some("https://example.com/some/page")
+    );
+}
+
+#[tokio::test]
+async fn unknown_code_is_not_found() {
+    let ctx = ctx().await;
+
+    let reply = get(&ctx, "/redirect/doesnotexist").await;
+
+    assert_eq!(reply.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn link_rate_limit_only_stops_that_link() {
+    let ctx = ctx_with(Config {
+        link_rate_limit: Some(2),
+        ..config()
+    })
+    .await;
+    let hammered = shorten(&ctx, "https://example.com/hammered").await;
+    let other = shorten(&ctx, "https://example.com/other").await;
+
+    for _ in 0..2 {
+        let reply = get(&ctx, &format!("/redirect/{}", hammered)).await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+    }
+    let reply = get(&ctx, &format!("/redirect/{}", hammered)).await;
+    assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(reply.header(header::RETRY_AFTER).is_some());
+
+    let reply = get(&ctx, &format!("/redirect/{}", other)).await;
+    assert!(reply.status.is_redirection(), "got {}", reply.status);
+}
+
+#[tokio::test]
+async fn link_rate_limit_of_1_allows_one_hit() {
+    let ctx = ctx_with(Config {
+        link_rate_limit: Some(1),
+        ..config()
+    })
+    .await;
+    let short_code = shorten(&ctx, "https://example.com/once").await;
+
+    let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+    assert!(reply.status.is_redirection(), "got {}", reply.status);
+    let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+    assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn link_rate_limit_of_0_refuses_without_panicking() {
+    let ctx = ctx_with(Config {
+        link_rate_limit: Some(0),
+        ..config()
+    })
+    .await;
+    let short_code = shorten(&ctx, "https://example.com/never").await;
+
+    let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+    assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn admin_sees_a_shortened_link() {
+    let ctx = ctx().await;
+
+    let short_code = shorten(&ctx, "https://example.com/admin").await;
+    let reply = admin(
+        &ctx,
+        Method::GET,
+        &format!("/admin/links/{}", short_code),
+        None,
+    )
+    .await;
+
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json()["long_url"], "https://example.com/admin");
+}
@@ -0,0 +1,370 @@
+use std::{future::Future, sync::LazyLock, time::Duration};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    trace::{Status, TraceContextExt, TracerProvider as _},
+};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{BatchConfigBuilder, BatchSpanProcessor, SdkTracerProvider},
+};
+use tracing::{
+    Dispatch, Instrument, Level, Span, instrument::WithSubscriber, subscriber::NoSubscriber,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Registry, filter::Targets, layer::SubscriberExt};
+
+use crate::{AppCtx, config::Config};
+
+/// spans held between exports, past this new ones are dropped rather than growing forever
+const MAX_BUFFERED: usize = 4096;
+
+/// how often buffered spans are sent to the collector
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// a collector taking longer than this to accept a batch loses it
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// interested in nothing, only there so tracing never sees a lone dispatch. with just
+/// one it decides whether a span is on by asking the first thread to reach it, and a
+/// thread outside any traced request would switch that span off for good
+static BYSTANDER: LazyLock<Dispatch> = LazyLock::new(|| Dispatch::new(NoSubscriber::default()));
+
+/// Where request spans go, only built when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+/// spans are recorded through `dispatch` rather than a global subscriber, so each
+/// app (and each test) keeps its own
+#[derive(Debug)]
+pub struct Tracer {
+    provider: SdkTracerProvider,
+    dispatch: Dispatch,
+}
+
+impl Tracer {
+    /// export over OTLP/HTTP JSON to `{endpoint}/v1/traces`, batched in the background
+    pub fn new(
+        endpoint: &str,
+        service_name: &str,
+    ) -> Result<Tracer, opentelemetry_otlp::ExporterBuildError> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()?;
+        let batches = BatchSpanProcessor::builder(exporter)
+            .with_batch_config(
+                BatchConfigBuilder::default()
+                    .with_max_queue_size(MAX_BUFFERED)
+                    .with_scheduled_delay(EXPORT_INTERVAL)
+                    .build(),
+            )
+            .build();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(batches)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.to_owned())
+                    .build(),
+            );
+        Ok(Tracer::with_provider(provider.build()))
+    }
+
+    /// every span handed to `exporter` as soon as it ends, for tests
+    #[cfg(test)]
+    pub fn with_exporter(
+        exporter: impl opentelemetry_sdk::trace::SpanExporter + 'static,
+    ) -> Tracer {
+        Tracer::with_provider(
+            SdkTracerProvider::builder()
+                .with_simple_exporter(exporter)
+                .build(),
+        )
+    }
+
+    fn with_provider(provider: SdkTracerProvider) -> Tracer {
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        // only our own spans, not whatever the dependencies instrument
+        let ours = Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE);
+        LazyLock::force(&BYSTANDER);
+        let dispatch = Dispatch::new(
+            Registry::default()
+                .with(ours)
+                .with(tracing_opentelemetry::layer().with_tracer(tracer)),
+        );
+        Tracer { provider, dispatch }
+    }
+
+    /// C -> O : export(spans), whatever is still buffered. a failed export is dropped,
+    /// traces are best effort
+    pub fn shutdown(&self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("Failed to export spans: {}", e);
+        }
+    }
+}
+
+/// the tracer for `OTEL_EXPORTER_OTLP_ENDPOINT`, none when it's unset or unusable
+pub fn from_config(config: &Config) -> Option<Tracer> {
+    let endpoint = config.otel_endpoint.as_deref()?;
+    match Tracer::new(endpoint, &config.otel_service_name) {
+        Ok(tracer) => Some(tracer),
+        Err(e) => {
+            eprintln!("Failed to build otlp exporter, not tracing: {}", e);
+            None
+        }
+    }
+}
+
+/// put `key=value` on the current span, does nothing when there isn't one
+pub fn record(key: &'static str, value: &str) {
+    Span::current().set_attribute(key, value.to_owned());
+}
+
+/// `record` for a boolean attribute
+pub fn record_bool(key: &'static str, value: bool) {
+    Span::current().set_attribute(key, value);
+}
+
+/// run the db call `fut` as a child span, named after the operation like `lookup_entry`
+pub async fn db<F: Future>(
+    operation: &'static str,
+    attributes: &[(&'static str, &str)],
+    fut: F,
+) -> F::Output {
+    // sqlx hands the current span to its worker thread, and a span dropped there would
+    // close its parent through that thread's subscriber, which is nobody, so the
+    // request span would never end. the parent is only set on the OTel side instead
+    let span = tracing::info_span!(
+        parent: None,
+        "db",
+        otel.name = operation,
+        otel.kind = "client",
+        db.system.name = "sqlite",
+        db.operation.name = operation,
+    );
+    let _ = span.set_parent(Span::current().context());
+    for (key, value) in attributes {
+        span.set_attribute(*key, value.to_string());
+    }
+    fut.instrument(span).await
+}
+
+/// middleware giving each request a server span that the db spans nest under
+pub async fn layer(State(ctx): State<AppCtx>, req: Request, next: Next) -> Response {
+    let Some(tracer) = ctx.tracer.clone() else {
+        return next.run(req).await;
+    };
+
+    // the route, not the path, so codes don't end up in span names
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned());
+    let method = req.method().to_string();
+    let name = match &route {
+        Some(route) => format!("{} {}", method, route),
+        None => method.clone(),
+    };
+
+    let span = tracing::dispatcher::with_default(&tracer.dispatch, || {
+        tracing::info_span!(
+            "request",
+            otel.name = name,
+            otel.kind = "server",
+            http.request.method = method,
+            http.route = route,
+        )
+    });
+    // a caller's W3C `traceparent` is joined instead of starting a new trace
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    if parent.span().span_context().is_remote() {
+        let _ = span.set_parent(parent);
+    }
+
+    let res = next
+        .run(req)
+        .instrument(span.clone())
+        .with_subscriber(tracer.dispatch.clone())
+        .await;
+
+    let status = res.status();
+    span.set_attribute("http.response.status_code", i64::from(status.as_u16()));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, http::Request};
+    use opentelemetry::{
+        Value,
+        trace::{SpanId, SpanKind},
+    };
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+
+    use super::*;
+    use crate::{
+        domain::{self, DEFAULT_DOMAIN},
+        tests::{call, ctx, eventually, get, shorten},
+    };
+
+    fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| &kv.value)
+    }
+
+    /// the finished spans once all of `names` are among them. sqlx hands the current
+    /// span to its worker thread with each query, so a span can end a moment after
+    /// the response
+    async fn finished(exporter: &InMemorySpanExporter, names: &[&str]) -> Vec<SpanData> {
+        let all_in = eventually(async || {
+            let spans = exporter.get_finished_spans().unwrap();
+            names
+                .iter()
+                .all(|name| spans.iter().any(|span| span.name == *name))
+        })
+        .await;
+        let spans = exporter.get_finished_spans().unwrap();
+        assert!(all_in, "wanted {:?} among {:?}", names, spans);
+        spans
+    }
+
+    fn named<'a>(spans: &'a [SpanData], name: &str) -> &'a SpanData {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no {} span in {:?}", name, spans))
+    }
+
+    async fn traced_ctx() -> (AppCtx, InMemorySpanExporter) {
+        let mut ctx = ctx().await;
+        let exporter = InMemorySpanExporter::default();
+        ctx.tracer = Some(Arc::new(Tracer::with_exporter(exporter.clone())));
+        (ctx, exporter)
+    }
+
+    #[tokio::test]
+    async fn a_redirect_exports_its_span_and_the_lookup_under_it() {
+        let (ctx, exporter) = traced_ctx().await;
+        let short_code = shorten(&ctx, "https://example.com/traced").await;
+        ctx.short_to_long_cache
+            .remove(&domain::scoped(DEFAULT_DOMAIN, &short_code));
+
+        let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert!(reply.status.is_redirection(), "got {}", reply.status);
+
+        let spans = finished(&exporter, &["GET /redirect/{short_code}", "lookup_entry"]).await;
+        let request = named(&spans, "GET /redirect/{short_code}");
+        assert_eq!(request.span_kind, SpanKind::Server);
+        assert_eq!(request.parent_span_id, SpanId::INVALID);
+        assert_eq!(
+            attribute(request, "short_code"),
+            Some(&Value::from(short_code.clone()))
+        );
+        assert_eq!(attribute(request, "cache.hit"), Some(&Value::Bool(false)));
+        assert_eq!(
+            attribute(request, "http.route"),
+            Some(&Value::from("/redirect/{short_code}"))
+        );
+        assert_eq!(
+            attribute(request, "http.response.status_code"),
+            Some(&Value::I64(reply.status.as_u16().into()))
+        );
+
+        let lookup = named(&spans, "lookup_entry");
+        assert_eq!(lookup.span_kind, SpanKind::Client);
+        assert_eq!(lookup.parent_span_id, request.span_context.span_id());
+        assert_eq!(
+            lookup.span_context.trace_id(),
+            request.span_context.trace_id()
+        );
+        assert_eq!(
+            attribute(lookup, "db.operation.name"),
+            Some(&Value::from("lookup_entry"))
+        );
+        assert_eq!(
+            attribute(lookup, "short_code"),
+            Some(&Value::from(short_code))
+        );
+    }
+
+    #[tokio::test]
+    async fn shortening_exports_a_store_entry_span() {
+        let (ctx, exporter) = traced_ctx().await;
+        shorten(&ctx, "https://example.com/stored").await;
+
+        let spans = finished(&exporter, &["POST /shorten", "store_entry"]).await;
+        let store = named(&spans, "store_entry");
+        assert_eq!(
+            attribute(store, "db.system.name"),
+            Some(&Value::from("sqlite"))
+        );
+        assert_eq!(
+            store.parent_span_id,
+            named(&spans, "POST /shorten").span_context.span_id()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_callers_traceparent_is_joined() {
+        let (ctx, exporter) = traced_ctx().await;
+        let req = Request::get("/livez")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+        call(&ctx, req).await;
+
+        let spans = finished(&exporter, &["GET /livez"]).await;
+        let request = named(&spans, "GET /livez");
+        assert_eq!(
+            request.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(request.parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert!(request.parent_span_is_remote);
+    }
+
+    #[tokio::test]
+    async fn server_errors_mark_the_span() {
+        let (ctx, exporter) = traced_ctx().await;
+        let short_code = shorten(&ctx, "https://example.com/unreachable").await;
+        ctx.short_to_long_cache
+            .remove(&domain::scoped(DEFAULT_DOMAIN, &short_code));
+        ctx.pool.close().await;
+
+        let reply = get(&ctx, &format!("/redirect/{}", short_code)).await;
+        assert!(reply.status.is_server_error(), "got {}", reply.status);
+
+        let spans = finished(&exporter, &["GET /redirect/{short_code}"]).await;
+        let request = named(&spans, "GET /redirect/{short_code}");
+        assert!(matches!(request.status, Status::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn nothing_is_traced_without_a_tracer() {
+        let ctx = ctx().await;
+        // outside any request, and with no tracer on the ctx, these must be no-ops
+        record("short_code", "abc");
+        record_bool("cache.hit", true);
+        let out = db("lookup_entry", &[], async { 7 }).await;
+        assert_eq!(out, 7);
+        assert!(get(&ctx, "/livez").await.status.is_success());
+    }
+}
@@ -0,0 +1,45 @@
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde::Serialize;
+
+use crate::AppCtx;
+
+/// What's deployed, both the binary and the schema it's running against
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    commit: &'static str,
+    /// unix seconds
+    built_at: u64,
+    /// latest migration applied to the db
+    schema_version: i64,
+}
+
+/// GET /version
+///
+/// crate version, commit and build time of this binary, plus the db's migration version
+pub async fn version(State(ctx): State<AppCtx>) -> impl IntoResponse {
+    println!("/version GET <--");
+
+    let schema_version: Result<Option<i64>, sqlx::Error> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success = 1")
+            .fetch_one(&ctx.pool)
+            .await;
+
+    match schema_version {
+        Ok(schema_version) => Json(Version {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("BUILD_GIT_COMMIT"),
+            built_at: env!("BUILD_TIMESTAMP").parse().unwrap_or(0),
+            schema_version: schema_version.unwrap_or(0),
+        })
+        .into_response(),
+        Err(e) => {
+            eprintln!("Failed to read schema version: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong on our end".to_owned(),
+            )
+                .into_response()
+        }
+    }
+}
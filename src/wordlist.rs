@@ -0,0 +1,87 @@
+use crate::AppCtx;
+
+/// generated codes skipped in a row before giving up, only a list matching
+/// almost everything gets this far
+const MAX_SKIPS: u32 = 32;
+
+/// Words no code may contain, read from `CODE_WORDLIST_PATH`
+///
+/// one word per line, matched anywhere in a code case-insensitively and with
+/// digits read as the letters they pass for. blank lines and `#` comments are skipped
+#[derive(Debug, Default)]
+pub struct Wordlist {
+    words: Vec<String>,
+}
+
+impl Wordlist {
+    pub fn parse(contents: &str) -> Wordlist {
+        let mut words = contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(deleet)
+            .collect::<Vec<String>>();
+        words.sort();
+        words.dedup();
+        Wordlist { words }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// whether `code` spells one of the words, `b4d_w0rd` counts for `badword`
+    pub fn matches(&self, code: &str) -> bool {
+        if self.words.is_empty() {
+            return false;
+        }
+        let code = deleet(code);
+        self.words.iter().any(|word| code.contains(word.as_str()))
+    }
+}
+
+/// lowercase with separators dropped and digits swapped for the letters they look like
+fn deleet(s: &str) -> String {
+    s.chars()
+        .filter(|c| !matches!(c, '_' | '-'))
+        .map(|c| match c.to_ascii_lowercase() {
+            '0' => 'o',
+            '1' => 'i',
+            '3' => 'e',
+            '4' => 'a',
+            '5' => 's',
+            '7' => 't',
+            '8' => 'b',
+            '9' => 'g',
+            c => c,
+        })
+        .collect()
+}
+
+/// read the list at `path`
+pub fn load(path: &str) -> std::io::Result<Wordlist> {
+    std::fs::read_to_string(path).map(|contents| Wordlist::parse(&contents))
+}
+
+/// whether `code` is ruled out by `CODE_WORDLIST_PATH`
+pub fn is_listed(ctx: &AppCtx, code: &str) -> bool {
+    ctx.code_wordlist.matches(code)
+}
+
+/// the first of `generate(attempt)`, `generate(attempt + 1)`, ... the wordlist
+/// doesn't rule out, leaving `attempt` on it. `None` once `MAX_SKIPS` in a row were
+pub fn first_unlisted(
+    ctx: &AppCtx,
+    attempt: &mut u32,
+    generate: impl Fn(u32) -> String,
+) -> Option<String> {
+    for _ in 0..MAX_SKIPS {
+        let code = generate(*attempt);
+        if !is_listed(ctx, &code) {
+            return Some(code);
+        }
+        println!("\tgenerated code is on the wordlist, regenerating");
+        *attempt += 1;
+    }
+    None
+}